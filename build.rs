@@ -0,0 +1,6 @@
+fn main() {
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/ext_authz.proto"], &["proto"])
+        .expect("failed to compile proto/ext_authz.proto");
+}
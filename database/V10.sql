@@ -0,0 +1,9 @@
+-- -----------------------------------------------------
+-- Time-bounded group memberships
+-- -----------------------------------------------------
+
+ALTER TABLE `group_members`
+  ADD COLUMN `valid_from` DATETIME NULL DEFAULT NULL COMMENT 'The membership holds from this time on, NULL means always',
+  ADD COLUMN `valid_until` DATETIME NULL DEFAULT NULL COMMENT 'The membership ends at this time and is then removed by the sweeper, NULL means never';
+
+CREATE INDEX `valid_until_IDX` ON `group_members` (`valid_until` ASC);
@@ -0,0 +1,6 @@
+-- -----------------------------------------------------
+-- Public OAuth clients
+-- -----------------------------------------------------
+
+ALTER TABLE `app`
+  ADD COLUMN `public` TINYINT(1) NOT NULL DEFAULT 0 COMMENT 'Public clients (e.g. SPAs and native apps) cannot keep a secret, every other app must authenticate with its own' AFTER `service_account`;
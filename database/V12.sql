@@ -0,0 +1,5 @@
+-- -----------------------------------------------------
+-- Scope for access tokens used on the feroauth API itself
+-- -----------------------------------------------------
+
+INSERT INTO `scope` (`uuid`, `_revision`, `name`, `desc`, `keys`) VALUES (0x3F9B6C2E8A1D4E7FB05C94D1A6E2F873, DEFAULT, 'Feroauth', 'Manage your account and anything else you may change in feroauth', '[]');
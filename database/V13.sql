@@ -0,0 +1,46 @@
+-- -----------------------------------------------------
+-- Reload delegations when the users who gave them change
+-- -----------------------------------------------------
+
+-- Enforcers keep the givers of delegations with their flags and groups, so a change to them is a change to the policy.
+-- Nested groups reach givers indirectly, any change to the members of a group counts as soon as there are delegations.
+DELIMITER $$
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`user_AFTER_UPDATE` AFTER UPDATE ON `user` FOR EACH ROW
+BEGIN
+	IF EXISTS (SELECT 1 FROM `policy_delegation` WHERE `from_uuid` = NEW.`uuid`) THEN
+		UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+	END IF;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`group_members_AFTER_INSERT` AFTER INSERT ON `group_members` FOR EACH ROW
+BEGIN
+	IF EXISTS (SELECT 1 FROM `policy_delegation` WHERE `from_uuid` = NEW.`member_uuid`)
+		OR (EXISTS (SELECT 1 FROM `policy_delegation`) AND EXISTS (SELECT 1 FROM `group` WHERE `uuid` = NEW.`member_uuid`)) THEN
+		UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+	END IF;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`group_members_AFTER_UPDATE` AFTER UPDATE ON `group_members` FOR EACH ROW
+BEGIN
+	IF EXISTS (SELECT 1 FROM `policy_delegation` WHERE `from_uuid` = NEW.`member_uuid`)
+		OR (EXISTS (SELECT 1 FROM `policy_delegation`) AND EXISTS (SELECT 1 FROM `group` WHERE `uuid` = NEW.`member_uuid`)) THEN
+		UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+	END IF;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`group_members_AFTER_DELETE` AFTER DELETE ON `group_members` FOR EACH ROW
+BEGIN
+	IF EXISTS (SELECT 1 FROM `policy_delegation` WHERE `from_uuid` = OLD.`member_uuid`)
+		OR (EXISTS (SELECT 1 FROM `policy_delegation`) AND EXISTS (SELECT 1 FROM `group` WHERE `uuid` = OLD.`member_uuid`)) THEN
+		UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+	END IF;
+END$$
+
+-- Rows removed by the cascade of a group deletion fire no trigger
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`group_AFTER_DELETE` AFTER DELETE ON `group` FOR EACH ROW
+BEGIN
+	IF EXISTS (SELECT 1 FROM `policy_delegation`) THEN
+		UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+	END IF;
+END$$
+DELIMITER ;
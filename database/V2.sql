@@ -0,0 +1,68 @@
+-- -----------------------------------------------------
+-- OAuth 2.0 authorization codes and tokens
+-- -----------------------------------------------------
+
+-- -----------------------------------------------------
+-- Table `oauth_code`
+-- -----------------------------------------------------
+CREATE TABLE IF NOT EXISTS `oauth_code` (
+  `code_hash` BINARY(32) NOT NULL,
+  `app_uuid` BINARY(16) NOT NULL,
+  `user_uuid` BINARY(16) NOT NULL,
+  `redirect_uri` VARCHAR(1024) NOT NULL,
+  `scope` MEDIUMTEXT NOT NULL,
+  `code_challenge` VARCHAR(128) CHARACTER SET 'ascii' COLLATE 'ascii_general_ci' NOT NULL,
+  `issued_at` DATETIME NOT NULL DEFAULT NOW(),
+  `expires_at` DATETIME NOT NULL,
+  PRIMARY KEY (`code_hash`),
+  CONSTRAINT `fk_oauth_code_app1`
+    FOREIGN KEY (`app_uuid`)
+    REFERENCES `app` (`uuid`)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE,
+  CONSTRAINT `fk_oauth_code_user1`
+    FOREIGN KEY (`user_uuid`)
+    REFERENCES `user` (`uuid`)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE)
+ENGINE = InnoDB
+DEFAULT CHARACTER SET = utf8mb4
+COLLATE = utf8mb4_unicode_ci;
+
+CREATE INDEX `expires_at_IDX` ON `oauth_code` (`expires_at` ASC);
+
+
+-- -----------------------------------------------------
+-- Table `oauth_token`
+-- -----------------------------------------------------
+CREATE TABLE IF NOT EXISTS `oauth_token` (
+  `uuid` BINARY(16) NOT NULL,
+  `token_hash` BINARY(32) NOT NULL,
+  `kind` VARCHAR(10) NOT NULL COMMENT 'ACCESS or REFRESH',
+  `app_uuid` BINARY(16) NOT NULL,
+  `user_uuid` BINARY(16) NOT NULL,
+  `parent_uuid` BINARY(16) NULL DEFAULT NULL COMMENT 'Refresh token this token was issued with',
+  `scope` MEDIUMTEXT NOT NULL,
+  `issued_at` DATETIME NOT NULL DEFAULT NOW(),
+  `expires_at` DATETIME NOT NULL,
+  `revoked` TINYINT NOT NULL DEFAULT 0,
+  PRIMARY KEY (`uuid`),
+  CONSTRAINT `fk_oauth_token_app1`
+    FOREIGN KEY (`app_uuid`)
+    REFERENCES `app` (`uuid`)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE,
+  CONSTRAINT `fk_oauth_token_user1`
+    FOREIGN KEY (`user_uuid`)
+    REFERENCES `user` (`uuid`)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE)
+ENGINE = InnoDB
+DEFAULT CHARACTER SET = utf8mb4
+COLLATE = utf8mb4_unicode_ci;
+
+CREATE UNIQUE INDEX `token_hash_UNIQUE` ON `oauth_token` (`token_hash` ASC);
+
+CREATE INDEX `parent_uuid_IDX` ON `oauth_token` (`parent_uuid` ASC);
+
+CREATE INDEX `expires_at_IDX` ON `oauth_token` (`expires_at` ASC);
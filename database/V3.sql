@@ -0,0 +1,29 @@
+-- -----------------------------------------------------
+-- OpenID Connect
+-- -----------------------------------------------------
+
+ALTER TABLE `oauth_code` ADD COLUMN `nonce` VARCHAR(255) NULL DEFAULT NULL AFTER `code_challenge`;
+
+
+-- -----------------------------------------------------
+-- Table `oidc_key`
+-- -----------------------------------------------------
+CREATE TABLE IF NOT EXISTS `oidc_key` (
+  `uuid` BINARY(16) NOT NULL COMMENT 'Also used as the JWK kid',
+  `alg` VARCHAR(10) NOT NULL,
+  `private_key` MEDIUMBLOB NOT NULL COMMENT 'PKCS#8 PEM',
+  `created_at` DATETIME NOT NULL DEFAULT NOW(),
+  `retired_at` DATETIME NULL DEFAULT NULL COMMENT 'NULL while the key is used for signing',
+  PRIMARY KEY (`uuid`))
+ENGINE = InnoDB;
+
+CREATE INDEX `retired_at_IDX` ON `oidc_key` (`retired_at` ASC);
+
+
+-- -----------------------------------------------------
+-- Data for table `scope`
+-- -----------------------------------------------------
+START TRANSACTION;
+INSERT INTO `scope` (`uuid`, `_revision`, `name`, `desc`, `keys`) VALUES (0x6C1F3E0B2D7A4C55A1E89B4F0D2C7E31, DEFAULT, 'OpenID', 'Sign in with your account', '[\'user.uuid\']');
+
+COMMIT;
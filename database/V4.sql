@@ -0,0 +1,36 @@
+-- -----------------------------------------------------
+-- OAuth 2.0 device authorization grant (RFC 8628)
+-- -----------------------------------------------------
+
+-- -----------------------------------------------------
+-- Table `oauth_device_code`
+-- -----------------------------------------------------
+CREATE TABLE IF NOT EXISTS `oauth_device_code` (
+  `device_code_hash` BINARY(32) NOT NULL,
+  `user_code` VARCHAR(16) CHARACTER SET 'ascii' COLLATE 'ascii_general_ci' NOT NULL,
+  `app_uuid` BINARY(16) NOT NULL,
+  `scope` MEDIUMTEXT NOT NULL,
+  `user_uuid` BINARY(16) NULL DEFAULT NULL COMMENT 'User who approved or denied the request',
+  `status` VARCHAR(10) NOT NULL DEFAULT 'PENDING' COMMENT 'PENDING, APPROVED or DENIED',
+  `interval` INT NOT NULL DEFAULT 5,
+  `last_polled_at` DATETIME NULL DEFAULT NULL,
+  `issued_at` DATETIME NOT NULL DEFAULT NOW(),
+  `expires_at` DATETIME NOT NULL,
+  PRIMARY KEY (`device_code_hash`),
+  CONSTRAINT `fk_oauth_device_code_app1`
+    FOREIGN KEY (`app_uuid`)
+    REFERENCES `app` (`uuid`)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE,
+  CONSTRAINT `fk_oauth_device_code_user1`
+    FOREIGN KEY (`user_uuid`)
+    REFERENCES `user` (`uuid`)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE)
+ENGINE = InnoDB
+DEFAULT CHARACTER SET = utf8mb4
+COLLATE = utf8mb4_unicode_ci;
+
+CREATE UNIQUE INDEX `user_code_UNIQUE` ON `oauth_device_code` (`user_code` ASC);
+
+CREATE INDEX `expires_at_IDX` ON `oauth_device_code` (`expires_at` ASC);
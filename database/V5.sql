@@ -0,0 +1,18 @@
+-- -----------------------------------------------------
+-- Service accounts and the OAuth 2.0 client credentials grant
+-- -----------------------------------------------------
+
+ALTER TABLE `user`
+  ADD COLUMN `service_owner` BINARY(16) NULL DEFAULT NULL COMMENT 'User or group owning the service account, NULL for humans' AFTER `last_login`;
+
+CREATE INDEX `service_owner_IDX` ON `user` (`service_owner` ASC);
+
+ALTER TABLE `app`
+  ADD COLUMN `service_account` BINARY(16) NULL DEFAULT NULL COMMENT 'Service account used by the client credentials grant' AFTER `users_group`,
+  ADD CONSTRAINT `fk_app_user1`
+    FOREIGN KEY (`service_account`)
+    REFERENCES `user` (`uuid`)
+    ON DELETE SET NULL
+    ON UPDATE CASCADE;
+
+CREATE INDEX `fk_app_user1` ON `app` (`service_account` ASC);
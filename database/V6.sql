@@ -0,0 +1,7 @@
+-- -----------------------------------------------------
+-- App client secret rotation
+-- -----------------------------------------------------
+
+ALTER TABLE `app`
+  ADD COLUMN `previous_key` MEDIUMBLOB NULL DEFAULT NULL COMMENT 'Client secret hash in use before the last rotation' AFTER `key`,
+  ADD COLUMN `previous_key_valid_until` DATETIME NULL DEFAULT NULL AFTER `previous_key`;
@@ -0,0 +1,38 @@
+-- -----------------------------------------------------
+-- SAML 2.0 identity provider
+-- -----------------------------------------------------
+
+-- -----------------------------------------------------
+-- Table `saml_sp`
+-- -----------------------------------------------------
+CREATE TABLE IF NOT EXISTS `saml_sp` (
+  `app_uuid` BINARY(16) NOT NULL,
+  `entity_id` VARCHAR(190) NOT NULL,
+  `acs_url` VARCHAR(1024) NOT NULL COMMENT 'Assertion consumer service, HTTP-POST binding',
+  `name_id_format` VARCHAR(20) NOT NULL DEFAULT 'PERSISTENT' COMMENT 'PERSISTENT or EMAIL',
+  `metadata` MEDIUMTEXT NOT NULL,
+  PRIMARY KEY (`app_uuid`),
+  CONSTRAINT `fk_saml_sp_app1`
+    FOREIGN KEY (`app_uuid`)
+    REFERENCES `app` (`uuid`)
+    ON DELETE CASCADE
+    ON UPDATE CASCADE)
+ENGINE = InnoDB
+DEFAULT CHARACTER SET = utf8mb4
+COLLATE = utf8mb4_unicode_ci;
+
+CREATE UNIQUE INDEX `entity_id_UNIQUE` ON `saml_sp` (`entity_id` ASC);
+
+
+-- -----------------------------------------------------
+-- Table `saml_key`
+-- -----------------------------------------------------
+CREATE TABLE IF NOT EXISTS `saml_key` (
+  `uuid` BINARY(16) NOT NULL,
+  `private_key` MEDIUMBLOB NOT NULL COMMENT 'PKCS#8 PEM',
+  `certificate` MEDIUMBLOB NOT NULL COMMENT 'Self-signed X.509 PEM',
+  `created_at` DATETIME NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (`uuid`))
+ENGINE = InnoDB
+DEFAULT CHARACTER SET = utf8mb4
+COLLATE = utf8mb4_unicode_ci;
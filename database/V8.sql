@@ -0,0 +1,31 @@
+-- -----------------------------------------------------
+-- Live policy reload
+-- -----------------------------------------------------
+
+-- -----------------------------------------------------
+-- Table `policy_revision`
+-- -----------------------------------------------------
+CREATE TABLE IF NOT EXISTS `policy_revision` (
+  `id` TINYINT NOT NULL COMMENT 'Always 1, there is a single row',
+  `revision` BIGINT NOT NULL DEFAULT 1 COMMENT 'Bumped on every change to `policy_rule`, instances reload their policy when it moves',
+  PRIMARY KEY (`id`))
+ENGINE = InnoDB;
+
+INSERT INTO `policy_revision` (`id`, `revision`) VALUES (1, 1);
+
+DELIMITER $$
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_rule_AFTER_INSERT` AFTER INSERT ON `policy_rule` FOR EACH ROW
+BEGIN
+	UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_rule_AFTER_UPDATE` AFTER UPDATE ON `policy_rule` FOR EACH ROW
+BEGIN
+	UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_rule_AFTER_DELETE` AFTER DELETE ON `policy_rule` FOR EACH ROW
+BEGIN
+	UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+END$$
+DELIMITER ;
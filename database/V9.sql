@@ -0,0 +1,40 @@
+-- -----------------------------------------------------
+-- Policy delegations
+-- -----------------------------------------------------
+
+-- The triggers of V1 were created on a `delegation` table that does not exist
+DELIMITER $$
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_delegation_BEFORE_INSERT` BEFORE INSERT ON `policy_delegation` FOR EACH ROW
+BEGIN
+	INSERT INTO `object_type` (`uuid`, `type`) VALUES (NEW.`uuid`, 'POL_D');
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_delegation_BEFORE_UPDATE` BEFORE UPDATE ON `policy_delegation` FOR EACH ROW
+BEGIN
+	IF (NEW.`_revision` != OLD.`_revision` + 1) THEN
+		SIGNAL SQLSTATE '45000'
+        SET MESSAGE_TEXT = '_revision field is incorrect', MYSQL_ERRNO = 1001;
+	END IF;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_delegation_BEFORE_DELETE` BEFORE DELETE ON `policy_delegation` FOR EACH ROW
+BEGIN
+	DELETE FROM `object_type` WHERE `uuid` = OLD.`uuid`;
+END$$
+
+-- Delegations are part of the policy, instances reload them like rules
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_delegation_AFTER_INSERT` AFTER INSERT ON `policy_delegation` FOR EACH ROW
+BEGIN
+	UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_delegation_AFTER_UPDATE` AFTER UPDATE ON `policy_delegation` FOR EACH ROW
+BEGIN
+	UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+END$$
+
+CREATE DEFINER = CURRENT_USER TRIGGER `ferrocene`.`policy_delegation_AFTER_DELETE` AFTER DELETE ON `policy_delegation` FOR EACH ROW
+BEGIN
+	UPDATE `policy_revision` SET `revision` = `revision` + 1 WHERE `id` = 1;
+END$$
+DELIMITER ;
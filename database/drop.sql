@@ -1,5 +1,6 @@
 SET @OLD_FOREIGN_KEY_CHECKS=@@FOREIGN_KEY_CHECKS, FOREIGN_KEY_CHECKS=0;
 DROP INDEX IF EXISTS `fk_app_group1` ON `app`;
+DROP INDEX IF EXISTS `fk_app_user1` ON `app`;
 DROP INDEX IF EXISTS `fk_app_user_scope_scope1_idx` ON `app_user_scope`;
 DROP INDEX IF EXISTS `fk_app_user_scope_scope1` ON `app_user_scope`;
 DROP INDEX IF EXISTS `fk_app_user_scope_user1_idx` ON `app_user_scope`;
@@ -18,6 +19,10 @@ DROP INDEX IF EXISTS `fk_table1_user1` ON `password`;
 DROP INDEX IF EXISTS `fk_webauthn_user1_idx` ON `webauthn`;
 DROP INDEX IF EXISTS `fk_webauthn_user1` ON `webauthn`;
 DROP INDEX IF EXISTS `from_user_uuid_IDX` ON `policy_delegation`;
+DROP INDEX IF EXISTS `entity_id_UNIQUE` ON `saml_sp`;
+DROP INDEX IF EXISTS `expires_at_IDX` ON `oauth_code`;
+DROP INDEX IF EXISTS `expires_at_IDX` ON `oauth_device_code`;
+DROP INDEX IF EXISTS `expires_at_IDX` ON `oauth_token`;
 DROP INDEX IF EXISTS `ip_addr_peer_IDX` ON `audit`;
 DROP INDEX IF EXISTS `ip_addr_real_IDX` ON `audit`;
 DROP INDEX IF EXISTS `key_IDX` ON `kv`;
@@ -25,10 +30,15 @@ DROP INDEX IF EXISTS `name_UNIQUE` ON `group`;
 DROP INDEX IF EXISTS `name_UNIQUE` ON `password`;
 DROP INDEX IF EXISTS `name_UNIQUE` ON `scope`;
 DROP INDEX IF EXISTS `name_UNIQUE` ON `webauthn`;
+DROP INDEX IF EXISTS `parent_uuid_IDX` ON `oauth_token`;
+DROP INDEX IF EXISTS `retired_at_IDX` ON `oidc_key`;
+DROP INDEX IF EXISTS `service_owner_IDX` ON `user`;
 DROP INDEX IF EXISTS `subject_uuid_IDX` ON `audit`;
 DROP INDEX IF EXISTS `title_UNIQUE` ON `policy_rule`;
+DROP INDEX IF EXISTS `token_hash_UNIQUE` ON `oauth_token`;
 DROP INDEX IF EXISTS `to_group_uuid_IDX` ON `policy_delegation`;
 DROP INDEX IF EXISTS `to_user_uuid_IDX` ON `policy_delegation`;
+DROP INDEX IF EXISTS `user_code_UNIQUE` ON `oauth_device_code`;
 DROP INDEX IF EXISTS `user_uuid_IDX` ON `login_handle`;
 DROP INDEX IF EXISTS `users_group_UNIQUE` ON `app`;
 DROP INDEX IF EXISTS `uuid_IDX` ON `kv`;
@@ -46,9 +56,16 @@ DROP TABLE IF EXISTS `group_members`;
 DROP TABLE IF EXISTS `group_members_view`;
 DROP TABLE IF EXISTS `history`;
 DROP TABLE IF EXISTS `kv`;
+DROP TABLE IF EXISTS `oauth_code`;
+DROP TABLE IF EXISTS `oauth_device_code`;
+DROP TABLE IF EXISTS `oauth_token`;
 DROP TABLE IF EXISTS `object_type`;
+DROP TABLE IF EXISTS `oidc_key`;
 DROP TABLE IF EXISTS `password`;
+DROP TABLE IF EXISTS `policy_revision`;
 DROP TABLE IF EXISTS `policy_rule`;
+DROP TABLE IF EXISTS `saml_key`;
+DROP TABLE IF EXISTS `saml_sp`;
 DROP TABLE IF EXISTS `scope`;
 DROP TABLE IF EXISTS `session`;
 DROP TABLE IF EXISTS `session_view`;
@@ -0,0 +1,159 @@
+use crate::prelude::*;
+
+#[derive(Debug, Serialize)]
+struct AppWithSecret {
+    app: App,
+    /// Clear text client secret, this is the only time it is shown
+    client_secret: String,
+}
+
+#[get("/apps")]
+async fn list_apps_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let mut ans = vec![];
+    for app in App::load_all(&mut tx).await? {
+        if data.enforcer.is_allowed(auth.get_user().clone(), POLVERB_APP_GET, app.clone())? {
+            ans.push(app);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ans))
+}
+
+#[get("/apps/{uuid}")]
+async fn get_app_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let app = App::load_by_uuid(*path, &mut tx).await?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_APP_GET, &app)?;
+
+    Ok(HttpResponse::Ok().json(app))
+}
+
+/// Only the owners of a service account (or whoever may save it) may let an app mint client credentials tokens as it
+async fn ensure_service_account_allowed(
+    data: &AppState,
+    user: &User,
+    uuid: Uuid,
+    tx: &mut Transaction<'_>,
+) -> FResult<()> {
+    let account = match User::load_by_uuid(uuid, &User::system_super_user(), &data.enforcer, tx).await {
+        Ok(v) => v,
+        Err(err) if err.is_not_found() => return Err(FError::new_permission_error(user, POLVERB_USER_SAV, uuid)),
+        Err(err) => return Err(err),
+    };
+    if account.is_owned_by(user) {
+        return Ok(());
+    }
+    data.enforcer.ensure_allowed(user, POLVERB_USER_SAV, &account)
+}
+
+/// Pointing an app at a group lets its members in, so it takes the same permission as adding members to it
+async fn ensure_users_group_allowed(
+    data: &AppState,
+    user: &User,
+    uuid: Uuid,
+    tx: &mut Transaction<'_>,
+) -> FResult<()> {
+    let group = match Group::load_by_uuid(uuid, &User::system_super_user(), &data.enforcer, tx).await {
+        Ok(v) => v,
+        Err(err) if err.is_not_found() => {
+            return Err(FError::new_permission_error(user, POLVERB_GROUP_MEMBER_ADD_ANYKIND, uuid))
+        }
+        Err(err) => return Err(err),
+    };
+    data.enforcer.ensure_allowed(user, POLVERB_GROUP_MEMBER_ADD_ANYKIND, &group)
+}
+
+/// Registers an app (when `uuid` is `new`) or updates it
+#[put("/apps/{uuid}")]
+async fn put_app_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Json<AppChange>,
+    path: web::Path<String>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let user = auth.get_user();
+    let info = info.into_inner();
+
+    if path.as_str() != "new" {
+        let mut app = App::load_by_uuid(parse_uuid_str(&path)?, &mut tx).await?;
+        let before = app.clone();
+        app.apply_changes(info);
+        data.enforcer.ensure_allowed(user, POLVERB_APP_SAV, &app)?;
+        if let Some(uuid) = app.service_account.filter(|v| before.service_account != Some(*v)) {
+            ensure_service_account_allowed(&data, user, uuid, &mut tx).await?;
+        }
+        if app.users_group != before.users_group {
+            ensure_users_group_allowed(&data, user, app.users_group, &mut tx).await?;
+        }
+        app.save(&mut tx).await?;
+        tx.commit().await?;
+        return Ok(HttpResponse::Ok().json(app));
+    }
+
+    let name = info.name.clone().unwrap_or_default();
+    let (mut app, client_secret) = App::new(&name, "", Uuid::nil());
+    let new_group = info.users_group.is_none();
+    app.apply_changes(info);
+    if let Some(uuid) = app.service_account {
+        ensure_service_account_allowed(&data, user, uuid, &mut tx).await?;
+    }
+    if new_group {
+        // Every app gets its own group of users unless told otherwise
+        let mut group = Group::new(Uuid::new_v4(), &format!("Users of {}", name), "");
+        data.enforcer.ensure_allowed(user, POLVERB_GROUP_ADD, &group)?;
+        group.save(&mut tx).await?;
+        app.users_group = group.get_uuid();
+    } else {
+        ensure_users_group_allowed(&data, user, app.users_group, &mut tx).await?;
+    }
+    data.enforcer.ensure_allowed(user, POLVERB_APP_SAV, &app)?;
+    app.save(&mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .json(AppWithSecret { app, client_secret }))
+}
+
+#[delete("/apps/{uuid}")]
+async fn delete_app_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let app = App::load_by_uuid(*path, &mut tx).await?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_APP_DEL, &app)?;
+    App::delete(app.get_uuid(), &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Issues a new client secret, the previous one keeps working for [`APP_SECRET_GRACE`](crate::model::app::APP_SECRET_GRACE)
+#[post("/apps/{uuid}/secret")]
+async fn rotate_app_secret_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let mut app = App::load_by_uuid(*path, &mut tx).await?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_APP_SECRET_SET, &app)?;
+    let client_secret = app.rotate_secret();
+    app.save(&mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .json(AppWithSecret { app, client_secret }))
+}
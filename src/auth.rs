@@ -2,6 +2,7 @@ use actix_web::http::{HeaderMap, HeaderValue};
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use crate::oauth::get_bearer_token;
 use crate::prelude::*;
 use futures_util::future::ok;
 use futures_util::future::Future;
@@ -13,20 +14,233 @@ use std::task::{Context, Poll};
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::Error as AWError;
 
+#[derive(Debug, Deserialize)]
+pub struct ValidateQuery {
+    /// Also check that the user may access the original request (see [`HttpResource`])
+    #[serde(default)]
+    policy: bool,
+}
+
+/// Returns the first of `names` present in the request
+fn get_header<'a>(req: &'a HttpRequest, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .filter_map(|name| req.headers().get(*name))
+        .filter_map(|v| v.to_str().ok())
+        .next()
+}
+
+/// Header values must be ASCII, so user provided text is percent encoded
+fn header_safe(val: &str) -> String {
+    url::form_urlencoded::byte_serialize(val.as_bytes()).collect()
+}
+
+/// Header family the reverse proxy describes the original request with, set with `FORWARD_AUTH_HEADERS`.
+///
+/// Only the configured family is read, the proxy must overwrite (or strip) those headers since it passes the ones of
+/// the client through otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardAuthHeaders {
+    /// nginx `auth_request`: `X-Original-Method`, `X-Original-URI` and `Host`
+    Nginx,
+    /// Traefik ForwardAuth and Caddy `forward_auth`: `X-Forwarded-Method`, `X-Forwarded-Uri` and `X-Forwarded-Host`
+    Traefik,
+}
+
+impl ForwardAuthHeaders {
+    #[track_caller]
+    pub fn parse(val: &str) -> FResult<Self> {
+        match val {
+            "nginx" => Ok(ForwardAuthHeaders::Nginx),
+            "traefik" => Ok(ForwardAuthHeaders::Traefik),
+            _ => Err(FError::new_faux_panic_3("unknown forward-auth header family", val)),
+        }
+    }
+
+    /// Names of the method, URI and host headers
+    fn names(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            ForwardAuthHeaders::Nginx => ("X-Original-Method", "X-Original-URI", "Host"),
+            ForwardAuthHeaders::Traefik => ("X-Forwarded-Method", "X-Forwarded-Uri", "X-Forwarded-Host"),
+        }
+    }
+}
+
+/// Describes the request the reverse proxy is asking about, from the headers of `family` only
+pub(crate) fn get_forwarded_resource(req: &HttpRequest, family: ForwardAuthHeaders) -> Option<HttpResource> {
+    let (method, uri, host) = family.names();
+    let method = get_header(req, &[method])?;
+    let uri = get_header(req, &[uri])?;
+    let host = get_header(req, &[host]).unwrap_or_default();
+    Some(HttpResource::new(host, method, uri))
+}
+
+/// Headers describing the user to an upstream: `X-Auth-User`, `X-Auth-Name`, `X-Auth-Groups`, `X-Auth-Group-Uuids`, `X-Auth-Session` and, when applicable, `X-Auth-Email` and `X-Auth-Service-Account`
+pub(crate) fn auth_headers(auth: &FullSession) -> Vec<(&'static str, String)> {
+    let user = auth.get_user();
+    let mut groups: Vec<(Uuid, &str)> = user.groups.iter().collect();
+    groups.sort_by(|a, b| a.1.cmp(b.1));
+    let mut ans = vec![
+        ("X-Auth-User", user.get_uuid().to_string()),
+        ("X-Auth-Name", header_safe(&user.display_name)),
+        ("X-Auth-Groups", groups.iter().map(|g| header_safe(g.1)).collect::<Vec<_>>().join(",")),
+        ("X-Auth-Group-Uuids", groups.iter().map(|g| g.0.to_string()).collect::<Vec<_>>().join(",")),
+        ("X-Auth-Session", auth.get_uuid().to_string()),
+    ];
+    if let Some(email) = user.primary_email() {
+        ans.push(("X-Auth-Email", header_safe(email)));
+    }
+    if user.is_service_account() {
+        ans.push(("X-Auth-Service-Account", "true".to_string()));
+    }
+    ans
+}
+
+/// Forward-auth endpoint for nginx `auth_request`, Traefik ForwardAuth and Caddy `forward_auth`.
+///
+/// Answers 401 without a valid session (or bearer token), 403 if `policy` is set and the user may not access the original request, 200 otherwise. On success the `X-Auth-*` headers describe the user to the upstream.
 #[get("/validate")]
 pub async fn validate_endpoint(
-    _data: web::Data<AppState>,
-    auth: Option<FullSession>,
-) -> FResult<String> {
-    Ok(format!("{:?}", auth))
+    data: web::Data<AppState>,
+    info: web::Query<ValidateQuery>,
+    req: HttpRequest,
+) -> FResult<HttpResponse> {
+    // Upstreams accept any access token, not only those that may use the feroauth API
+    let auth = match FullSession::from_request_any(&req) {
+        Some(v) => v,
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+    let user = auth.get_user();
+
+    if info.policy {
+        let resource = match get_forwarded_resource(&req, data.forward_auth_headers) {
+            Some(v) => v,
+            None => {
+                warn!("Forward-auth policy check without the original method and URI headers");
+                return Ok(HttpResponse::Forbidden().finish());
+            }
+        };
+        if !data.enforcer.is_allowed(user.clone(), POLVERB_HTTP_ACCESS, resource.clone())? {
+            debug!("User {} denied access to {:?}", user.get_uuid(), resource);
+            return Ok(HttpResponse::Forbidden().finish());
+        }
+    }
+
+    let mut ans = HttpResponse::Ok();
+    ans.header(httpHeader::CACHE_CONTROL, "no-store");
+    for (name, val) in auth_headers(&auth) {
+        ans.header(name, val);
+    }
+    Ok(ans.finish())
+}
+
+/// Looks at all cookies, finds the one with the desired name and return its value as an UUID
+fn get_session_uuid(cookie_name: &str, headers: &HeaderMap) -> Option<Uuid> {
+    use actix_web::http::header::COOKIE;
+    use cookie::Cookie;
+
+    for cookie in headers.get_all(COOKIE) {
+        let cookie = match cookie.to_str() {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Failed to parse cookie {:?}: {:?}", cookie, err);
+                continue;
+            }
+        };
+        let cookie = match Cookie::parse(cookie) {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Failed to parse cookie {:?}: {:?}", cookie, err);
+                continue;
+            }
+        };
+        if cookie.name() == cookie_name {
+            use std::str::FromStr;
+
+            let val = cookie.value();
+            let val = match Uuid::from_str(val) {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("Failed to parse UUID {:?}: {:?}", val, err);
+                    continue;
+                }
+            };
+            return Some(val);
+        }
+    }
+    None
 }
 
+/// Builds a request-scoped session from an `Authorization: Bearer` OAuth access token
+async fn load_bearer_session(
+    db_pool: Arc<sqlx::Pool<sqlx::MySql>>,
+    enforcer: &PolicyEnforcer,
+    headers: &HeaderMap,
+    ip_addr_real: &str,
+    ip_addr_peer: &str,
+) -> Option<FullSession> {
+    let token = get_bearer_token(headers)?;
+    let ans: FResult<Option<FullSession>> = async {
+        let mut tx = db_pool.begin().await?;
+        let token = OAuthToken::load_by_token(&token, &mut tx).await?;
+        if token.kind != OAuthTokenKind::Access || !token.is_active() {
+            return Ok(None);
+        }
+        let user = User::load_by_uuid(token.user_uuid, &User::system_super_user(), enforcer, &mut tx).await?;
+        let user_agent = headers
+            .get(httpHeader::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        Ok(Some(FullSession::for_access_token(&token, &user, ip_addr_real, ip_addr_peer, user_agent)))
+    }
+    .await;
+    match ans {
+        Ok(v) => v,
+        Err(err) => {
+            if !err.is_not_found() {
+                warn!("Failed to load bearer token: {:?}", err);
+            }
+            None
+        }
+    }
+}
+
+/// Finds the session of a request from its session cookie, or from its bearer token if there is no cookie.
+///
+/// Used by [`SessionAuthMiddleware`] and by anything else that authenticates requests on behalf of others (e.g. ext_authz).
+pub(crate) async fn resolve_session(
+    db_pool: Arc<sqlx::Pool<sqlx::MySql>>,
+    enforcer: &PolicyEnforcer,
+    cookie_name: &str,
+    headers: &HeaderMap,
+    ip_addr_real: &str,
+    ip_addr_peer: &str,
+) -> Option<FullSession> {
+    let session_uuid = match get_session_uuid(cookie_name, headers) {
+        Some(v) => v,
+        None => return load_bearer_session(db_pool, enforcer, headers, ip_addr_real, ip_addr_peer).await,
+    };
+    match FullSession::safe_load_by_uuid(session_uuid, &User::system_super_user(), enforcer, db_pool).await {
+        Ok(v) => Some(v),
+        Err(err) => {
+            if !err.is_not_found() {
+                warn!("Failed to get session {}: {:?}", session_uuid, err);
+            }
+            None
+        }
+    }
+}
+
+/// Name of the session cookie
+pub const SESSION_COOKIE: &'static str = "feroauth";
+
 #[derive(Debug)]
 pub struct SessionAuth(Arc<sqlx::Pool<sqlx::MySql>>, PolicyEnforcer, &'static str);
 
 impl SessionAuth {
-    pub fn new(cookie_name: &'static str, db_pool: Arc<sqlx::Pool<sqlx::MySql>>) -> Self {
-        SessionAuth(db_pool, PolicyEnforcer::new().expect("PolicyEnforcer::new() should not fail"), cookie_name)
+    /// `enforcer` should be the one shared with the rest of the app, so that sessions see the same policy
+    pub fn new(cookie_name: &'static str, db_pool: Arc<sqlx::Pool<sqlx::MySql>>, enforcer: PolicyEnforcer) -> Self {
+        SessionAuth(db_pool, enforcer, cookie_name)
     }
 }
 
@@ -74,60 +288,22 @@ impl<S> SessionAuthMiddleware<S> {
         }
     }
 
-    /// Looks at all cookies, finds the one with the desired name and return its value as an UUID
-    fn get_session_uuid(&self, headers: &HeaderMap) -> Option<Uuid> {
-        use actix_web::http::header::COOKIE;
-        use cookie::Cookie;
-
-        for cookie in headers.get_all(COOKIE) {
-            let cookie = match cookie.to_str() {
-                Ok(v) => v,
-                Err(err) => {
-                    warn!("Failed to parse cookie {:?}: {:?}", cookie, err);
-                    continue;
-                }
-            };
-            let cookie = match Cookie::parse(cookie) {
-                Ok(v) => v,
-                Err(err) => {
-                    warn!("Failed to parse cookie {:?}: {:?}", cookie, err);
-                    continue;
-                }
-            };
-            if cookie.name() == self.cookie_name {
-                use std::str::FromStr;
-
-                let val = cookie.value();
-                let val = match Uuid::from_str(val) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        warn!("Failed to parse UUID {:?}: {:?}", val, err);
-                        continue;
-                    }
-                };
-                return Some(val);
-            }
-        }
-        None
-    }
-
-    /// Tries to load the session from the database and the session UUID from the cookies
+    /// Tries to load the session from the cookies, falling back to a bearer token
     async fn before_request(&mut self, req: &mut ServiceRequest) {
-        let session_uuid = match self.get_session_uuid(req.head().headers()) {
-            Some(v) => v,
-            None => return,
-        };
-        let session = match FullSession::safe_load_by_uuid(session_uuid, &User::system_super_user(), &self.enforcer, self.db_pool.clone()).await
-        {
-            Ok(v) => v,
-            Err(err) => {
-                if !err.is_not_found() {
-                    warn!("Failed to get session {}: {:?}", session_uuid, err);
-                }
-                return;
-            }
-        };
-        req.head().extensions_mut().insert(session);
+        let ip_addr_real = req.connection_info().realip_remote_addr().unwrap_or_default().to_string();
+        let ip_addr_peer = req.peer_addr().map(|v| v.ip().to_string()).unwrap_or_default();
+        let session = resolve_session(
+            self.db_pool.clone(),
+            &self.enforcer,
+            self.cookie_name,
+            req.head().headers(),
+            &ip_addr_real,
+            &ip_addr_peer,
+        )
+        .await;
+        if let Some(session) = session {
+            req.head().extensions_mut().insert(session);
+        }
     }
 
     /// sends the session id cookie to the browser
@@ -139,6 +315,9 @@ impl<S> SessionAuthMiddleware<S> {
             Some(v) => v.clone(),
             None => return,
         };
+        if session.is_ephemeral() {
+            return;
+        }
         let cookie = Cookie::build(self.cookie_name, session.get_uuid().to_string())
             .secure(true)
             .http_only(true)
@@ -181,3 +360,41 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_forwarded_resource_ignores_other_family() {
+        // A client of nginx sends its own X-Forwarded-* headers, which nginx passes through to the subrequest
+        let req = TestRequest::get()
+            .uri("/validate?policy=true")
+            .header("Host", "wiki.example.com")
+            .header("X-Original-Method", "GET")
+            .header("X-Original-URI", "/admin")
+            .header("X-Forwarded-Method", "GET")
+            .header("X-Forwarded-Uri", "/public")
+            .to_http_request();
+        let resource = get_forwarded_resource(&req, ForwardAuthHeaders::Nginx).unwrap();
+        assert_eq!("/admin", resource.path);
+        assert_eq!("wiki.example.com", resource.host);
+
+        let req = TestRequest::get()
+            .header("X-Forwarded-Method", "GET")
+            .header("X-Forwarded-Uri", "/admin")
+            .header("X-Forwarded-Host", "wiki.example.com")
+            .header("X-Original-URI", "/public")
+            .to_http_request();
+        let resource = get_forwarded_resource(&req, ForwardAuthHeaders::Traefik).unwrap();
+        assert_eq!("/admin", resource.path);
+
+        // Only the other family is there
+        let req = TestRequest::get()
+            .header("X-Forwarded-Method", "GET")
+            .header("X-Forwarded-Uri", "/public")
+            .to_http_request();
+        assert!(get_forwarded_resource(&req, ForwardAuthHeaders::Nginx).is_none());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::oauth::authenticate_client;
+use crate::prelude::*;
+use oso::ToPolar;
+
+/// Maximum number of checks in one batch
+pub const MAX_AUTHZ_BATCH: usize = 100;
+
+/// What the action is performed on
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ResourceDescriptor {
+    User { uuid: Uuid },
+    Group { uuid: Uuid },
+    App { uuid: Uuid },
+    Http { host: String, method: String, path: String },
+    /// Anything only the calling app knows about, rules see it as a string
+    Other { value: String },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthzCheck {
+    /// UUID or login handle of the user performing the action, defaults to the caller when it is a user
+    actor: Option<String>,
+    action: String,
+    resource: ResourceDescriptor,
+}
+
+#[derive(Debug, Serialize)]
+struct AuthzAnswer {
+    allow: bool,
+    /// The basic rules allow the request (e.g. superusers)
+    builtin: bool,
+    /// Policy rules that allow the request on their own
+    matched_rules: Vec<Uuid>,
+}
+
+impl From<PolicyDecision> for AuthzAnswer {
+    fn from(decision: PolicyDecision) -> Self {
+        AuthzAnswer {
+            allow: decision.allowed,
+            builtin: decision.builtin,
+            matched_rules: decision.rules,
+        }
+    }
+}
+
+/// Who is asking, sessions may only ask about users they can see while registered apps may ask about anyone
+enum Caller {
+    User(User),
+    App(App),
+}
+
+async fn get_caller(auth: Option<FullSession>, req: &HttpRequest, tx: &mut Transaction<'_>) -> FResult<Caller> {
+    match auth {
+        Some(auth) => Ok(Caller::User(auth.get_user().clone())),
+        None => Ok(Caller::App(authenticate_client(req, None, None, true, tx).await?)),
+    }
+}
+
+/// Loads a user from a UUID or a login handle
+pub(crate) async fn load_actor(
+    handle: &str,
+    as_user: &User,
+    enforcer: &PolicyEnforcer,
+    tx: &mut Transaction<'_>,
+) -> FResult<User> {
+    match Uuid::parse_str(handle) {
+        Ok(uuid) => User::load_by_uuid(uuid, as_user, enforcer, tx).await,
+        Err(_) => User::load_by_login_handle(handle, as_user, enforcer, tx).await,
+    }
+}
+
+/// What to ask the enforcer once the descriptors are resolved
+trait PolicyQuery {
+    type Output: std::fmt::Debug;
+
+    fn run<R: ToPolar + Clone>(&self, enforcer: &PolicyEnforcer, actor: &User, action: &str, resource: &R) -> FResult<Self::Output>;
+}
+
+struct Decide;
+
+impl PolicyQuery for Decide {
+    type Output = PolicyDecision;
+
+    fn run<R: ToPolar + Clone>(&self, enforcer: &PolicyEnforcer, actor: &User, action: &str, resource: &R) -> FResult<PolicyDecision> {
+        enforcer.decide(actor, action, resource)
+    }
+}
+
+struct Explain;
+
+impl PolicyQuery for Explain {
+    type Output = PolicyExplanation;
+
+    fn run<R: ToPolar + Clone>(&self, enforcer: &PolicyEnforcer, actor: &User, action: &str, resource: &R) -> FResult<PolicyExplanation> {
+        enforcer.explain(actor, action, resource)
+    }
+}
+
+/// Loads the actor and the resource of `check` and runs `query` on them
+async fn run_check<Q: PolicyQuery>(
+    data: &AppState,
+    caller: &Caller,
+    check: AuthzCheck,
+    query: Q,
+    tx: &mut Transaction<'_>,
+) -> FResult<Q::Output> {
+    let su = User::system_super_user();
+    let actor = match (&check.actor, caller) {
+        (None, Caller::User(user)) => user.clone(),
+        (None, Caller::App(_)) => {
+            return Err(FError::new(ValidationError(vec![InvalidValue::MustNotNull("actor")])));
+        }
+        (Some(handle), Caller::User(user)) => load_actor(handle, user, &data.enforcer, tx)
+            .await
+            .map_err(|err| err.hide_not_found(user, POLVERB_USER_GET, handle))?,
+        (Some(handle), Caller::App(_)) => load_actor(handle, &su, &data.enforcer, tx).await?,
+    };
+
+    // Users may only ask about objects they can see, or they could probe which ones exist and what they look like
+    let as_user = match caller {
+        Caller::User(user) => user,
+        Caller::App(_) => &su,
+    };
+    let action = check.action.as_str();
+    let enforcer = &data.enforcer;
+    let ans = match check.resource {
+        ResourceDescriptor::User { uuid } => {
+            let user = User::load_by_uuid(uuid, as_user, enforcer, tx)
+                .await
+                .map_err(|err| err.hide_not_found(as_user, POLVERB_USER_GET, uuid))?;
+            query.run(enforcer, &actor, action, &user)?
+        }
+        ResourceDescriptor::Group { uuid } => {
+            let group = Group::load_by_uuid(uuid, as_user, enforcer, tx)
+                .await
+                .map_err(|err| err.hide_not_found(as_user, POLVERB_GROUP_GET, uuid))?;
+            query.run(enforcer, &actor, action, &group)?
+        }
+        ResourceDescriptor::App { uuid } => {
+            let app = App::load_by_uuid(uuid, tx)
+                .await
+                .map_err(|err| err.hide_not_found(as_user, POLVERB_APP_GET, uuid))?;
+            if !as_user.is_system_super_user() {
+                enforcer.ensure_allowed(as_user, POLVERB_APP_GET, &app)?;
+            }
+            query.run(enforcer, &actor, action, &app)?
+        }
+        ResourceDescriptor::Http { host, method, path } => {
+            let resource = HttpResource::new(&host, &method, &path);
+            query.run(enforcer, &actor, action, &resource)?
+        }
+        ResourceDescriptor::Other { value } => query.run(enforcer, &actor, action, &value)?,
+    };
+    debug!("Policy check {:?} by {} on behalf of {}: {:?}", action, actor.get_uuid(), caller_name(caller), ans);
+    Ok(ans)
+}
+
+fn caller_name(caller: &Caller) -> String {
+    match caller {
+        Caller::User(user) => format!("user {}", user.get_uuid()),
+        Caller::App(app) => format!("app {}", app.get_uuid()),
+    }
+}
+
+/// Asks the policy whether an actor may perform an action on a resource.
+///
+/// Callers authenticate either with a session (or bearer token) or as a registered app with HTTP Basic client credentials.
+#[post("/authz/check")]
+async fn check_endpoint(
+    data: web::Data<AppState>,
+    auth: Option<FullSession>,
+    info: web::Json<AuthzCheck>,
+    req: HttpRequest,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let caller = get_caller(auth, &req, &mut tx).await?;
+    let ans: AuthzAnswer = run_check(&data, &caller, info.into_inner(), Decide, &mut tx).await?.into();
+
+    Ok(HttpResponse::Ok().header(httpHeader::CACHE_CONTROL, "no-store").json(ans))
+}
+
+/// Same as `/authz/check` for up to [`MAX_AUTHZ_BATCH`] checks, answers are in the same order
+#[post("/authz/check/batch")]
+async fn check_batch_endpoint(
+    data: web::Data<AppState>,
+    auth: Option<FullSession>,
+    info: web::Json<Vec<AuthzCheck>>,
+    req: HttpRequest,
+) -> FResult<HttpResponse> {
+    let checks = info.into_inner();
+    if checks.len() > MAX_AUTHZ_BATCH {
+        return Err(FError::new(ValidationError(vec![InvalidValue::OutOfRange("checks", 0, MAX_AUTHZ_BATCH)])));
+    }
+    let mut tx = data.db.begin().await?;
+    let caller = get_caller(auth, &req, &mut tx).await?;
+    let mut ans = Vec::with_capacity(checks.len());
+    for item in checks {
+        ans.push(AuthzAnswer::from(run_check(&data, &caller, item, Decide, &mut tx).await?));
+    }
+
+    Ok(HttpResponse::Ok().header(httpHeader::CACHE_CONTROL, "no-store").json(ans))
+}
+
+/// Tells admins why a check is allowed or denied: which basic or DB rules allow it alone and which ones it depends on.
+///
+/// Needs `POLVERB_POLICY_RULE_GET` on the actor of the check.
+#[post("/policy/explain")]
+async fn explain_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Json<AuthzCheck>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let user = auth.get_user();
+    let mut info = info.into_inner();
+    let actor = match &info.actor {
+        Some(handle) => load_actor(handle, &User::system_super_user(), &data.enforcer, &mut tx)
+            .await
+            .map_err(|err| err.hide_not_found(user, POLVERB_USER_GET, handle))?,
+        None => user.clone(),
+    };
+    data.enforcer.ensure_allowed(user, POLVERB_POLICY_RULE_GET, &actor)?;
+    // Permission is already checked, the explanation is about the actor and not about the admin
+    info.actor = Some(actor.get_uuid().to_string());
+    let caller = Caller::User(User::system_super_user());
+    let ans = run_check(&data, &caller, info, Explain, &mut tx).await?;
+
+    Ok(HttpResponse::Ok().json(ans))
+}
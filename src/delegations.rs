@@ -0,0 +1,135 @@
+use crate::policy_rules::reload_enforcer;
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize)]
+struct DelegationRequest {
+    /// User or group receiving the delegation
+    to: UuidObjectOption,
+    action: String,
+    resource: UuidObjectOption,
+    valid_until: DateTime<Utc>,
+    #[serde(default)]
+    desc: String,
+}
+
+/// Checks that `user` may delegate `action` on `resource`, i.e. the policy rules grant it to them directly.
+///
+/// Only single objects can be checked precisely, for groups of objects (or no object) any grant of `action` is
+/// enough since every use of the delegation checks `user_allowed` for the giver again.
+async fn ensure_may_delegate(
+    data: &AppState,
+    user: &User,
+    action: &str,
+    resource: &UuidObjectOption,
+    tx: &mut Transaction<'_>,
+) -> FResult<()> {
+    let enforcer = &data.enforcer;
+    let allowed = match resource {
+        OneObject(uuid) => {
+            let object = MinObject::load_by_uuid(*uuid, tx).await?;
+            match object.get_kind() {
+                "USER" => {
+                    let resource = User::load_by_uuid(*uuid, &User::system_super_user(), enforcer, tx).await?;
+                    enforcer.user_allowed(user, action, &resource)?
+                }
+                "GROUP" => enforcer.user_allowed(user, action, &Group::load_by_uuid(*uuid, &User::system_super_user(), enforcer, tx).await?)?,
+                "APP" => enforcer.user_allowed(user, action, &App::load_by_uuid(*uuid, tx).await?)?,
+                "POL_RULE" => enforcer.user_allowed(user, action, &PolicyRule::load_by_uuid(*uuid, tx).await?)?,
+                _ => enforcer.user_allowed_any(user, action)?,
+            }
+        }
+        ObjectsInGroup(_) | NoObject => enforcer.user_allowed_any(user, action)?,
+    };
+    if !allowed {
+        return Err(FError::new_permission_error(user, action, resource));
+    }
+    Ok(())
+}
+
+/// Checks that `to` is an existing user or group, unknown objects and other kinds get the same answer
+async fn ensure_valid_recipient(to: &UuidObjectOption, tx: &mut Transaction<'_>) -> FResult<()> {
+    let uuid = match to.to_uuid_option() {
+        Some(v) => v,
+        None => return Err(FError::new(ValidationError(vec![InvalidValue::MustNotNull("to")]))),
+    };
+    let object = match MinObject::load_by_uuid(uuid, tx).await {
+        Ok(v) => v,
+        Err(err) if err.is_not_found() => return Err(FError::new(ValidationError(vec![InvalidValue::BadFormat("to")]))),
+        Err(err) => return Err(err),
+    };
+    match object.get_kind() {
+        "USER" | "GROUP" => Ok(()),
+        _ => Err(FError::new(ValidationError(vec![InvalidValue::BadFormat("to")]))),
+    }
+}
+
+/// Delegates one of the caller's permissions to a user or a group until `valid_until`
+#[post("/delegations")]
+async fn post_delegation_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Json<DelegationRequest>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let user = auth.get_user();
+    let info = info.into_inner();
+    ensure_valid_recipient(&info.to, &mut tx).await?;
+    ensure_may_delegate(&data, user, &info.action, &info.resource, &mut tx).await?;
+    let mut delegation = PolicyDelegation::new(
+        user.get_uuid(),
+        info.to,
+        &info.action,
+        info.resource,
+        info.valid_until,
+        &info.desc,
+    );
+    delegation.save(&mut tx).await?;
+    tx.commit().await?;
+    reload_enforcer(&data).await;
+
+    Ok(HttpResponse::Ok().json(delegation))
+}
+
+#[get("/delegations/given")]
+async fn list_given_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let ans = PolicyDelegation::load_given(auth.get_user().get_uuid(), &mut tx).await?;
+
+    Ok(HttpResponse::Ok().json(ans))
+}
+
+#[get("/delegations/received")]
+async fn list_received_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let ans = PolicyDelegation::load_received(auth.get_user(), &mut tx).await?;
+
+    Ok(HttpResponse::Ok().json(ans))
+}
+
+/// Revokes a delegation, either side of it (or a superuser) may do so
+#[delete("/delegations/{uuid}")]
+async fn delete_delegation_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let user = auth.get_user();
+    let delegation = match PolicyDelegation::load_by_uuid(*path, &mut tx).await {
+        Ok(v) if v.get_from() == user.get_uuid() || v.is_granted_to(user) || user.superuser => v,
+        // Do not tell others whether the delegation exists
+        Ok(_) => return Ok(HttpResponse::NotFound().finish()),
+        Err(err) => return Err(err),
+    };
+    PolicyDelegation::delete(delegation.get_uuid(), &mut tx).await?;
+    tx.commit().await?;
+    reload_enforcer(&data).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,300 @@
+use crate::oauth::{authenticate_client, ensure_interactive, ensure_user_still_allowed, issue_tokens, TokenResponse};
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEVICE_CODE_GRANT: &'static str = "urn:ietf:params:oauth:grant-type:device_code";
+/// Wrong user codes a user may type within [`USER_CODE_FAILURE_WINDOW`] before lookups are refused
+pub const USER_CODE_MAX_FAILURES: u32 = 5;
+pub const USER_CODE_FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Verification page where the user types the code shown by the device, it talks to `/oauth/device`
+const VERIFICATION_PAGE: &'static str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Connect a device</title>
+</head>
+<body>
+<h1>Connect a device</h1>
+<form id="lookup">
+<label>Code shown on the device <input id="user_code" autocomplete="off" required></label>
+<button type="submit">Continue</button>
+</form>
+<div id="consent" hidden>
+<p><strong id="app"></strong> is asking for:</p>
+<ul id="scopes"></ul>
+<button id="approve">Approve</button> <button id="deny">Deny</button>
+</div>
+<p id="message"></p>
+<script>
+const $ = (id) => document.getElementById(id);
+const show = (msg) => { $("message").textContent = msg; };
+async function lookup(code) {
+  const resp = await fetch("oauth/device?user_code=" + encodeURIComponent(code), { credentials: "same-origin" });
+  if (resp.status === 401) return show("Please log in first, then come back to this page.");
+  const body = await resp.json().catch(() => ({}));
+  if (!resp.ok) return show(body.error_description || "Unknown or expired code.");
+  $("app").textContent = body.app.name;
+  $("scopes").replaceChildren(...body.scopes.map((s) => {
+    const li = document.createElement("li");
+    li.textContent = s.desc || s.name;
+    return li;
+  }));
+  $("lookup").hidden = true;
+  $("consent").hidden = false;
+  show("");
+}
+async function decide(approve) {
+  const resp = await fetch("oauth/device", {
+    method: "POST",
+    credentials: "same-origin",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ user_code: $("user_code").value, approve }),
+  });
+  $("consent").hidden = true;
+  show(resp.ok ? (approve ? "Done, you can go back to your device." : "The request was denied.") : "Something went wrong, please try again.");
+}
+$("lookup").addEventListener("submit", (e) => { e.preventDefault(); lookup($("user_code").value); });
+$("approve").addEventListener("click", () => decide(true));
+$("deny").addEventListener("click", () => decide(false));
+const code = new URLSearchParams(location.search).get("user_code");
+if (code) { $("user_code").value = code; lookup(code); }
+</script>
+</body>
+</html>
+"#;
+
+/// Counts wrong user codes per user, user codes are short enough to be guessed otherwise
+#[derive(Debug, Clone, Default)]
+pub struct UserCodeLimiter(Arc<Mutex<HashMap<Uuid, (Instant, u32)>>>);
+
+impl UserCodeLimiter {
+    pub fn new() -> Self {
+        UserCodeLimiter::default()
+    }
+
+    fn ensure_allowed(&self, user: Uuid) -> FResult<()> {
+        let mut limiter = self.0.lock().map_err(|_| FError::new(LockError))?;
+        let now = Instant::now();
+        limiter.retain(|_, (since, _)| now < *since + USER_CODE_FAILURE_WINDOW);
+        match limiter.get(&user) {
+            Some((_, failures)) if *failures >= USER_CODE_MAX_FAILURES => {
+                Err(FError::new_oauth_error("slow_down", "too many wrong codes, try again later"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn record_failure(&self, user: Uuid) -> FResult<()> {
+        let mut limiter = self.0.lock().map_err(|_| FError::new(LockError))?;
+        limiter.entry(user).or_insert((Instant::now(), 0)).1 += 1;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorizationRequest {
+    client_id: Option<Uuid>,
+    client_secret: Option<String>,
+    #[serde(default)]
+    scope: String,
+}
+
+/// Answer as defined in RFC 8628 section 3.2
+#[derive(Debug, Serialize)]
+struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: i64,
+    interval: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceQuery {
+    user_code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceConsent {
+    user_code: String,
+    approve: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceConsentInfo {
+    user_code: String,
+    app: MinApp,
+    scopes: Vec<Scope>,
+}
+
+#[post("/oauth/device_authorization")]
+async fn device_authorization_endpoint(
+    data: web::Data<AppState>,
+    info: web::Form<DeviceAuthorizationRequest>,
+    req: HttpRequest,
+) -> FResult<HttpResponse> {
+    let info = info.into_inner();
+    let mut tx = data.db.begin().await?;
+    let app = authenticate_client(&req, info.client_id, info.client_secret, false, &mut tx).await?;
+
+    let (scopes, unknown) = Scope::load_by_oauth_names(&info.scope, &mut tx).await?;
+    if unknown.len() != 0 {
+        return Err(FError::new_oauth_error("invalid_scope", &format!("unknown scopes: {}", unknown.join(" "))));
+    }
+
+    let (code, device_code) = OAuthDeviceCode::new(app.get_uuid(), &Scope::to_oauth_str(&scopes));
+    code.save(&mut tx).await?;
+    tx.commit().await?;
+
+    let verification_uri = format!("{}/device", data.issuer);
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .json(DeviceAuthorizationResponse {
+            device_code,
+            user_code: code.display_user_code(),
+            verification_uri_complete: format!("{}?user_code={}", verification_uri, code.user_code),
+            verification_uri,
+            expires_in: code.expires_in(),
+            interval: code.interval,
+        }))
+}
+
+/// Loads the pending request the user typed in and checks they may use the app
+async fn load_for_user(
+    data: &AppState,
+    user_code: &str,
+    user: &User,
+    tx: &mut Transaction<'_>,
+) -> FResult<(OAuthDeviceCode, App, Vec<Scope>)> {
+    data.user_code_limiter.ensure_allowed(user.get_uuid())?;
+    let code = match OAuthDeviceCode::load_pending_by_user_code(user_code, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                data.user_code_limiter.record_failure(user.get_uuid())?;
+            }
+            return Err(err);
+        }
+    };
+    let app = App::load_by_uuid(code.app_uuid, tx).await?;
+    if !app.is_user_allowed(user) {
+        return Err(FError::new_oauth_error("access_denied", "user is not allowed to use this app"));
+    }
+    let (scopes, _) = Scope::load_by_oauth_names(&code.scope, tx).await?;
+    Ok((code, app, scopes))
+}
+
+/// The `verification_uri` of RFC 8628 section 3.2
+#[get("/device")]
+async fn verification_page_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .body(VERIFICATION_PAGE)
+}
+
+/// Used by the verification page to show the user what they are about to approve
+#[get("/oauth/device")]
+async fn get_device_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Query<DeviceQuery>,
+) -> FResult<HttpResponse> {
+    ensure_interactive(&auth)?;
+    let mut tx = data.db.begin().await?;
+    let (code, app, scopes) = load_for_user(&data, &info.user_code, auth.get_user(), &mut tx).await?;
+
+    Ok(HttpResponse::Ok().json(DeviceConsentInfo {
+        user_code: code.display_user_code(),
+        app: app.to_min_app(),
+        scopes,
+    }))
+}
+
+#[post("/oauth/device")]
+async fn post_device_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Json<DeviceConsent>,
+) -> FResult<HttpResponse> {
+    ensure_interactive(&auth)?;
+    let mut tx = data.db.begin().await?;
+    let user = auth.get_user();
+    let (mut code, app, scopes) = load_for_user(&data, &info.user_code, user, &mut tx).await?;
+
+    if info.approve {
+        Scope::grant(app.get_uuid(), user.get_uuid(), &scopes, &mut tx).await?;
+    }
+    code.decide(user.get_uuid(), info.approve, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(code.status))
+}
+
+/// Exchanges an approved device code for tokens (RFC 8628 section 3.4)
+pub(crate) async fn token_from_device_code(
+    data: &AppState,
+    app: &App,
+    device_code: Option<&str>,
+    tx: &mut Transaction<'_>,
+) -> FResult<TokenResponse> {
+    let device_code = match device_code {
+        Some(v) => v,
+        None => return Err(FError::new_oauth_error("invalid_request", "missing device_code")),
+    };
+    let mut code = match OAuthDeviceCode::load_by_device_code(device_code, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new_oauth_error("invalid_grant", "invalid device_code"));
+            }
+            return Err(err);
+        }
+    };
+    if code.app_uuid != app.get_uuid() {
+        return Err(FError::new_oauth_error("invalid_grant", "device_code was issued to another client"));
+    }
+    if code.is_expired() {
+        code.delete(tx).await?;
+        return Err(FError::new_oauth_error("expired_token", "device_code has expired"));
+    }
+    if !code.mark_polled(tx).await? {
+        return Err(FError::new_oauth_error("slow_down", &format!("poll at most every {} seconds", code.interval)));
+    }
+
+    match (code.status, code.user_uuid) {
+        (DeviceCodeStatus::Pending, _) => Err(FError::new_oauth_error("authorization_pending", "the user has not answered yet")),
+        (DeviceCodeStatus::Denied, _) => {
+            code.delete(tx).await?;
+            Err(FError::new_oauth_error("access_denied", "the user denied the request"))
+        }
+        (DeviceCodeStatus::Approved, Some(user_uuid)) => {
+            code.delete(tx).await?;
+            ensure_user_still_allowed(app, user_uuid, &data.enforcer, tx).await?;
+            issue_tokens(data, app, user_uuid, &code.scope, None, tx).await
+        }
+        (DeviceCodeStatus::Approved, None) => Err(FError::new_faux_panic_1("approved device code without user")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_code_limiter() {
+        let limiter = UserCodeLimiter::new();
+        let (user, other) = (Uuid::new_v4(), Uuid::new_v4());
+        for _ in 0..USER_CODE_MAX_FAILURES {
+            assert!(limiter.ensure_allowed(user).is_ok());
+            limiter.record_failure(user).unwrap();
+        }
+        assert!(limiter.ensure_allowed(user).unwrap_err().is_oauth());
+        assert!(limiter.ensure_allowed(other).is_ok());
+    }
+}
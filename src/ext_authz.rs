@@ -0,0 +1,136 @@
+use crate::auth::{auth_headers, resolve_session, SESSION_COOKIE};
+use crate::prelude::*;
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue};
+use pb::authorization_server::Authorization;
+pub use pb::authorization_server::AuthorizationServer;
+use pb::check_response::HttpResponse as CheckHttpResponse;
+use tonic::{Request, Response, Status};
+
+/// Types generated from `proto/ext_authz.proto`
+pub mod pb {
+    tonic::include_proto!("envoy.service.auth.v3");
+}
+
+// google.rpc.Code
+const GRPC_OK: i32 = 0;
+const GRPC_PERMISSION_DENIED: i32 = 7;
+const GRPC_UNAUTHENTICATED: i32 = 16;
+
+/// Envoy `ext_authz` gRPC service (`envoy.service.auth.v3.Authorization/Check`).
+///
+/// Authenticates exactly like [`SessionAuthMiddleware`](crate::auth::SessionAuthMiddleware) and then asks the enforcer about [`POLVERB_HTTP_ACCESS`] on the request's [`HttpResource`].
+#[derive(Debug, Clone)]
+pub struct ExtAuthz {
+    db: Arc<sqlx::Pool<sqlx::MySql>>,
+    enforcer: PolicyEnforcer,
+}
+
+impl ExtAuthz {
+    pub fn new(db: Arc<sqlx::Pool<sqlx::MySql>>, enforcer: PolicyEnforcer) -> Self {
+        ExtAuthz { db, enforcer }
+    }
+
+    /// Envoy only normalizes `:path` when told to (`normalize_path`, `merge_slashes`), so [`HttpResource::new`] does it again
+    fn resource_of(http: &pb::attribute_context::HttpRequest) -> HttpResource {
+        HttpResource::new(&http.host, &http.method, &http.path)
+    }
+
+    fn denied(grpc_code: i32, http_code: i32) -> pb::CheckResponse {
+        pb::CheckResponse {
+            status: Some(pb::Status {
+                code: grpc_code,
+                message: String::new(),
+            }),
+            http_response: Some(CheckHttpResponse::DeniedResponse(pb::DeniedHttpResponse {
+                status: Some(pb::HttpStatus { code: http_code }),
+                headers: vec![],
+                body: String::new(),
+            })),
+        }
+    }
+
+    fn allowed(auth: &FullSession) -> pb::CheckResponse {
+        let headers = auth_headers(auth)
+            .into_iter()
+            .map(|(key, value)| pb::HeaderValueOption {
+                header: Some(pb::HeaderValue {
+                    key: key.to_string(),
+                    value,
+                }),
+                // Overwrite so the client can not impersonate someone by sending these itself
+                append: Some(pb::BoolValue { value: false }),
+            })
+            .collect();
+        pb::CheckResponse {
+            status: Some(pb::Status {
+                code: GRPC_OK,
+                message: String::new(),
+            }),
+            http_response: Some(CheckHttpResponse::OkResponse(pb::OkHttpResponse { headers })),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Authorization for ExtAuthz {
+    async fn check(&self, request: Request<pb::CheckRequest>) -> Result<Response<pb::CheckResponse>, Status> {
+        let http = request
+            .into_inner()
+            .attributes
+            .and_then(|v| v.request)
+            .and_then(|v| v.http)
+            .ok_or_else(|| Status::invalid_argument("missing attributes.request.http"))?;
+
+        let mut headers = HeaderMap::new();
+        for (key, value) in &http.headers {
+            if let (Ok(key), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(value)) {
+                headers.append(key, value);
+            }
+        }
+        let ip_addr_real = http
+            .headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.split(',').next())
+            .map(|v| v.trim())
+            .unwrap_or_default();
+
+        let auth = match resolve_session(self.db.clone(), &self.enforcer, SESSION_COOKIE, &headers, ip_addr_real, "").await {
+            Some(v) => v,
+            None => return Ok(Response::new(ExtAuthz::denied(GRPC_UNAUTHENTICATED, 401))),
+        };
+
+        let resource = ExtAuthz::resource_of(&http);
+        let allowed = self
+            .enforcer
+            .is_allowed(auth.get_user().clone(), POLVERB_HTTP_ACCESS, resource.clone())
+            .map_err(|err| {
+                error!("Failed to evaluate policy for {:?}: {:?}", resource, err);
+                Status::internal("policy evaluation failed")
+            })?;
+        if !allowed {
+            debug!("User {} denied access to {:?}", auth.get_user().get_uuid(), resource);
+            return Ok(Response::new(ExtAuthz::denied(GRPC_PERMISSION_DENIED, 403)));
+        }
+        Ok(Response::new(ExtAuthz::allowed(&auth)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_of_normalizes_path() {
+        for path in &["/public/../admin", "//admin", "/%61dmin", "/public/%2e%2e/admin?a=b"] {
+            let http = pb::attribute_context::HttpRequest {
+                method: "get".to_string(),
+                host: "wiki.example.com".to_string(),
+                path: path.to_string(),
+                ..Default::default()
+            };
+            let resource = ExtAuthz::resource_of(&http);
+            assert_eq!("/admin", resource.path, "{}", path);
+            assert_eq!("GET", resource.method);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::prelude::*;
+use crate::model::group::DEFAULT_GROUP_MEMBERS_PAGE;
+
+#[derive(Debug, Deserialize)]
+struct MembersQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// Optional bounds of a membership, e.g. for contractors or on-call rotations
+#[derive(Debug, Deserialize, Default)]
+struct MemberBounds {
+    valid_from: Option<DateTime<Utc>>,
+    valid_until: Option<DateTime<Utc>>,
+}
+
+/// Answers 403 for unknown groups too, so that existence does not leak to those who may not see them
+fn hide_not_found(err: FError, as_user: &User, uuid: Uuid) -> FError {
+    err.hide_not_found(as_user, POLVERB_GROUP_GET, uuid)
+}
+
+/// Tells whether `user` may see the display name of `object`, i.e. whether they may get the object itself
+async fn may_see_name(data: &AppState, user: &User, object: &MinObject, tx: &mut Transaction<'_>) -> FResult<bool> {
+    let su = User::system_super_user();
+    let uuid = object.get_uuid();
+    let ans = match object.get_kind() {
+        "USER" => data.enforcer.is_allowed(user.clone(), POLVERB_USER_GET, User::load_by_uuid(uuid, &su, &data.enforcer, tx).await?)?,
+        "GROUP" => data.enforcer.is_allowed(user.clone(), POLVERB_GROUP_GET, Group::load_by_uuid(uuid, &su, &data.enforcer, tx).await?)?,
+        "APP" => data.enforcer.is_allowed(user.clone(), POLVERB_APP_GET, App::load_by_uuid(uuid, tx).await?)?,
+        // Other kinds have no name
+        _ => true,
+    };
+    Ok(ans)
+}
+
+/// Keeps the UUID and kind of members, as they are part of the group, but only the names `user` may see
+async fn hide_member_names<'a>(
+    data: &AppState,
+    user: &User,
+    members: impl IntoIterator<Item = &'a mut MinObject>,
+    tx: &mut Transaction<'_>,
+) -> FResult<()> {
+    if user.superuser {
+        return Ok(());
+    }
+    for member in members {
+        if member.get_name().is_some() && !may_see_name(data, user, member, tx).await? {
+            member.hide_name();
+        }
+    }
+    Ok(())
+}
+
+/// Loads a group for `user` with the member names they may not see removed
+async fn load_group_view(data: &AppState, user: &User, uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<Group> {
+    let mut group = Group::load_by_uuid(uuid, user, &data.enforcer, tx)
+        .await
+        .map_err(|err| hide_not_found(err, user, uuid))?;
+    if let Some(members) = group.get_members_mut() {
+        hide_member_names(data, user, members.iter_mut(), tx).await?;
+    }
+    Ok(group)
+}
+
+#[get("/groups")]
+async fn list_groups_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let mut ans = vec![];
+    for group in Group::load_all(&mut tx).await? {
+        if data.enforcer.is_allowed(auth.get_user().clone(), POLVERB_GROUP_GET, group.clone())? {
+            ans.push(group);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ans))
+}
+
+#[get("/groups/{uuid}")]
+async fn get_group_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let group = load_group_view(&data, auth.get_user(), *path, &mut tx).await?;
+
+    Ok(HttpResponse::Ok().json(group))
+}
+
+/// Members at any depth, one page at a time
+#[get("/groups/{uuid}/members")]
+async fn get_members_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+    info: web::Query<MembersQuery>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let group = Group::load_by_uuid(*path, auth.get_user(), &data.enforcer, &mut tx)
+        .await
+        .map_err(|err| hide_not_found(err, auth.get_user(), *path))?;
+    let limit = info.limit.unwrap_or(DEFAULT_GROUP_MEMBERS_PAGE);
+    let mut page = Group::load_members_page(group.get_uuid(), info.offset, limit, &mut tx).await?;
+    hide_member_names(&data, auth.get_user(), page.members.iter_mut().map(|m| &mut m.object), &mut tx).await?;
+
+    Ok(HttpResponse::Ok().json(page))
+}
+
+/// Creates a group (when `uuid` is `new`), renames it or changes its description
+#[put("/groups/{uuid}")]
+async fn put_group_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Json<GroupChange>,
+    path: web::Path<String>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let user = auth.get_user();
+    let info = info.into_inner();
+
+    let group = match path.as_str() {
+        "new" => {
+            let mut group = Group::new(Uuid::new_v4(), "", "");
+            group.apply_changes(info)?;
+            data.enforcer.ensure_allowed(user, POLVERB_GROUP_ADD, &group)?;
+            group.save(&mut tx).await?;
+            group
+        }
+        _ => {
+            let uuid = parse_uuid_str(&path)?;
+            let mut group = Group::load_by_uuid(uuid, user, &data.enforcer, &mut tx)
+                .await
+                .map_err(|err| hide_not_found(err, user, uuid))?;
+            for verb in group.clone().apply_changes(info.clone())? {
+                data.enforcer.ensure_allowed(user, verb, &group)?;
+            }
+            group.apply_changes(info)?;
+            group.save(&mut tx).await?;
+            load_group_view(&data, user, uuid, &mut tx).await?
+        }
+    };
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(group))
+}
+
+#[delete("/groups/{uuid}")]
+async fn delete_group_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let group = Group::load_by_uuid(*path, auth.get_user(), &data.enforcer, &mut tx)
+        .await
+        .map_err(|err| hide_not_found(err, auth.get_user(), *path))?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_GROUP_DEL, &group)?;
+    Group::delete(group.get_uuid(), &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Adds a user, group or any other object to a group, the body may bound the membership in time
+#[put("/groups/{uuid}/members/{member}")]
+async fn put_member_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<(Uuid, Uuid)>,
+    info: Option<web::Json<MemberBounds>>,
+) -> FResult<HttpResponse> {
+    let (uuid, member) = path.into_inner();
+    let bounds = info.map(|v| v.into_inner()).unwrap_or_default();
+    let mut tx = data.db.begin().await?;
+    let group = Group::load_by_uuid(uuid, auth.get_user(), &data.enforcer, &mut tx)
+        .await
+        .map_err(|err| hide_not_found(err, auth.get_user(), uuid))?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_GROUP_MEMBER_ADD_ANYKIND, &group)?;
+    // Unknown members get a 403 as well, the caller may not be allowed to see them
+    MinObject::load_by_uuid(member, &mut tx)
+        .await
+        .map_err(|err| err.hide_not_found(auth.get_user(), POLVERB_GROUP_MEMBER_ADD_ANYKIND, member))?;
+    Group::add_member(uuid, member, bounds.valid_from, bounds.valid_until, &mut tx).await?;
+    let ans = load_group_view(&data, auth.get_user(), uuid, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(ans))
+}
+
+#[delete("/groups/{uuid}/members/{member}")]
+async fn delete_member_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<(Uuid, Uuid)>,
+) -> FResult<HttpResponse> {
+    let (uuid, member) = path.into_inner();
+    let mut tx = data.db.begin().await?;
+    let group = Group::load_by_uuid(uuid, auth.get_user(), &data.enforcer, &mut tx)
+        .await
+        .map_err(|err| hide_not_found(err, auth.get_user(), uuid))?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_GROUP_MEMBER_DEL_ANYKIND, &group)?;
+    Group::remove_member(uuid, member, &mut tx).await?;
+    let ans = load_group_view(&data, auth.get_user(), uuid, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(ans))
+}
@@ -0,0 +1,164 @@
+use crate::oauth::authenticate_client;
+use crate::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long an introspection answer is reused before the database is queried again
+pub const INTROSPECTION_CACHE_TTL: Duration = Duration::from_secs(30);
+pub const INTROSPECTION_CACHE_MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+struct IntrospectRequest {
+    token: String,
+    token_type_hint: Option<String>,
+    /// Non standard: also report the UUIDs of the groups (direct or indirect) the user belongs to
+    #[serde(default)]
+    include_groups: bool,
+    client_id: Option<Uuid>,
+    client_secret: Option<String>,
+}
+
+/// Answer as defined in RFC 7662 section 2.2
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct IntrospectResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<Uuid>>,
+}
+
+/// Short lived in-process cache of introspection answers keyed by token hash.
+///
+/// Revocations made by other instances may take up to [`INTROSPECTION_CACHE_TTL`] to be noticed.
+#[derive(Debug, Clone, Default)]
+pub struct IntrospectionCache(Arc<Mutex<HashMap<(Vec<u8>, bool), (Instant, IntrospectResponse)>>>);
+
+impl IntrospectionCache {
+    pub fn new() -> Self {
+        IntrospectionCache::default()
+    }
+
+    fn get(&self, token_hash: &[u8], include_groups: bool) -> Option<IntrospectResponse> {
+        let cache = self.0.lock().ok()?;
+        let (valid_until, ans) = cache.get(&(token_hash.to_vec(), include_groups))?;
+        if *valid_until < Instant::now() {
+            return None;
+        }
+        Some(ans.clone())
+    }
+
+    fn insert(&self, token_hash: Vec<u8>, include_groups: bool, ans: &IntrospectResponse) {
+        let mut cache = match self.0.lock() {
+            Ok(v) => v,
+            Err(err) => {
+                error!("Introspection cache is poisoned: {:?}", err);
+                return;
+            }
+        };
+        let now = Instant::now();
+        if cache.len() >= INTROSPECTION_CACHE_MAX_ENTRIES {
+            cache.retain(|_, (valid_until, _)| *valid_until >= now);
+        }
+        if cache.len() >= INTROSPECTION_CACHE_MAX_ENTRIES {
+            cache.clear();
+        }
+
+        // Never cache an active answer past the token's expiration
+        let mut ttl = INTROSPECTION_CACHE_TTL;
+        if let Some(exp) = ans.exp {
+            let left = (exp - Utc::now().timestamp()).max(0) as u64;
+            ttl = ttl.min(Duration::from_secs(left));
+        }
+        cache.insert((token_hash, include_groups), (now + ttl, ans.clone()));
+    }
+
+    /// Forgets the cached answers about the tokens (e.g. because they were revoked)
+    pub fn invalidate(&self, token_hashes: &[Vec<u8>]) {
+        if let Ok(mut cache) = self.0.lock() {
+            for token_hash in token_hashes {
+                cache.remove(&(token_hash.clone(), false));
+                cache.remove(&(token_hash.clone(), true));
+            }
+        }
+    }
+}
+
+async fn introspect(
+    data: &AppState,
+    token: &str,
+    include_groups: bool,
+    tx: &mut Transaction<'_>,
+) -> FResult<IntrospectResponse> {
+    let token = match OAuthToken::load_by_token(token, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Ok(IntrospectResponse::default());
+            }
+            return Err(err);
+        }
+    };
+    if !token.is_active() {
+        return Ok(IntrospectResponse::default());
+    }
+
+    let mut ans = IntrospectResponse {
+        active: true,
+        scope: Some(token.scope.clone()),
+        client_id: Some(token.app_uuid),
+        sub: Some(token.user_uuid),
+        token_type: Some(match token.kind {
+            OAuthTokenKind::Access => "Bearer",
+            OAuthTokenKind::Refresh => "refresh_token",
+        }),
+        exp: Some(token.expires_at.timestamp()),
+        iat: Some(token.issued_at.timestamp()),
+        groups: None,
+    };
+    if include_groups {
+        let user = User::load_by_uuid(token.user_uuid, &User::system_super_user(), &data.enforcer, tx).await?;
+        let mut groups: Vec<Uuid> = user.groups.to_keys_set().into_iter().collect();
+        groups.sort();
+        ans.groups = Some(groups);
+    }
+    Ok(ans)
+}
+
+/// Token introspection as defined in RFC 7662. Only apps with a client secret may call it.
+#[post("/oauth/introspect")]
+async fn introspect_endpoint(
+    data: web::Data<AppState>,
+    info: web::Form<IntrospectRequest>,
+    req: HttpRequest,
+) -> FResult<HttpResponse> {
+    let info = info.into_inner();
+    let mut tx = data.db.begin().await?;
+    let app = authenticate_client(&req, info.client_id, info.client_secret.clone(), true, &mut tx).await?;
+    trace!("App {} introspecting token (hint: {:?})", app.get_uuid(), info.token_type_hint);
+
+    let token_hash = hash_secret(&info.token);
+    let ans = match data.introspection_cache.get(&token_hash, info.include_groups) {
+        Some(v) => v,
+        None => {
+            let ans = introspect(&data, &info.token, info.include_groups, &mut tx).await?;
+            data.introspection_cache.insert(token_hash, info.include_groups, &ans);
+            ans
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .json(ans))
+}
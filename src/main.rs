@@ -1,7 +1,18 @@
+mod apps;
 mod auth;
+mod authz;
+mod delegations;
+mod device;
+mod ext_authz;
+mod groups;
+mod introspect;
 mod misc;
 mod model;
+mod oauth;
+mod oidc;
+mod policy_rules;
 mod prelude;
+mod saml;
 mod users;
 
 #[macro_use]
@@ -29,12 +40,116 @@ async fn main() -> FResult<()> {
     let db_pool = model::db::get_pool(&db_host, &db_user, &db_pass, &db_name).await;
     let origin = env::var("ORIGIN").expect("ORIGIN is not set in .env file");
     info!("Allowing ORIGIN: {}", origin);
+    // Deployments from before OpenID Connect have no ISSUER, fall back to where the server listens
+    let issuer = env::var("ISSUER").unwrap_or_else(|_| {
+        let host = env::var("HOST").expect("HOST is not set in .env file");
+        let port = env::var("PORT").expect("PORT is not set in .env file");
+        let issuer = format!("http://{}:{}", host, port);
+        warn!("ISSUER is not set in .env file, using {}", issuer);
+        issuer
+    });
+    let oidc_alg = OidcAlg::parse(&env::var("OIDC_ALG").unwrap_or("RS256".to_string()))?;
+    let forward_auth_headers = auth::ForwardAuthHeaders::parse(&env::var("FORWARD_AUTH_HEADERS").unwrap_or("nginx".to_string()))?;
+
+    let introspection_cache = introspect::IntrospectionCache::new();
+    let user_code_limiter = device::UserCodeLimiter::new();
 
     let mut enforcer = PolicyEnforcer::new()?;
     let mut tx = db_pool.begin().await?;
     enforcer.reload(&mut tx).await?;
     drop(tx);
 
+    // Every worker shares this enforcer, polling picks up rules changed by any instance
+    let poll_secs: u64 = env::var("POLICY_POLL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    let mut poll_enforcer = enforcer.clone();
+    let poll_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(poll_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let ans: FResult<bool> = async {
+                let mut tx = poll_pool.begin().await?;
+                poll_enforcer.reload_if_changed(&mut tx).await
+            }
+            .await;
+            match ans {
+                Ok(true) => info!("Reloaded policy rules"),
+                Ok(false) => {}
+                Err(err) => error!("Failed to poll policy revision: {:?}", err),
+            }
+        }
+    });
+
+    // Memberships past their valid_until are already ignored, this only removes and audits them
+    let sweep_secs: u64 = env::var("GROUP_SWEEP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+    let sweep_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(sweep_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let ans: FResult<u64> = async {
+                let mut tx = sweep_pool.begin().await?;
+                let count = GroupMembership::sweep_expired(Utc::now(), &mut tx).await?;
+                tx.commit().await?;
+                Ok(count)
+            }
+            .await;
+            match ans {
+                Ok(0) => {}
+                Ok(count) => info!("Removed {} expired group memberships", count),
+                Err(err) => error!("Failed to sweep expired group memberships: {:?}", err),
+            }
+        }
+    });
+
+    // Expired codes (device codes too) and tokens are already refused and retired keys left the JWKS, this only keeps their tables from growing
+    let oauth_sweep_secs: u64 = env::var("OAUTH_SWEEP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(600);
+    let oauth_sweep_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(oauth_sweep_secs.max(1)));
+        loop {
+            interval.tick().await;
+            let ans: FResult<()> = async {
+                let mut tx = oauth_sweep_pool.begin().await?;
+                OAuthCode::delete_expired(&mut tx).await?;
+                OAuthToken::delete_expired(&mut tx).await?;
+                OidcKey::delete_expired(&mut tx).await?;
+                OAuthDeviceCode::delete_expired(&mut tx).await?;
+                tx.commit().await?;
+                Ok(())
+            }
+            .await;
+            if let Err(err) = ans {
+                error!("Failed to sweep expired OAuth codes, device codes, tokens and keys: {:?}", err);
+            }
+        }
+    });
+
+    // Envoy ext_authz is optional, it only runs if an address is given
+    if let Ok(addr) = env::var("EXT_AUTHZ_ADDR") {
+        let addr = addr.parse().expect("EXT_AUTHZ_ADDR is not a valid socket address");
+        let service = ext_authz::ExtAuthz::new(db_pool.clone(), enforcer.clone());
+        info!("Starting ext_authz gRPC server on {}", addr);
+        tokio::spawn(async move {
+            let ans = tonic::transport::Server::builder()
+                .add_service(ext_authz::AuthorizationServer::new(service))
+                .serve(addr)
+                .await;
+            if let Err(err) = ans {
+                error!("ext_authz gRPC server failed: {:?}", err);
+            }
+        });
+    }
+
     let mut server = HttpServer::new(move || {
         let cors = Cors::default()
             .allowed_origin(&origin)
@@ -46,14 +161,62 @@ async fn main() -> FResult<()> {
             .wrap(cors)
             .data(AppState {
                 db: db_pool.clone(),
-                enforcer: enforcer.clone()
+                enforcer: enforcer.clone(),
+                issuer: issuer.clone(),
+                oidc_alg: oidc_alg,
+                introspection_cache: introspection_cache.clone(),
+                user_code_limiter: user_code_limiter.clone(),
+                forward_auth_headers: forward_auth_headers,
             })
-            .wrap(crate::auth::SessionAuth::new("feroauth", db_pool.clone()))
+            .wrap(crate::auth::SessionAuth::new(auth::SESSION_COOKIE, db_pool.clone(), enforcer.clone()))
             .service(auth::validate_endpoint)
+            .service(authz::check_endpoint)
+            .service(authz::check_batch_endpoint)
+            .service(authz::explain_endpoint)
+            .service(policy_rules::list_rules_endpoint)
+            .service(policy_rules::get_rule_endpoint)
+            .service(policy_rules::put_rule_endpoint)
+            .service(policy_rules::delete_rule_endpoint)
+            .service(policy_rules::list_verbs_endpoint)
+            .service(delegations::post_delegation_endpoint)
+            .service(delegations::list_given_endpoint)
+            .service(delegations::list_received_endpoint)
+            .service(delegations::delete_delegation_endpoint)
             .service(users::login_endpoint)
             .service(users::get_user_endpoint)
             .service(users::put_user_endpoint)
             .service(misc::get_session_info_endpoint)
+            .service(groups::list_groups_endpoint)
+            .service(groups::get_group_endpoint)
+            .service(groups::get_members_endpoint)
+            .service(groups::put_group_endpoint)
+            .service(groups::delete_group_endpoint)
+            .service(groups::put_member_endpoint)
+            .service(groups::delete_member_endpoint)
+            .service(apps::list_apps_endpoint)
+            .service(apps::get_app_endpoint)
+            .service(apps::put_app_endpoint)
+            .service(apps::delete_app_endpoint)
+            .service(apps::rotate_app_secret_endpoint)
+            .service(oauth::get_authorize_endpoint)
+            .service(oauth::post_authorize_endpoint)
+            .service(oauth::token_endpoint)
+            .service(oauth::revoke_endpoint)
+            .service(introspect::introspect_endpoint)
+            .service(device::device_authorization_endpoint)
+            .service(device::verification_page_endpoint)
+            .service(device::get_device_endpoint)
+            .service(device::post_device_endpoint)
+            .service(oidc::discovery_endpoint)
+            .service(oidc::jwks_endpoint)
+            .service(oidc::get_userinfo_endpoint)
+            .service(oidc::post_userinfo_endpoint)
+            .service(saml::idp_metadata_endpoint)
+            .service(saml::get_sp_endpoint)
+            .service(saml::put_sp_endpoint)
+            .service(saml::delete_sp_endpoint)
+            .service(saml::get_sso_endpoint)
+            .service(saml::post_sso_endpoint)
     });
 
     let host = env::var("HOST").expect("HOST is not set in .env file");
@@ -2,7 +2,8 @@ use crate::prelude::*;
 
 #[get("/session/info")]
 async fn get_session_info_endpoint(
+    data: web::Data<AppState>,
     auth: FullSession,
 ) -> FResult<HttpResponse> {
-    return Ok(HttpResponse::Ok().json(auth));
+    return Ok(HttpResponse::Ok().json(auth.to_view(&data.enforcer)?));
 }
\ No newline at end of file
@@ -0,0 +1,355 @@
+use crate::model::prelude::*;
+use chrono::Duration;
+
+pub const MAX_APP_NAME_LEN: usize = 255;
+pub const MAX_APP_URL_LEN: usize = 1024;
+/// How long the previous client secret keeps working after a rotation
+pub const APP_SECRET_GRACE: i64 = 24 * 3600; // 1 day
+
+#[derive(Debug, Clone, Serialize, Deserialize, PolarClass)]
+pub struct App {
+    #[polar(attribute)]
+    uuid: Uuid,
+    _revision: i32,
+    #[polar(attribute)]
+    pub name: String,
+    /// Base URL of the app, every OAuth `redirect_uri` must live under it
+    #[polar(attribute)]
+    pub url: String,
+    /// Only members of this group may log into the app
+    #[polar(attribute)]
+    pub users_group: Uuid,
+    /// Service account the app acts as when using the client credentials grant
+    pub service_account: Option<Uuid>,
+    /// Public clients cannot keep a secret, so they are the only ones allowed to omit it
+    #[serde(default)]
+    pub public: bool,
+    /// SHA-256 of the client secret
+    #[serde(skip)]
+    key: Vec<u8>,
+    /// SHA-256 of the client secret in use before the last rotation
+    #[serde(skip)]
+    previous_key: Option<Vec<u8>>,
+    /// Until when [`App::previous_key`] is still accepted
+    previous_key_valid_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppChange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// When registering an app without one a new group is created for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users_group: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_account: Option<Option<Uuid>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public: Option<bool>,
+}
+
+// This is used when we need just a vague idea of the app (e.g. when asking the user for consent)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MinApp {
+    uuid: Uuid,
+    pub name: String,
+    pub url: String,
+}
+
+impl App {
+    /// Returns the new app and its clear text client secret (which must be shown to whoever registered it)
+    pub fn new(name: &str, url: &str, users_group: Uuid) -> (App, String) {
+        let secret = new_secret();
+        let ans = App {
+            uuid: Uuid::new_v4(),
+            _revision: 0,
+            name: name.to_string(),
+            url: url.to_string(),
+            users_group,
+            service_account: None,
+            public: false,
+            key: hash_secret(&secret),
+            previous_key: None,
+            previous_key_valid_until: None,
+        };
+        (ans, secret)
+    }
+
+    #[inline]
+    pub fn get_uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    pub fn is_new(&self) -> bool {
+        self._revision == 0
+    }
+
+    pub fn to_min_app(&self) -> MinApp {
+        MinApp {
+            uuid: self.uuid,
+            name: self.name.clone(),
+            url: self.url.clone(),
+        }
+    }
+
+    pub async fn load_by_uuid(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<Self> {
+        trace!("Loading app {:?}", uuid);
+        let row = sqlx::query!(
+            "SELECT `uuid`, `_revision`, `name`, `url`, `users_group`, `service_account`, `public`, `key`, `previous_key`, `previous_key_valid_until` FROM `app` WHERE `uuid` = ?",
+            uuid
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(App {
+            uuid: parse_uuid_vec(row.uuid)?,
+            _revision: row._revision,
+            name: row.name,
+            url: row.url,
+            users_group: parse_uuid_vec(row.users_group)?,
+            service_account: match row.service_account {
+                Some(v) => Some(parse_uuid_vec(v)?),
+                None => None,
+            },
+            public: row.public != 0,
+            key: row.key,
+            previous_key: row.previous_key,
+            previous_key_valid_until: row
+                .previous_key_valid_until
+                .as_ref()
+                .map(|dt| Utc.from_utc_datetime(dt)),
+        })
+    }
+
+    pub async fn load_all(tx: &mut Transaction<'_>) -> FResult<Vec<Self>> {
+        let rows = sqlx::query!(
+            "SELECT `uuid`, `_revision`, `name`, `url`, `users_group`, `service_account`, `public`, `key`, `previous_key`, `previous_key_valid_until` FROM `app` ORDER BY `name`"
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut ans = Vec::with_capacity(rows.len());
+        for row in rows {
+            ans.push(App {
+                uuid: parse_uuid_vec(row.uuid)?,
+                _revision: row._revision,
+                name: row.name,
+                url: row.url,
+                users_group: parse_uuid_vec(row.users_group)?,
+                service_account: match row.service_account {
+                    Some(v) => Some(parse_uuid_vec(v)?),
+                    None => None,
+                },
+                public: row.public != 0,
+                key: row.key,
+                previous_key: row.previous_key,
+                previous_key_valid_until: row
+                    .previous_key_valid_until
+                    .as_ref()
+                    .map(|dt| Utc.from_utc_datetime(dt)),
+            });
+        }
+        Ok(ans)
+    }
+
+    /// Checks a clear text client secret against the stored hash (or the previous one during its grace period)
+    pub fn verify_secret(&self, secret: &str) -> bool {
+        let hash = hash_secret(secret);
+        if constant_time_eq(&hash, &self.key) {
+            return true;
+        }
+        match (&self.previous_key, self.previous_key_valid_until) {
+            (Some(previous_key), Some(valid_until)) => {
+                Utc::now() < valid_until && constant_time_eq(&hash, previous_key)
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces the client secret and returns the new one in clear text.
+    ///
+    /// The old secret keeps working for [`APP_SECRET_GRACE`] so clients can be updated without downtime.
+    pub fn rotate_secret(&mut self) -> String {
+        let secret = new_secret();
+        self.previous_key = Some(std::mem::replace(&mut self.key, hash_secret(&secret)));
+        self.previous_key_valid_until = Some(Utc::now() + Duration::seconds(APP_SECRET_GRACE));
+        secret
+    }
+
+    pub fn apply_changes(&mut self, changes: AppChange) {
+        if let Some(name) = changes.name {
+            self.name = name
+        }
+        if let Some(url) = changes.url {
+            self.url = url
+        }
+        if let Some(users_group) = changes.users_group {
+            self.users_group = users_group
+        }
+        if let Some(service_account) = changes.service_account {
+            self.service_account = service_account
+        }
+        if let Some(public) = changes.public {
+            self.public = public
+        }
+    }
+
+    pub fn validate(&self) -> Vec<InvalidValue> {
+        let mut ans = vec![];
+        let len = self.name.chars().count();
+        if !(MIN_NON_EMPTY_STR < len && len <= MAX_APP_NAME_LEN) {
+            ans.push(InvalidValue::OutOfRange(
+                "app.name",
+                MIN_NON_EMPTY_STR,
+                MAX_APP_NAME_LEN,
+            ))
+        }
+        let len = self.url.chars().count();
+        if !(MIN_NON_EMPTY_STR < len && len <= MAX_APP_URL_LEN) {
+            ans.push(InvalidValue::OutOfRange(
+                "app.url",
+                MIN_NON_EMPTY_STR,
+                MAX_APP_URL_LEN,
+            ))
+        }
+        match url::Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+            _ => ans.push(InvalidValue::BadFormat("app.url")),
+        }
+        if self.users_group.is_nil() {
+            ans.push(InvalidValue::MustNotNull("app.users_group"))
+        }
+        ans
+    }
+
+    pub fn validate_as_err(&self) -> FResult<()> {
+        let errs = self.validate();
+        if errs.len() != 0 {
+            return Err(FError::new(ValidationError(errs)));
+        }
+        Ok(())
+    }
+
+    pub async fn save(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving app {:?}", self.uuid);
+
+        self.validate_as_err()?;
+
+        match self._revision {
+            0 => self.db_insert(tx).await?,
+            _ => self.db_update(tx).await?,
+        };
+        Ok(())
+    }
+
+    pub async fn delete(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!("DELETE FROM `app` WHERE `uuid` = ?", uuid)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn db_insert(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        self._revision = 1;
+        sqlx::query!(
+            "INSERT INTO `app` (`uuid`, `_revision`, `name`, `url`, `users_group`, `service_account`, `public`, `key`, `previous_key`, `previous_key_valid_until`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            self.uuid,
+            self._revision,
+            self.name,
+            self.url,
+            self.users_group,
+            self.service_account,
+            self.public,
+            self.key,
+            self.previous_key,
+            self.previous_key_valid_until
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    async fn db_update(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        self._revision += 1;
+        sqlx::query!(
+            "UPDATE `app` SET `_revision` = ?, `name` = ?, `url` = ?, `users_group` = ?, `service_account` = ?, `public` = ?, `key` = ?, `previous_key` = ?, `previous_key_valid_until` = ? WHERE `uuid` = ?",
+            self._revision,
+            self.name,
+            self.url,
+            self.users_group,
+            self.service_account,
+            self.public,
+            self.key,
+            self.previous_key,
+            self.previous_key_valid_until,
+            self.uuid
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Checks that `uri` has the same origin as the app's URL and that its path is under the app's path
+    pub fn is_redirect_allowed(&self, uri: &str) -> bool {
+        let base = match url::Url::parse(&self.url) {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("App {} has an invalid URL {:?}: {:?}", self.uuid, self.url, err);
+                return false;
+            }
+        };
+        let uri = match url::Url::parse(uri) {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        uri.origin() == base.origin() && is_path_under(uri.path(), base.path()) && uri.fragment().is_none()
+    }
+
+    /// Only members (direct or indirect) of [`App::users_group`] may use the app
+    pub fn is_user_allowed(&self, user: &User) -> bool {
+        user.groups.has(self.users_group)
+    }
+}
+
+/// Compares on segment boundaries so that `/app` covers `/app/cb` but not `/app-evil/cb`
+fn is_path_under(path: &str, base: &str) -> bool {
+    match path.strip_prefix(base) {
+        Some(rest) => rest.is_empty() || base.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_secret() {
+        let (mut app, old) = App::new("Test", "https://example.com/", Uuid::new_v4());
+        assert!(app.verify_secret(&old));
+
+        let new = app.rotate_secret();
+        assert!(app.verify_secret(&new));
+        assert!(app.verify_secret(&old));
+        assert!(!app.verify_secret("wrong"));
+
+        app.previous_key_valid_until = Some(Utc::now() - Duration::seconds(1));
+        assert!(app.verify_secret(&new));
+        assert!(!app.verify_secret(&old));
+    }
+
+    #[test]
+    fn test_redirect_path_boundary() {
+        let (app, _) = App::new("Test", "https://example.com/app", Uuid::new_v4());
+        assert!(app.is_redirect_allowed("https://example.com/app"));
+        assert!(app.is_redirect_allowed("https://example.com/app/cb?x=1"));
+        assert!(!app.is_redirect_allowed("https://example.com/app-evil/cb"));
+        assert!(!app.is_redirect_allowed("https://example.com/application"));
+        assert!(!app.is_redirect_allowed("https://evil.example.com/app/cb"));
+
+        let (app, _) = App::new("Test", "https://example.com/app/", Uuid::new_v4());
+        assert!(app.is_redirect_allowed("https://example.com/app/cb"));
+        assert!(!app.is_redirect_allowed("https://example.com/app-evil/cb"));
+    }
+}
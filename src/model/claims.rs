@@ -0,0 +1,18 @@
+use crate::model::prelude::*;
+use crate::model::selector::merge_claim;
+use serde_json::{Map, Value};
+
+/// Projects the user into a claims object containing only what the scopes' keys select.
+///
+/// A key such as `user.display_name` becomes the claim `display_name`, lists selected by more than one key are merged. The `sub` claim is always present.
+pub fn user_claims(user: &User, scopes: &[Scope]) -> FResult<Map<String, Value>> {
+    let mut claims = Map::new();
+    claims.insert("sub".to_string(), Value::String(user.get_uuid().to_string()));
+    for scope in scopes {
+        for selector in scope.selectors()? {
+            let (name, val) = selector.eval(user);
+            merge_claim(&mut claims, name, val);
+        }
+    }
+    Ok(claims)
+}
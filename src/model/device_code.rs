@@ -0,0 +1,234 @@
+use crate::model::prelude::*;
+use chrono::Duration;
+use rand::rngs::OsRng;
+use rand::Rng;
+
+pub const DEVICE_CODE_LIFE: i64 = 10 * 60; // 10 min
+/// Minimum number of seconds between polls (RFC 8628 section 3.2)
+pub const DEVICE_CODE_INTERVAL: i32 = 5;
+/// Consonants only so user codes never spell words and are not confused with digits
+pub const USER_CODE_ALPHABET: &'static [u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+pub const USER_CODE_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceCodeStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+impl DeviceCodeStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceCodeStatus::Pending => "PENDING",
+            DeviceCodeStatus::Approved => "APPROVED",
+            DeviceCodeStatus::Denied => "DENIED",
+        }
+    }
+
+    #[track_caller]
+    pub fn parse(val: &str) -> FResult<Self> {
+        match val {
+            "PENDING" => Ok(DeviceCodeStatus::Pending),
+            "APPROVED" => Ok(DeviceCodeStatus::Approved),
+            "DENIED" => Ok(DeviceCodeStatus::Denied),
+            _ => Err(FError::new_faux_panic_3("unknown device code status", val)),
+        }
+    }
+}
+
+/// Device authorization as defined in RFC 8628. Only the hash of the device code is stored.
+#[derive(Debug, Clone)]
+pub struct OAuthDeviceCode {
+    device_code_hash: Vec<u8>,
+    /// Short code the user types on the verification page, always stored normalized (see [`OAuthDeviceCode::normalize_user_code`])
+    pub user_code: String,
+    pub app_uuid: Uuid,
+    /// Space separated list of [`Scope::oauth_name`]
+    pub scope: String,
+    /// User who approved or denied the request
+    pub user_uuid: Option<Uuid>,
+    pub status: DeviceCodeStatus,
+    /// Seconds the client must wait between polls, raised every time it polls too fast
+    pub interval: i32,
+    pub last_polled_at: Option<DateTime<Utc>>,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OAuthDeviceCode {
+    /// Returns the new request and the clear text device code (which must be sent to the client)
+    pub fn new(app_uuid: Uuid, scope: &str) -> (OAuthDeviceCode, String) {
+        let device_code = new_secret();
+        let user_code: String = (0..USER_CODE_LEN)
+            .map(|_| USER_CODE_ALPHABET[OsRng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+            .collect();
+        let now = Utc::now();
+        let ans = OAuthDeviceCode {
+            device_code_hash: hash_secret(&device_code),
+            user_code,
+            app_uuid,
+            scope: scope.to_string(),
+            user_uuid: None,
+            status: DeviceCodeStatus::Pending,
+            interval: DEVICE_CODE_INTERVAL,
+            last_polled_at: None,
+            issued_at: now,
+            expires_at: now + Duration::seconds(DEVICE_CODE_LIFE),
+        };
+        (ans, device_code)
+    }
+
+    /// Uppercases the code and drops everything that is not in [`USER_CODE_ALPHABET`] (e.g. dashes and spaces)
+    pub fn normalize_user_code(user_code: &str) -> String {
+        user_code
+            .to_uppercase()
+            .chars()
+            .filter(|c| USER_CODE_ALPHABET.contains(&(*c as u8)))
+            .collect()
+    }
+
+    /// User code formatted for display (e.g. `BCDF-GHJK`)
+    pub fn display_user_code(&self) -> String {
+        let half = self.user_code.len() / 2;
+        format!("{}-{}", &self.user_code[..half], &self.user_code[half..])
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < Utc::now()
+    }
+
+    pub fn expires_in(&self) -> i64 {
+        (self.expires_at - Utc::now()).num_seconds().max(0)
+    }
+
+    pub async fn save(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving device code {:?} for app {:?}", self.user_code, self.app_uuid);
+        sqlx::query!(
+            "INSERT INTO `oauth_device_code` (`device_code_hash`, `user_code`, `app_uuid`, `scope`, `user_uuid`, `status`, `interval`, `last_polled_at`, `issued_at`, `expires_at`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            self.device_code_hash,
+            self.user_code,
+            self.app_uuid,
+            self.scope,
+            self.user_uuid,
+            self.status.as_str(),
+            self.interval,
+            self.last_polled_at,
+            self.issued_at,
+            self.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn load_by_device_code(device_code: &str, tx: &mut Transaction<'_>) -> FResult<OAuthDeviceCode> {
+        let device_code_hash = hash_secret(device_code);
+        let row = sqlx::query!(
+            "SELECT `device_code_hash`, `user_code`, `app_uuid`, `scope`, `user_uuid`, `status`, `interval`, `last_polled_at`, `issued_at`, `expires_at` FROM `oauth_device_code` WHERE `device_code_hash` = ? FOR UPDATE",
+            device_code_hash
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(OAuthDeviceCode {
+            device_code_hash: row.device_code_hash,
+            user_code: row.user_code,
+            app_uuid: parse_uuid_vec(row.app_uuid)?,
+            scope: row.scope,
+            user_uuid: match row.user_uuid {
+                Some(v) => Some(parse_uuid_vec(v)?),
+                None => None,
+            },
+            status: DeviceCodeStatus::parse(&row.status)?,
+            interval: row.interval,
+            last_polled_at: row.last_polled_at.as_ref().map(|dt| Utc.from_utc_datetime(dt)),
+            issued_at: Utc.from_utc_datetime(&row.issued_at),
+            expires_at: Utc.from_utc_datetime(&row.expires_at),
+        })
+    }
+
+    /// Loads a pending, non expired request by the code the user typed
+    pub async fn load_pending_by_user_code(user_code: &str, tx: &mut Transaction<'_>) -> FResult<OAuthDeviceCode> {
+        let user_code = OAuthDeviceCode::normalize_user_code(user_code);
+        let row = sqlx::query!(
+            "SELECT `device_code_hash`, `user_code`, `app_uuid`, `scope`, `user_uuid`, `status`, `interval`, `last_polled_at`, `issued_at`, `expires_at` FROM `oauth_device_code` WHERE `user_code` = ? AND `status` = 'PENDING' AND `expires_at` > ? FOR UPDATE",
+            user_code,
+            Utc::now()
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(OAuthDeviceCode {
+            device_code_hash: row.device_code_hash,
+            user_code: row.user_code,
+            app_uuid: parse_uuid_vec(row.app_uuid)?,
+            scope: row.scope,
+            user_uuid: None,
+            status: DeviceCodeStatus::Pending,
+            interval: row.interval,
+            last_polled_at: row.last_polled_at.as_ref().map(|dt| Utc.from_utc_datetime(dt)),
+            issued_at: Utc.from_utc_datetime(&row.issued_at),
+            expires_at: Utc.from_utc_datetime(&row.expires_at),
+        })
+    }
+
+    /// Records the user's decision
+    pub async fn decide(&mut self, user_uuid: Uuid, approve: bool, tx: &mut Transaction<'_>) -> FResult<()> {
+        self.user_uuid = Some(user_uuid);
+        self.status = match approve {
+            true => DeviceCodeStatus::Approved,
+            false => DeviceCodeStatus::Denied,
+        };
+        sqlx::query!(
+            "UPDATE `oauth_device_code` SET `user_uuid` = ?, `status` = ? WHERE `device_code_hash` = ?",
+            self.user_uuid,
+            self.status.as_str(),
+            self.device_code_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Records a poll and returns false if the client is polling faster than allowed (in which case the interval is raised)
+    pub async fn mark_polled(&mut self, tx: &mut Transaction<'_>) -> FResult<bool> {
+        let now = Utc::now();
+        let too_fast = match self.last_polled_at {
+            Some(last) => now - last < Duration::seconds(self.interval as i64),
+            None => false,
+        };
+        if too_fast {
+            self.interval += DEVICE_CODE_INTERVAL;
+        }
+        self.last_polled_at = Some(now);
+        sqlx::query!(
+            "UPDATE `oauth_device_code` SET `interval` = ?, `last_polled_at` = ? WHERE `device_code_hash` = ?",
+            self.interval,
+            self.last_polled_at,
+            self.device_code_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(!too_fast)
+    }
+
+    /// Deletes the request so the device code can not be exchanged twice
+    pub async fn delete(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!(
+            "DELETE FROM `oauth_device_code` WHERE `device_code_hash` = ?",
+            self.device_code_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes requests that expired without being exchanged
+    pub async fn delete_expired(tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!("DELETE FROM `oauth_device_code` WHERE `expires_at` < ?", Utc::now())
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+}
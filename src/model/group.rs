@@ -1,6 +1,25 @@
+use crate::model::group_membership::MAX_GROUP_DEPTH;
 use crate::model::prelude::*;
 
 pub const MAX_GROUP_NAME_LEN: usize = 190;
+pub const DEFAULT_GROUP_MEMBERS_PAGE: usize = 100;
+pub const MAX_GROUP_MEMBERS_PAGE: usize = 1000;
+
+/// Members at any depth of a group with their level, following only rows in effect at a given time.
+/// Binds the group, the time twice, [`MAX_GROUP_DEPTH`] and the time twice again.
+///
+/// UNION (not UNION ALL) keeps each member once per level, so diamonds do not multiply rows and cycles stop at the
+/// depth bound.
+const GROUP_MEMBERS_CTE: &'static str = "WITH RECURSIVE `members` (`member_uuid`, `level`) AS ( \
+        SELECT `member_uuid`, CAST(0 AS SIGNED) FROM `group_members` \
+        WHERE `group_uuid` = ? \
+        AND (`valid_from` IS NULL OR `valid_from` <= ?) AND (`valid_until` IS NULL OR `valid_until` > ?) \
+        UNION \
+        SELECT M.`member_uuid`, P.`level` + 1 FROM `group_members` M \
+        JOIN `members` P ON M.`group_uuid` = P.`member_uuid` \
+        WHERE P.`level` < ? \
+        AND (M.`valid_from` IS NULL OR M.`valid_from` <= ?) AND (M.`valid_until` IS NULL OR M.`valid_until` > ?) \
+    )";
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, PolarClass)]
 pub struct Group {
@@ -9,11 +28,36 @@ pub struct Group {
     _revision: i32,
     pub name: String,
     pub desc: String,
+    /// Direct members, `None` when not loaded (e.g. in listings)
     members: Option<Vec<MinObject>>,
     #[polar(attribute)]
     pub groups: GroupMembership,
 }
 
+/// A direct or indirect member of a group, `level` 0 means direct
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMember {
+    #[serde(flatten)]
+    pub object: MinObject,
+    pub level: i32,
+}
+
+/// One page of the transitive members of a group
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMembersPage {
+    pub total: usize,
+    pub offset: usize,
+    pub members: Vec<GroupMember>,
+}
+
+/// Changes to a [`Group`], `_revision` is the revision the change is based on
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct GroupChange {
+    pub _revision: Option<i32>,
+    pub name: Option<String>,
+    pub desc: Option<String>,
+}
+
 impl Group {
     pub fn new(uuid: Uuid, name: &str, desc: &str) -> Self {
         Group {
@@ -31,7 +75,19 @@ impl Group {
         self.uuid
     }
 
-    pub async fn load_by_uuid(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<Self> {
+    pub fn is_new(&self) -> bool {
+        self._revision == 0
+    }
+
+    pub fn get_members(&self) -> Option<&[MinObject]> {
+        self.members.as_deref()
+    }
+
+    pub fn get_members_mut(&mut self) -> Option<&mut [MinObject]> {
+        self.members.as_deref_mut()
+    }
+
+    pub async fn load_by_uuid(uuid: Uuid, as_user: &User, enforcer: &PolicyEnforcer, tx: &mut Transaction<'_>) -> FResult<Self> {
         trace!("Loading group {:?}", uuid);
         let row = sqlx::query!(
             "SELECT `uuid`, `_revision`, `name`, `desc` FROM `group` WHERE `uuid` = ?",
@@ -41,16 +97,185 @@ impl Group {
         .await?;
         let uuid = parse_uuid_vec(row.uuid)?;
 
-        Ok(Group {
+        let group = Group {
             uuid,
             _revision: row._revision,
             name: row.name,
             desc: row.desc,
-            members: None,
+            members: Some(Group::load_members(uuid, tx).await?),
             groups: GroupMembership::load_for(uuid, tx).await?,
+        };
+        // The system super user skips the policy
+        if !as_user.is_system_super_user() {
+            enforcer.ensure_allowed(as_user, POLVERB_GROUP_GET, &group)?;
+        }
+        Ok(group)
+    }
+
+    /// Every group, for listings that filter them with `POLVERB_GROUP_GET`
+    pub async fn load_all(tx: &mut Transaction<'_>) -> FResult<Vec<Self>> {
+        let rows = sqlx::query!("SELECT `uuid`, `_revision`, `name`, `desc` FROM `group` ORDER BY `name`")
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let mut ans = Vec::new();
+        for row in rows {
+            let uuid = parse_uuid_vec(row.uuid)?;
+            ans.push(Group {
+                uuid,
+                _revision: row._revision,
+                name: row.name,
+                desc: row.desc,
+                members: None,
+                groups: GroupMembership::load_for(uuid, tx).await?,
+            });
+        }
+        Ok(ans)
+    }
+
+    /// Direct members of any kind, sorted by kind and name, including memberships not in effect right now
+    pub async fn load_members(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<Vec<MinObject>> {
+        let rows = sqlx::query!(
+            "SELECT M.`member_uuid`, O.`type` AS `kind`, COALESCE(U.`display_name`, G.`name`, A.`name`) AS `name` \
+            FROM `group_members` M \
+            JOIN `object_type` O ON O.`uuid` = M.`member_uuid` \
+            LEFT JOIN `user` U ON U.`uuid` = M.`member_uuid` \
+            LEFT JOIN `group` G ON G.`uuid` = M.`member_uuid` \
+            LEFT JOIN `app` A ON A.`uuid` = M.`member_uuid` \
+            WHERE M.`group_uuid` = ? ORDER BY `kind`, `name`",
+            uuid
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut ans = Vec::new();
+        for row in rows {
+            ans.push(MinObject::new(parse_uuid_vec(row.member_uuid)?, &row.kind, row.name));
+        }
+        Ok(ans)
+    }
+
+    /// Loads `limit` members at any depth starting at `offset`, ordered by level then UUID. Only the page is read
+    /// back from the DB, along with the total count
+    pub async fn load_members_page(
+        uuid: Uuid,
+        offset: usize,
+        limit: usize,
+        tx: &mut Transaction<'_>,
+    ) -> FResult<GroupMembersPage> {
+        let now = Utc::now();
+        // The group itself shows up when it is in a cycle
+        let count_query = format!(
+            "{} SELECT COUNT(DISTINCT `member_uuid`) FROM `members` WHERE `member_uuid` <> ?",
+            GROUP_MEMBERS_CTE
+        );
+        let (total,): (i64,) = sqlx::query_as(&count_query)
+            .bind(uuid)
+            .bind(now)
+            .bind(now)
+            .bind(MAX_GROUP_DEPTH)
+            .bind(now)
+            .bind(now)
+            .bind(uuid)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let page_query = format!(
+            "{} SELECT `member_uuid`, MIN(`level`) AS `min_level` FROM `members` WHERE `member_uuid` <> ? \
+            GROUP BY `member_uuid` ORDER BY `min_level`, `member_uuid` LIMIT ? OFFSET ?",
+            GROUP_MEMBERS_CTE
+        );
+        let page: Vec<(Uuid, i64)> = sqlx::query_as(&page_query)
+            .bind(uuid)
+            .bind(now)
+            .bind(now)
+            .bind(MAX_GROUP_DEPTH)
+            .bind(now)
+            .bind(now)
+            .bind(uuid)
+            .bind(limit.min(MAX_GROUP_MEMBERS_PAGE) as u64)
+            .bind(offset as u64)
+            .fetch_all(&mut *tx)
+            .await?;
+        let uuids: Vec<Uuid> = page.iter().map(|(uuid, _)| *uuid).collect();
+        let objects = MinObject::load_many(&uuids, tx).await?;
+
+        let mut members = Vec::new();
+        for object in objects {
+            let level = page
+                .iter()
+                .find(|(uuid, _)| *uuid == object.get_uuid())
+                .map_or(0, |(_, level)| *level as i32);
+            members.push(GroupMember { object, level });
+        }
+        Ok(GroupMembersPage {
+            total: total as usize,
+            offset,
+            members,
         })
     }
 
+    /// Adds `member` or changes the bounds of its membership, `None` leaves that side open
+    pub async fn add_member(
+        uuid: Uuid,
+        member: Uuid,
+        valid_from: Option<DateTime<Utc>>,
+        valid_until: Option<DateTime<Utc>>,
+        tx: &mut Transaction<'_>,
+    ) -> FResult<()> {
+        if let (Some(from), Some(until)) = (valid_from, valid_until) {
+            if until <= from {
+                return Err(FError::new(ValidationError(vec![InvalidValue::BadFormat("valid_until")])));
+            }
+        }
+        GroupMembership::ensure_no_cycle("members", uuid, member, tx).await?;
+        sqlx::query!(
+            "INSERT INTO `group_members` (`group_uuid`, `member_uuid`, `valid_from`, `valid_until`) VALUES (?, ?, ?, ?) \
+            ON DUPLICATE KEY UPDATE `valid_from` = VALUES(`valid_from`), `valid_until` = VALUES(`valid_until`)",
+            uuid,
+            member,
+            valid_from,
+            valid_until
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_member(uuid: Uuid, member: Uuid, tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!(
+            "DELETE FROM `group_members` WHERE `group_uuid` = ? AND `member_uuid` = ?",
+            uuid,
+            member
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Applies `changes` and returns the verbs they need (`POLVERB_GROUP_NAME_SET` and/or `POLVERB_GROUP_DESC_SET`)
+    pub fn apply_changes(&mut self, changes: GroupChange) -> FResult<Vec<&'static str>> {
+        if let Some(v) = changes._revision {
+            if v != self._revision {
+                return Err(FError::new(StaleRevision(self.uuid, v)));
+            }
+        }
+        let mut verbs = vec![];
+        if let Some(name) = changes.name {
+            if name != self.name {
+                self.name = name;
+                verbs.push(POLVERB_GROUP_NAME_SET);
+            }
+        }
+        if let Some(desc) = changes.desc {
+            if desc != self.desc {
+                self.desc = desc;
+                verbs.push(POLVERB_GROUP_DESC_SET);
+            }
+        }
+        Ok(verbs)
+    }
+
     pub fn validate(&self) -> Vec<InvalidValue> {
         let len = self.name.chars().count();
         let mut ans = vec![];
@@ -72,17 +297,22 @@ impl Group {
         Ok(())
     }
 
+    /// Saves the name and description, plus the parent groups of new groups.
+    ///
+    /// Members of existing groups only change through [`Group::add_member`] and [`Group::remove_member`], writing back
+    /// the rows loaded with the group would undo concurrent changes to them.
     pub async fn save(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
         trace!("Saving group {:?}", self.uuid);
 
         self.validate_as_err()?;
 
         match self._revision {
-            0 => self.db_insert(tx).await?,
+            0 => {
+                self.db_insert(tx).await?;
+                self.groups.save_for(self.uuid, tx).await?;
+            }
             _ => self.db_update(tx).await?,
         };
-        self.db_save_members(tx).await?;
-        self.groups.save_for(self.uuid, tx).await?;
         Ok(())
     }
 
@@ -120,29 +350,4 @@ impl Group {
         .await?;
         Ok(())
     }
-
-    async fn db_save_members(&self, tx: &mut Transaction<'_>) -> FResult<()> {
-        let direct_members = match &self.members {
-            Some(v) => v,
-            None => return Ok(()),
-        };
-        sqlx::query!(
-            "DELETE FROM `group_members` WHERE `group_uuid` = ?",
-            self.uuid
-        )
-        .execute(&mut *tx)
-        .await?;
-
-        for member in direct_members {
-            sqlx::query!(
-                "INSERT INTO `group_members` (`group_uuid`, `member_uuid`) VALUES (?, ?)",
-                self.uuid,
-                member.get_uuid()
-            )
-            .execute(&mut *tx)
-            .await?;
-        }
-
-        Ok(())
-    }
 }
@@ -2,9 +2,33 @@ use crate::model::prelude::*;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
+/// Deepest level of indirection followed.
+///
+/// Cycles are rejected on save, but two transactions nesting groups into each other at the same time can each pass
+/// that check, so this bound is what keeps lookups finite on such data.
+pub const MAX_GROUP_DEPTH: i32 = 64;
+
+/// Membership in one group, `level` is the level of indirection where 0 means direct
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Membership {
+    pub name: String,
+    pub level: i32,
+    /// Only set for direct memberships
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_from: Option<DateTime<Utc>>,
+    /// For indirect memberships, the earliest expiry along the path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl Membership {
+    pub fn is_active_at(&self, when: DateTime<Utc>) -> bool {
+        self.valid_from.map_or(true, |from| from <= when) && self.valid_until.map_or(true, |until| when < until)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, PolarClass)]
-// the i32 indicates the level of indirection where 0 means direct
-pub struct GroupMembership(HashMap<Uuid, (String, i32)>);
+pub struct GroupMembership(HashMap<Uuid, Membership>);
 
 impl GroupMembership {
     pub fn new() -> Self {
@@ -12,15 +36,75 @@ impl GroupMembership {
     }
 
     pub fn add(&mut self, uuid: Uuid, name: &str) {
-        self.0.insert(uuid, (name.to_string(), 0));
+        self.add_bounded(uuid, name, None, None);
+    }
+
+    /// Adds a direct membership that only holds between `valid_from` and `valid_until`
+    pub fn add_bounded(
+        &mut self,
+        uuid: Uuid,
+        name: &str,
+        valid_from: Option<DateTime<Utc>>,
+        valid_until: Option<DateTime<Utc>>,
+    ) {
+        self.0.insert(
+            uuid,
+            Membership {
+                name: name.to_string(),
+                level: 0,
+                valid_from,
+                valid_until,
+            },
+        );
+    }
+
+    pub fn get(&self, uuid: Uuid) -> Option<&Membership> {
+        self.0.get(&uuid)
+    }
+
+    /// Memberships that hold right now, a loaded user may outlive the end of one
+    fn active(&self) -> impl Iterator<Item = (&Uuid, &Membership)> {
+        let now = Utc::now();
+        self.0.iter().filter(move |(_, membership)| membership.is_active_at(now))
     }
 
     pub fn has(&self, uuid: Uuid) -> bool {
-        self.0.get(&uuid).is_some()
+        let now = Utc::now();
+        self.0.get(&uuid).map_or(false, |membership| membership.is_active_at(now))
+    }
+
+    /// Iterates over the UUID and name of every group (direct or indirect)
+    pub fn iter(&self) -> impl Iterator<Item = (Uuid, &str)> {
+        self.active().map(|(uuid, membership)| (*uuid, membership.name.as_str()))
+    }
+
+    /// UUIDs of the groups the member is directly in
+    pub fn direct_keys_set(&self) -> HashSet<Uuid> {
+        self.active()
+            .filter(|(_, membership)| membership.level == 0)
+            .map(|(uuid, _)| *uuid)
+            .collect()
+    }
+
+    /// UUIDs of the groups `self` is directly in that `before` lacks or bounds differently, in effect or not.
+    ///
+    /// Moving or extending the bounds of a membership grants it for a time it did not cover, so it counts as adding it
+    pub fn added_or_rebounded(&self, before: &GroupMembership) -> HashSet<Uuid> {
+        self.0
+            .iter()
+            .filter(|(_, membership)| membership.level == 0)
+            .filter(|(uuid, membership)| match before.0.get(uuid) {
+                Some(old) if old.level == 0 => {
+                    old.valid_from != membership.valid_from || old.valid_until != membership.valid_until
+                }
+                _ => true,
+            })
+            .map(|(uuid, _)| *uuid)
+            .collect()
     }
 
     pub fn to_keys_set(&self) -> HashSet<Uuid> {
-        self.0.keys().cloned().collect()
+        self.active().map(|(uuid, _)| *uuid).collect()
     }
 
     pub fn has_intersection(&self, other: &GroupMembership) -> bool {
@@ -50,16 +134,25 @@ impl GroupMembership {
         }
     }
 
+    /// Saves the direct memberships, rows not in effect right now (not yet valid or awaiting the sweeper) are kept
     pub async fn save_for(&self, uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<()> {
-        sqlx::query!("DELETE FROM `group_members` WHERE `member_uuid` = ?", uuid)
-            .execute(&mut *tx)
-            .await?;
+        let now = Utc::now();
+        let direct: HashMap<&Uuid, &Membership> = self.0.iter().filter(|(_, m)| m.level == 0).collect();
 
-        for (group, (_, level)) in &self.0 {
-            // Save only direct group memberships
-            if *level == 0 {
+        let rows = sqlx::query!(
+            "SELECT `group_uuid` FROM `group_members` WHERE `member_uuid` = ? \
+            AND (`valid_from` IS NULL OR `valid_from` <= ?) AND (`valid_until` IS NULL OR `valid_until` > ?)",
+            uuid,
+            now,
+            now
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for row in rows {
+            let group = parse_uuid_vec(row.group_uuid)?;
+            if !direct.contains_key(&group) {
                 sqlx::query!(
-                    "INSERT INTO `group_members` (`group_uuid`, `member_uuid`) VALUES (?, ?)",
+                    "DELETE FROM `group_members` WHERE `group_uuid` = ? AND `member_uuid` = ?",
                     group,
                     uuid
                 )
@@ -68,56 +161,345 @@ impl GroupMembership {
             }
         }
 
+        for (group, membership) in direct {
+            GroupMembership::ensure_no_cycle("groups", *group, uuid, tx).await?;
+            sqlx::query!(
+                "INSERT INTO `group_members` (`group_uuid`, `member_uuid`, `valid_from`, `valid_until`) VALUES (?, ?, ?, ?) \
+                ON DUPLICATE KEY UPDATE `valid_from` = VALUES(`valid_from`), `valid_until` = VALUES(`valid_until`)",
+                group,
+                uuid,
+                membership.valid_from,
+                membership.valid_until
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
         Ok(())
     }
 
+    /// Fails if adding `member` to `group` would make `group` a member of itself, now or at any other time.
+    ///
+    /// Reads are not locked, concurrent changes may still close a cycle which [`MAX_GROUP_DEPTH`] then cuts short.
+    pub async fn ensure_no_cycle(
+        field: &'static str,
+        group: Uuid,
+        member: Uuid,
+        tx: &mut Transaction<'_>,
+    ) -> FResult<()> {
+        if group == member || GroupMembership::load_with(group, None, tx).await?.0.contains_key(&member) {
+            return Err(FError::new(ValidationError(vec![InvalidValue::MembershipCycle(
+                field, group, member,
+            )])));
+        }
+        Ok(())
+    }
+
+    /// Every group `uuid` is in right now, directly or through other groups, with the shortest level of indirection
     pub async fn load_for(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<GroupMembership> {
+        GroupMembership::load_with(uuid, Some(Utc::now()), tx).await
+    }
+
+    /// Follows only the rows in effect at `when`, or every row if `None`
+    async fn load_with(uuid: Uuid, when: Option<DateTime<Utc>>, tx: &mut Transaction<'_>) -> FResult<GroupMembership> {
+        let in_effect = |table: &str| match when {
+            Some(_) => format!(
+                "AND ({0}`valid_from` IS NULL OR {0}`valid_from` <= ?) AND ({0}`valid_until` IS NULL OR {0}`valid_until` > ?)",
+                table
+            ),
+            None => String::new(),
+        };
+        // UNION (not UNION ALL) keeps each row once, so diamonds do not multiply rows.
+        // A group reached by several paths stays until the last of them expires.
+        let sql_query = format!(
+            "WITH RECURSIVE `parents` (`group_uuid`, `level`, `valid_from`, `valid_until`) AS ( \
+                SELECT `group_uuid`, CAST(0 AS SIGNED), `valid_from`, `valid_until` FROM `group_members` \
+                WHERE `member_uuid` = ? {} \
+                UNION \
+                SELECT M.`group_uuid`, P.`level` + 1, CAST(NULL AS DATETIME), \
+                CASE WHEN M.`valid_until` IS NULL THEN P.`valid_until` \
+                WHEN P.`valid_until` IS NULL THEN M.`valid_until` \
+                ELSE LEAST(M.`valid_until`, P.`valid_until`) END \
+                FROM `group_members` M \
+                JOIN `parents` P ON M.`member_uuid` = P.`group_uuid` \
+                WHERE P.`level` < ? {} \
+            ) \
+            SELECT P.`group_uuid`, G.`name`, MIN(P.`level`), MAX(P.`valid_from`), \
+            CASE WHEN COUNT(*) > COUNT(P.`valid_until`) THEN NULL ELSE MAX(P.`valid_until`) END \
+            FROM `parents` P \
+            JOIN `group` G ON G.`uuid` = P.`group_uuid` \
+            GROUP BY P.`group_uuid`, G.`name`",
+            in_effect(""),
+            in_effect("M.")
+        );
+        let mut query = sqlx::query_as(&sql_query).bind(uuid);
+        if let Some(when) = when {
+            query = query.bind(when).bind(when);
+        }
+        query = query.bind(MAX_GROUP_DEPTH);
+        if let Some(when) = when {
+            query = query.bind(when).bind(when);
+        }
+        let rows: Vec<(Uuid, String, i64, Option<DateTime<Utc>>, Option<DateTime<Utc>>)> =
+            query.fetch_all(&mut *tx).await?;
+
+        let mut groups = HashMap::<Uuid, Membership>::new();
+        for (group_uuid, name, level, valid_from, valid_until) in rows {
+            let level = level as i32;
+            groups.insert(
+                group_uuid,
+                Membership {
+                    name,
+                    level,
+                    valid_from: if level == 0 { valid_from } else { None },
+                    valid_until,
+                },
+            );
+        }
+
+        Ok(GroupMembership(groups))
+    }
+
+    /// Deletes memberships that ended before `now`, leaving an audit record for each one
+    pub async fn sweep_expired(now: DateTime<Utc>, tx: &mut Transaction<'_>) -> FResult<u64> {
         let rows = sqlx::query!(
-            "SELECT `group_uuid`, `group_name` FROM `group_members_view` WHERE `member_uuid` = ?",
-            uuid
+            "SELECT `group_uuid`, `member_uuid`, `valid_from`, `valid_until` FROM `group_members` \
+            WHERE `valid_until` <= ? FOR UPDATE",
+            now
         )
         .fetch_all(&mut *tx)
         .await?;
-        let mut groups = HashMap::<Uuid, (String, i32)>::new();
+
+        let mut count = 0;
         for row in rows {
-            let group_uuid = parse_uuid_vec(row.group_uuid)?;
-            groups.insert(group_uuid, (row.group_name, 0));
+            let group = parse_uuid_vec(row.group_uuid)?;
+            let member = parse_uuid_vec(row.member_uuid)?;
+            let kv = serde_json::json!({
+                "group_uuid": group,
+                "member_uuid": member,
+                "valid_from": row.valid_from.map(|v| Utc.from_utc_datetime(&v)),
+                "valid_until": row.valid_until.map(|v| Utc.from_utc_datetime(&v)),
+            });
+            sqlx::query!(
+                "DELETE FROM `group_members` WHERE `group_uuid` = ? AND `member_uuid` = ?",
+                group,
+                member
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO `audit` (`subject_uuid`, `level`, `msg_code`, `msg`, `kv`) VALUES (?, 'I', 'group.membership.expired', ?, ?)",
+                member,
+                format!("Membership of {} in group {} expired", member, group),
+                kv.to_string()
+            )
+            .execute(&mut *tx)
+            .await?;
+            count += 1;
         }
 
-        let mut old_count = 0;
-        let mut level = 1;
-        // Until all_groups size stabilizes...
-        while old_count != groups.len() {
-            old_count = groups.len();
-
-            // ... construct a query to find the parents ...
-            let mut sql_query = "SELECT `group_uuid`, `group_name` FROM `group_members_view` WHERE `member_uuid` IN (".to_string();
-            let mut first_flag = true;
-            for _ in 0..groups.len() {
-                if first_flag {
-                    sql_query += "?";
-                    first_flag = false;
-                } else {
-                    sql_query += ", ?";
-                }
-            }
-            sql_query += ")";
-            let mut query = sqlx::query_as(&sql_query);
-            for group in &groups {
-                query = query.bind(group.0);
-            }
+        Ok(count)
+    }
+}
 
-            // ... and add each result to groups
-            let rows: Vec<(Uuid, String)> = query.fetch_all(&mut *tx).await?;
-            for row in rows {
-                if groups.get(&row.0).is_none() {
-                    groups.insert(row.0, (row.1, level));
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    const BENCH_ROUNDS: u32 = 20;
+
+    #[test]
+    fn test_bounded_membership() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        let (current, past, future) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut groups = GroupMembership::new();
+        groups.add_bounded(current, "Current", Some(now - hour), Some(now + hour));
+        groups.add_bounded(past, "Past", None, Some(now - hour));
+        groups.add_bounded(future, "Future", Some(now + hour), None);
+
+        assert!(groups.has(current));
+        assert!(!groups.has(past));
+        assert!(!groups.has(future));
+        assert_eq!(groups.to_keys_set().len(), 1);
+        assert!(groups.get(future).unwrap().is_active_at(now + hour * 2));
+
+        let json = serde_json::to_value(&groups).unwrap();
+        assert!(json[current.to_string()]["valid_until"].is_string());
+        let back: GroupMembership = serde_json::from_value(json).unwrap();
+        assert_eq!(back, groups);
+    }
+
+    #[test]
+    fn test_added_or_rebounded() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        let (same, extended, added, future) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut before = GroupMembership::new();
+        before.add(same, "Same");
+        before.add_bounded(extended, "Extended", None, Some(now + hour));
+
+        let mut after = before.clone();
+        assert!(after.added_or_rebounded(&before).is_empty());
+
+        after.add_bounded(extended, "Extended", None, Some(now + hour * 24));
+        after.add(added, "Added");
+        // Not in effect yet, still a new membership
+        after.add_bounded(future, "Future", Some(now + hour), None);
+        let changed = after.added_or_rebounded(&before);
+        assert_eq!(changed.len(), 3);
+        assert!(changed.contains(&extended) && changed.contains(&added) && changed.contains(&future));
+
+        // Dropping the bounds extends the membership too
+        after = before.clone();
+        after.add(extended, "Extended");
+        assert_eq!(after.added_or_rebounded(&before).into_iter().collect::<Vec<_>>(), vec![extended]);
+    }
+
+    async fn new_group(name: &str, tx: &mut Transaction<'_>) -> Uuid {
+        let mut group = Group::new(Uuid::new_v4(), name, "");
+        group.save(tx).await.unwrap();
+        group.get_uuid()
+    }
+
+    /// Pool on the database from `.env`, each test rolls its transaction back
+    async fn test_pool() -> MySqlPool {
+        dotenv::dotenv().ok();
+        let var = |name| std::env::var(name).unwrap();
+        crate::model::db::get_pool(&var("DB_HOST"), &var("DB_USER"), &var("DB_PASS"), &var("DB_NAME")).await
+    }
+
+    async fn bench_load_for(label: &str, uuid: Uuid, expected: usize, tx: &mut Transaction<'_>) {
+        let start = Instant::now();
+        for _ in 0..BENCH_ROUNDS {
+            let groups = GroupMembership::load_for(uuid, tx).await.unwrap();
+            assert_eq!(groups.to_keys_set().len(), expected);
+        }
+        info!("{}: {:?} per load_for", label, start.elapsed() / BENCH_ROUNDS);
+    }
+
+    /// Needs the database from `.env`, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_load_for_levels() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        let leaf = new_group("levels-leaf", &mut tx).await;
+        let middle = new_group("levels-middle", &mut tx).await;
+        let top = new_group("levels-top", &mut tx).await;
+        Group::add_member(middle, leaf, None, None, &mut tx).await.unwrap();
+        Group::add_member(top, middle, None, None, &mut tx).await.unwrap();
+
+        let groups = GroupMembership::load_for(leaf, &mut tx).await.unwrap();
+        assert_eq!(groups.to_keys_set().len(), 2);
+        assert_eq!(groups.get(middle).unwrap().level, 0);
+        assert_eq!(groups.get(top).unwrap().level, 1);
+        assert_eq!(groups.get(top).unwrap().name, "levels-top");
+        tx.rollback().await.unwrap();
+    }
+
+    /// Needs the database from `.env`, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_load_for_diamond() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        let member = new_group("diamond-member", &mut tx).await;
+        let top = new_group("diamond-top", &mut tx).await;
+        let mut sides = vec![];
+        for i in 0..2 {
+            let side = new_group(&format!("diamond-{}", i), &mut tx).await;
+            Group::add_member(side, member, None, None, &mut tx).await.unwrap();
+            Group::add_member(top, side, None, None, &mut tx).await.unwrap();
+            sides.push(side);
+        }
+        // A shortcut makes the top a direct parent too
+        Group::add_member(top, member, None, None, &mut tx).await.unwrap();
+
+        let groups = GroupMembership::load_for(member, &mut tx).await.unwrap();
+        assert_eq!(groups.to_keys_set().len(), 3);
+        assert_eq!(groups.get(top).unwrap().level, 0);
+        for side in sides {
+            assert_eq!(groups.get(side).unwrap().level, 0);
+        }
+        tx.rollback().await.unwrap();
+    }
+
+    /// Needs the database from `.env`, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_cycle_rejected() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        let leaf = new_group("cycle-leaf", &mut tx).await;
+        let top = new_group("cycle-top", &mut tx).await;
+        Group::add_member(top, leaf, None, None, &mut tx).await.unwrap();
+
+        assert!(Group::add_member(leaf, top, None, None, &mut tx).await.unwrap_err().is_validation());
+        assert!(Group::add_member(leaf, leaf, None, None, &mut tx).await.unwrap_err().is_validation());
+        // Bounded memberships count too, even when they are not in effect yet
+        let later = Utc::now() + chrono::Duration::days(1);
+        let other = new_group("cycle-other", &mut tx).await;
+        Group::add_member(other, top, Some(later), None, &mut tx).await.unwrap();
+        assert!(Group::add_member(leaf, other, None, None, &mut tx).await.unwrap_err().is_validation());
+        tx.rollback().await.unwrap();
+    }
+
+    /// Needs the database from `.env`, run with `cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn test_load_for_depth_bound() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        let leaf = new_group("depth-0", &mut tx).await;
+        let mut child = leaf;
+        for i in 1..(MAX_GROUP_DEPTH + 10) {
+            let parent = new_group(&format!("depth-{}", i), &mut tx).await;
+            Group::add_member(parent, child, None, None, &mut tx).await.unwrap();
+            child = parent;
+        }
+
+        let groups = GroupMembership::load_for(leaf, &mut tx).await.unwrap();
+        assert_eq!(groups.to_keys_set().len(), MAX_GROUP_DEPTH as usize + 1);
+        assert!(groups.iter().all(|(uuid, _)| groups.get(uuid).unwrap().level <= MAX_GROUP_DEPTH));
+        assert!(!groups.has(child));
+        tx.rollback().await.unwrap();
+    }
+
+    /// Needs the database from `.env`, run with `RUST_LOG=info cargo test -- --ignored bench_` to see the timings
+    #[tokio::test]
+    #[ignore]
+    async fn bench_deep_and_wide_hierarchies() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+
+        // A chain of 50 nested groups
+        let leaf = new_group("deep-0", &mut tx).await;
+        let mut child = leaf;
+        for i in 1..50 {
+            let parent = new_group(&format!("deep-{}", i), &mut tx).await;
+            Group::add_member(parent, child, None, None, &mut tx).await.unwrap();
+            child = parent;
+        }
+        bench_load_for("deep", leaf, 49, &mut tx).await;
+
+        // One group in 500 groups that are each in the same 10 groups
+        let member = new_group("wide-member", &mut tx).await;
+        let mut tops = vec![];
+        for i in 0..10 {
+            tops.push(new_group(&format!("wide-top-{}", i), &mut tx).await);
+        }
+        for i in 0..500 {
+            let middle = new_group(&format!("wide-{}", i), &mut tx).await;
+            Group::add_member(middle, member, None, None, &mut tx).await.unwrap();
+            for top in &tops {
+                Group::add_member(*top, middle, None, None, &mut tx).await.unwrap();
             }
-            // Go the next level of indirection
-            level += 1;
         }
+        bench_load_for("wide", member, 510, &mut tx).await;
 
-        Ok(GroupMembership(groups))
+        tx.rollback().await.unwrap();
     }
 }
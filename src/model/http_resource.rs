@@ -0,0 +1,109 @@
+use crate::model::prelude::*;
+
+/// A request made to an upstream protected by feroauth (e.g. behind a forward-auth reverse proxy)
+///
+/// Policies match on it with [`POLVERB_HTTP_ACCESS`], for instance:
+/// `allow(actor: User, "feroauth/http.access", req: HttpResource) if req.host = "wiki.example.com" and req.method = "GET";`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, PolarClass)]
+pub struct HttpResource {
+    #[polar(attribute)]
+    pub host: String,
+    /// Always uppercase
+    #[polar(attribute)]
+    pub method: String,
+    /// Path without the query string, always normalized (see [`HttpResource::normalize_path`])
+    #[polar(attribute)]
+    pub path: String,
+}
+
+/// Decodes every `%XX` once, anything that is not a valid escape is kept as is
+fn percent_decode(val: &str) -> String {
+    let bytes = val.as_bytes();
+    let mut ans = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|v| std::str::from_utf8(v).ok());
+        match (bytes[i], hex.and_then(|v| u8::from_str_radix(v, 16).ok())) {
+            (b'%', Some(byte)) => {
+                ans.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                ans.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&ans).into_owned()
+}
+
+impl HttpResource {
+    pub fn new(host: &str, method: &str, uri: &str) -> HttpResource {
+        let path = uri.split(|c| c == '?' || c == '#').next().unwrap_or_default();
+        HttpResource {
+            host: host.to_lowercase(),
+            method: method.to_uppercase(),
+            path: HttpResource::normalize_path(path),
+        }
+    }
+
+    /// Percent-decodes the path, then collapses `//`, drops `.` and resolves `..` (never above the root).
+    ///
+    /// Rules must see the path the upstream will serve, otherwise `/public/../admin`, `//admin` or `/%61dmin` would slip past `path_starts_with("/admin")`.
+    pub fn normalize_path(path: &str) -> String {
+        // Some upstreams also take backslashes as separators
+        let decoded = percent_decode(path).replace('\\', "/");
+        let mut segments: Vec<&str> = vec![];
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                _ => segments.push(segment),
+            }
+        }
+        let mut ans = format!("/{}", segments.join("/"));
+        let last = decoded.rsplit('/').next().unwrap_or_default();
+        if !segments.is_empty() && (decoded.ends_with('/') || last == "." || last == "..") {
+            ans.push('/');
+        }
+        ans
+    }
+
+    pub fn path_starts_with(&self, prefix: String) -> bool {
+        self.path.starts_with(&prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let res = HttpResource::new("Wiki.Example.com", "get", "/a/b?c=d");
+        assert_eq!("wiki.example.com", res.host);
+        assert_eq!("GET", res.method);
+        assert_eq!("/a/b", res.path);
+        assert!(res.path_starts_with("/a/".to_string()));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!("/admin", HttpResource::new("h", "GET", "/public/../admin").path);
+        assert_eq!("/admin", HttpResource::new("h", "GET", "//admin").path);
+        assert_eq!("/admin", HttpResource::new("h", "GET", "/%61dmin").path);
+        assert_eq!("/admin/", HttpResource::new("h", "GET", "/./admin/./").path);
+        assert_eq!("/admin", HttpResource::new("h", "GET", "/../../admin").path);
+        assert_eq!("/admin/x", HttpResource::new("h", "GET", "/public%2F..%2Fadmin/x").path);
+        assert_eq!("/admin", HttpResource::new("h", "GET", "/public\\..\\admin?x=/public").path);
+        assert_eq!("/a/", HttpResource::new("h", "GET", "/a/b/..").path);
+        assert_eq!("/", HttpResource::new("h", "GET", "").path);
+        assert_eq!("/%zz", HttpResource::new("h", "GET", "/%zz").path);
+
+        let res = HttpResource::new("h", "GET", "/public/../admin/users");
+        assert!(!res.path_starts_with("/public".to_string()));
+        assert!(res.path_starts_with("/admin/".to_string()));
+    }
+}
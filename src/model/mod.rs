@@ -1,19 +1,31 @@
 #![allow(unused)]
 
+pub mod app;
+pub mod claims;
 pub mod db;
+pub mod device_code;
 pub mod fset;
 pub mod group;
 pub mod group_membership;
+pub mod http_resource;
+pub mod oauth;
+pub mod oidc_key;
 pub mod password;
 pub mod policy_delegation;
 pub mod policy_enforcer;
 pub mod policy_rule;
+pub mod policy_verb;
 pub mod prelude;
+pub mod saml;
+pub mod scope;
+pub mod secret;
+pub mod selector;
 pub mod session;
 pub mod user;
 
 // SET = ADD/NEW + SAV + DEL
 // SAV = save
+// Every verb must also be listed in POLICY_VERBS
 
 pub const POLVERB_USER_ADD: &'static str = "feroauth/user.add";
 pub const POLVERB_USER_GET: &'static str = "feroauth/user.get";
@@ -29,6 +41,11 @@ pub const POLVERB_USER_GROUP_ADD: &'static str = "feroauth/user.groups.add";
 pub const POLVERB_USER_GROUP_DEL: &'static str = "feroauth/user.groups.del";
 pub const POLVERB_USER_LOGIN_ADD: &'static str = "feroauth/user.login.add";
 pub const POLVERB_USER_LOGIN_DEL: &'static str = "feroauth/user.login.del";
+// Reading single fields of a user, see UserView
+pub const POLVERB_USER_SUPER_GET: &'static str = "feroauth/user.super.get";
+pub const POLVERB_USER_LOGIN_GET: &'static str = "feroauth/user.login.get";
+pub const POLVERB_USER_GROUP_GET: &'static str = "feroauth/user.groups.get";
+pub const POLVERB_USER_DATES_GET: &'static str = "feroauth/user.dates.get";
 
 pub const POLVERB_GROUP_ADD: &'static str = "feroauth/group.add";
 pub const POLVERB_GROUP_GET: &'static str = "feroauth/group.get";
@@ -40,14 +57,33 @@ pub const POLVERB_GROUP_DEL: &'static str = "feroauth/group.del";
 pub const POLVERB_GROUP_MEMBER_ADD_ANYKIND: &'static str = "feroauth/group.add-anykind";
 pub const POLVERB_GROUP_MEMBER_DEL_ANYKIND: &'static str = "feroauth/group.del-anykind";
 
+pub const POLVERB_APP_ADD: &'static str = "feroauth/app.add";
+pub const POLVERB_APP_GET: &'static str = "feroauth/app.get";
+pub const POLVERB_APP_SAV: &'static str = "feroauth/app.sav";
+pub const POLVERB_APP_DEL: &'static str = "feroauth/app.del";
+pub const POLVERB_APP_SECRET_SET: &'static str = "feroauth/app.secret.set";
+
+// Access to upstreams protected through forward-auth or ext_authz
+pub const POLVERB_HTTP_ACCESS: &'static str = "feroauth/http.access";
+
+pub const POLVERB_POLICY_RULE_GET: &'static str = "feroauth/policy_rule.get";
 pub const POLVERB_POLICY_RULE_SET: &'static str = "feroauth/policy_rule.set";
 
+pub use app::{App, AppChange, MinApp};
+pub use device_code::{DeviceCodeStatus, OAuthDeviceCode};
 pub use fset::FSet;
-pub use group::Group;
-pub use group_membership::GroupMembership;
+pub use group::{Group, GroupChange};
+pub use group_membership::{GroupMembership, Membership};
+pub use http_resource::HttpResource;
+pub use oauth::{OAuthCode, OAuthToken, OAuthTokenKind, API_SCOPE};
+pub use oidc_key::{OidcAlg, OidcKey};
 pub use password::Password;
-pub use policy_delegation::PolicyDelegation;
-pub use policy_enforcer::PolicyEnforcer;
-pub use policy_rule::PolicyRule;
-pub use session::FullSession;
-pub use user::{MinUser, User, UserChange};
+pub use policy_delegation::{ActiveDelegation, DelegationSet, PolicyDelegation};
+pub use policy_enforcer::{PolicyDecision, PolicyEnforcer, PolicyExplanation};
+pub use policy_rule::{PolicyRule, PolicyRuleChange};
+pub use policy_verb::{PolicyVerb, POLICY_VERBS};
+pub use scope::Scope;
+pub use selector::Selector;
+pub use secret::{constant_time_eq, hash_secret, new_secret, pkce_s256};
+pub use session::{FullSession, SessionView};
+pub use user::{LoginHandle, MinUser, User, UserChange, UserView};
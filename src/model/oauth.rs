@@ -0,0 +1,288 @@
+use crate::model::prelude::*;
+use chrono::Duration;
+
+pub const OAUTH_CODE_LIFE: i64 = 10 * 60; // 10 min
+pub const OAUTH_ACCESS_TOKEN_LIFE: i64 = 60 * 60; // 1 hour
+pub const OAUTH_REFRESH_TOKEN_LIFE: i64 = 30 * 24 * 3600; // 30 days
+
+/// Authorization code issued by `/oauth/authorize` and redeemed once at `/oauth/token`.
+///
+/// Only the hash of the code is stored.
+#[derive(Debug, Clone)]
+pub struct OAuthCode {
+    code_hash: Vec<u8>,
+    pub app_uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub redirect_uri: String,
+    /// Space separated list of [`Scope::oauth_name`]
+    pub scope: String,
+    code_challenge: String,
+    /// OpenID Connect nonce to be copied into the ID token
+    pub nonce: Option<String>,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl OAuthCode {
+    /// Returns the new code and its clear text value (which must be sent to the client)
+    pub fn new(
+        app_uuid: Uuid,
+        user_uuid: Uuid,
+        redirect_uri: &str,
+        scope: &str,
+        code_challenge: &str,
+        nonce: Option<&str>,
+    ) -> (OAuthCode, String) {
+        let code = new_secret();
+        let now = Utc::now();
+        let ans = OAuthCode {
+            code_hash: hash_secret(&code),
+            app_uuid,
+            user_uuid,
+            redirect_uri: redirect_uri.to_string(),
+            scope: scope.to_string(),
+            code_challenge: code_challenge.to_string(),
+            nonce: nonce.map(|v| v.to_string()),
+            issued_at: now,
+            expires_at: now + Duration::seconds(OAUTH_CODE_LIFE),
+        };
+        (ans, code)
+    }
+
+    pub async fn save(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving OAuth code for app {:?} and user {:?}", self.app_uuid, self.user_uuid);
+        sqlx::query!(
+            "INSERT INTO `oauth_code` (`code_hash`, `app_uuid`, `user_uuid`, `redirect_uri`, `scope`, `code_challenge`, `nonce`, `issued_at`, `expires_at`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            self.code_hash,
+            self.app_uuid,
+            self.user_uuid,
+            self.redirect_uri,
+            self.scope,
+            self.code_challenge,
+            self.nonce,
+            self.issued_at,
+            self.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Loads and deletes the code so it can never be used twice. Expired codes are treated as not found.
+    pub async fn consume(code: &str, tx: &mut Transaction<'_>) -> FResult<OAuthCode> {
+        let code_hash = hash_secret(code);
+        let row = sqlx::query!(
+            "SELECT `code_hash`, `app_uuid`, `user_uuid`, `redirect_uri`, `scope`, `code_challenge`, `nonce`, `issued_at`, `expires_at` FROM `oauth_code` WHERE `code_hash` = ? FOR UPDATE",
+            code_hash
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM `oauth_code` WHERE `code_hash` = ?", code_hash)
+            .execute(&mut *tx)
+            .await?;
+
+        let ans = OAuthCode {
+            code_hash: row.code_hash,
+            app_uuid: parse_uuid_vec(row.app_uuid)?,
+            user_uuid: parse_uuid_vec(row.user_uuid)?,
+            redirect_uri: row.redirect_uri,
+            scope: row.scope,
+            code_challenge: row.code_challenge,
+            nonce: row.nonce,
+            issued_at: Utc.from_utc_datetime(&row.issued_at),
+            expires_at: Utc.from_utc_datetime(&row.expires_at),
+        };
+        if ans.expires_at < Utc::now() {
+            return Err(FError::new(SQLError(SQLErrorReal::RowNotFound)));
+        }
+        Ok(ans)
+    }
+
+    /// Checks the PKCE `code_verifier` against the `S256` challenge given at `/oauth/authorize`
+    pub fn verify_pkce(&self, code_verifier: &str) -> bool {
+        constant_time_eq(
+            pkce_s256(code_verifier).as_bytes(),
+            self.code_challenge.as_bytes(),
+        )
+    }
+
+    /// Deletes codes that expired without being used
+    pub async fn delete_expired(tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!("DELETE FROM `oauth_code` WHERE `expires_at` < ?", Utc::now())
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OAuthTokenKind {
+    Access,
+    Refresh,
+}
+
+impl OAuthTokenKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OAuthTokenKind::Access => "ACCESS",
+            OAuthTokenKind::Refresh => "REFRESH",
+        }
+    }
+
+    #[track_caller]
+    pub fn parse(val: &str) -> FResult<Self> {
+        match val {
+            "ACCESS" => Ok(OAuthTokenKind::Access),
+            "REFRESH" => Ok(OAuthTokenKind::Refresh),
+            _ => Err(FError::new_faux_panic_3("unknown OAuth token kind", val)),
+        }
+    }
+
+    pub fn life(&self) -> Duration {
+        match self {
+            OAuthTokenKind::Access => Duration::seconds(OAUTH_ACCESS_TOKEN_LIFE),
+            OAuthTokenKind::Refresh => Duration::seconds(OAUTH_REFRESH_TOKEN_LIFE),
+        }
+    }
+}
+
+/// Scope an access token needs to be used on the feroauth API (unless it belongs to a service account)
+pub const API_SCOPE: &'static str = "feroauth";
+
+/// Access or refresh token. Only the hash of the token is stored.
+#[derive(Debug, Clone, Serialize)]
+pub struct OAuthToken {
+    uuid: Uuid,
+    #[serde(skip)]
+    token_hash: Vec<u8>,
+    pub kind: OAuthTokenKind,
+    pub app_uuid: Uuid,
+    pub user_uuid: Uuid,
+    /// Refresh token this token was issued with (if any), revoking it revokes this token too
+    pub parent_uuid: Option<Uuid>,
+    /// Space separated list of [`Scope::oauth_name`]
+    pub scope: String,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    revoked: bool,
+}
+
+impl OAuthToken {
+    /// Returns the new token and its clear text value (which must be sent to the client)
+    pub fn new(
+        kind: OAuthTokenKind,
+        app_uuid: Uuid,
+        user_uuid: Uuid,
+        parent_uuid: Option<Uuid>,
+        scope: &str,
+    ) -> (OAuthToken, String) {
+        let token = new_secret();
+        let now = Utc::now();
+        let ans = OAuthToken {
+            uuid: Uuid::new_v4(),
+            token_hash: hash_secret(&token),
+            kind,
+            app_uuid,
+            user_uuid,
+            parent_uuid,
+            scope: scope.to_string(),
+            issued_at: now,
+            expires_at: now + kind.life(),
+            revoked: false,
+        };
+        (ans, token)
+    }
+
+    #[inline]
+    pub fn get_uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.revoked && Utc::now() < self.expires_at
+    }
+
+    pub fn expires_in(&self) -> i64 {
+        (self.expires_at - Utc::now()).num_seconds().max(0)
+    }
+
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope.split_whitespace().collect()
+    }
+
+    pub async fn save(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving OAuth token {:?}", self.uuid);
+        sqlx::query!(
+            "INSERT INTO `oauth_token` (`uuid`, `token_hash`, `kind`, `app_uuid`, `user_uuid`, `parent_uuid`, `scope`, `issued_at`, `expires_at`, `revoked`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            self.uuid,
+            self.token_hash,
+            self.kind.as_str(),
+            self.app_uuid,
+            self.user_uuid,
+            self.parent_uuid,
+            self.scope,
+            self.issued_at,
+            self.expires_at,
+            self.revoked
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Loads a token by its clear text value. Revoked and expired tokens are also returned, check [`OAuthToken::is_active`].
+    pub async fn load_by_token(token: &str, tx: &mut Transaction<'_>) -> FResult<OAuthToken> {
+        let token_hash = hash_secret(token);
+        let row = sqlx::query!(
+            "SELECT `uuid`, `token_hash`, `kind`, `app_uuid`, `user_uuid`, `parent_uuid`, `scope`, `issued_at`, `expires_at`, `revoked` FROM `oauth_token` WHERE `token_hash` = ?",
+            token_hash
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(OAuthToken {
+            uuid: parse_uuid_vec(row.uuid)?,
+            token_hash: row.token_hash,
+            kind: OAuthTokenKind::parse(&row.kind)?,
+            app_uuid: parse_uuid_vec(row.app_uuid)?,
+            user_uuid: parse_uuid_vec(row.user_uuid)?,
+            parent_uuid: match row.parent_uuid {
+                Some(v) => Some(parse_uuid_vec(v)?),
+                None => None,
+            },
+            scope: row.scope,
+            issued_at: Utc.from_utc_datetime(&row.issued_at),
+            expires_at: Utc.from_utc_datetime(&row.expires_at),
+            revoked: row.revoked != 0,
+        })
+    }
+
+    /// Revokes the token and the tokens issued with it, returns the hashes of all of them (e.g. to evict them from caches)
+    pub async fn revoke(&mut self, tx: &mut Transaction<'_>) -> FResult<Vec<Vec<u8>>> {
+        debug!("Revoking OAuth token {:?}", self.uuid);
+        self.revoked = true;
+        let rows = sqlx::query!(
+            "SELECT `token_hash` FROM `oauth_token` WHERE `uuid` = ? OR `parent_uuid` = ? FOR UPDATE",
+            self.uuid,
+            self.uuid
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE `oauth_token` SET `revoked` = 1 WHERE `uuid` = ? OR `parent_uuid` = ?",
+            self.uuid,
+            self.uuid
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.token_hash).collect())
+    }
+
+    /// Deletes tokens that can no longer be used
+    pub async fn delete_expired(tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!("DELETE FROM `oauth_token` WHERE `expires_at` < ?", Utc::now())
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+}
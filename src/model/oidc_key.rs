@@ -0,0 +1,222 @@
+use crate::model::prelude::*;
+use chrono::Duration;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+
+/// How long a key is used for signing before a new one is generated
+pub const OIDC_KEY_ROTATION: i64 = 30 * 24 * 3600; // 30 days
+/// How long a retired key is still published in the JWKS so tokens signed with it can be verified
+pub const OIDC_KEY_GRACE: i64 = 24 * 3600; // 1 day
+pub const OIDC_RSA_BITS: u32 = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OidcAlg {
+    RS256,
+    EdDSA,
+}
+
+impl OidcAlg {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OidcAlg::RS256 => "RS256",
+            OidcAlg::EdDSA => "EdDSA",
+        }
+    }
+
+    #[track_caller]
+    pub fn parse(val: &str) -> FResult<Self> {
+        match val {
+            "RS256" => Ok(OidcAlg::RS256),
+            "EdDSA" => Ok(OidcAlg::EdDSA),
+            _ => Err(FError::new_faux_panic_3("unknown OIDC signing algorithm", val)),
+        }
+    }
+}
+
+/// Key used to sign ID tokens. The key id (`kid`) is the UUID.
+pub struct OidcKey {
+    uuid: Uuid,
+    pub alg: OidcAlg,
+    key: PKey<Private>,
+    pub created_at: DateTime<Utc>,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+impl std::fmt::Debug for OidcKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcKey")
+            .field("uuid", &self.uuid)
+            .field("alg", &self.alg)
+            .field("created_at", &self.created_at)
+            .field("retired_at", &self.retired_at)
+            .finish()
+    }
+}
+
+impl OidcKey {
+    #[inline]
+    pub fn get_uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    pub fn kid(&self) -> String {
+        self.uuid.to_string()
+    }
+
+    pub fn generate(alg: OidcAlg) -> FResult<OidcKey> {
+        let key = match alg {
+            OidcAlg::RS256 => PKey::from_rsa(Rsa::generate(OIDC_RSA_BITS)?)?,
+            OidcAlg::EdDSA => PKey::generate_ed25519()?,
+        };
+        Ok(OidcKey {
+            uuid: Uuid::new_v4(),
+            alg,
+            key,
+            created_at: Utc::now(),
+            retired_at: None,
+        })
+    }
+
+    pub async fn save(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving OIDC key {:?}", self.uuid);
+        let pem = self.key.private_key_to_pem_pkcs8()?;
+        sqlx::query!(
+            "INSERT INTO `oidc_key` (`uuid`, `alg`, `private_key`, `created_at`, `retired_at`) VALUES (?, ?, ?, ?, ?)",
+            self.uuid,
+            self.alg.as_str(),
+            pem,
+            self.created_at,
+            self.retired_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Loads every key that must appear in the JWKS (i.e. active keys and keys retired less than [`OIDC_KEY_GRACE`] ago)
+    pub async fn load_published(tx: &mut Transaction<'_>) -> FResult<Vec<OidcKey>> {
+        let cutoff = Utc::now() - Duration::seconds(OIDC_KEY_GRACE);
+        let rows = sqlx::query!(
+            "SELECT `uuid`, `alg`, `private_key`, `created_at`, `retired_at` FROM `oidc_key` WHERE `retired_at` IS NULL OR `retired_at` > ? ORDER BY `created_at` DESC",
+            cutoff
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut ans = Vec::new();
+        for row in rows {
+            ans.push(OidcKey {
+                uuid: parse_uuid_vec(row.uuid)?,
+                alg: OidcAlg::parse(&row.alg)?,
+                key: PKey::private_key_from_pem(&row.private_key)?,
+                created_at: Utc.from_utc_datetime(&row.created_at),
+                retired_at: row.retired_at.as_ref().map(|dt| Utc.from_utc_datetime(dt)),
+            });
+        }
+        Ok(ans)
+    }
+
+    /// Returns the key that should be used for signing, rotating keys older than [`OIDC_KEY_ROTATION`]
+    pub async fn get_signing_key(alg: OidcAlg, tx: &mut Transaction<'_>) -> FResult<OidcKey> {
+        let now = Utc::now();
+        let row = sqlx::query!(
+            "SELECT `uuid`, `alg`, `private_key`, `created_at`, `retired_at` FROM `oidc_key` WHERE `retired_at` IS NULL AND `alg` = ? ORDER BY `created_at` DESC LIMIT 1 FOR UPDATE",
+            alg.as_str()
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(row) = row {
+            let created_at = Utc.from_utc_datetime(&row.created_at);
+            if now - created_at < Duration::seconds(OIDC_KEY_ROTATION) {
+                return Ok(OidcKey {
+                    uuid: parse_uuid_vec(row.uuid)?,
+                    alg: alg,
+                    key: PKey::private_key_from_pem(&row.private_key)?,
+                    created_at,
+                    retired_at: None,
+                });
+            }
+        }
+
+        info!("Rotating OIDC {} signing key", alg.as_str());
+        OidcKey::retire_all(alg, tx).await?;
+        let key = OidcKey::generate(alg)?;
+        key.save(tx).await?;
+        Ok(key)
+    }
+
+    /// Stops using all active keys for signing. They remain published for [`OIDC_KEY_GRACE`].
+    pub async fn retire_all(alg: OidcAlg, tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!(
+            "UPDATE `oidc_key` SET `retired_at` = ? WHERE `retired_at` IS NULL AND `alg` = ?",
+            Utc::now(),
+            alg.as_str()
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes keys that are no longer published
+    pub async fn delete_expired(tx: &mut Transaction<'_>) -> FResult<()> {
+        let cutoff = Utc::now() - Duration::seconds(OIDC_KEY_GRACE);
+        sqlx::query!("DELETE FROM `oidc_key` WHERE `retired_at` < ?", cutoff)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Encodes and signs the claims as a compact JWS (RFC 7515)
+    pub fn sign_jwt(&self, claims: &serde_json::Value) -> FResult<String> {
+        let header = serde_json::json!({
+            "alg": self.alg.as_str(),
+            "typ": "JWT",
+            "kid": self.kid(),
+        });
+        let header = base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD);
+        let payload = base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD);
+        let signing_input = format!("{}.{}", header, payload);
+
+        let signature = match self.alg {
+            OidcAlg::RS256 => {
+                let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+                signer.update(signing_input.as_bytes())?;
+                signer.sign_to_vec()?
+            }
+            OidcAlg::EdDSA => {
+                let mut signer = Signer::new_without_digest(&self.key)?;
+                signer.sign_oneshot_to_vec(signing_input.as_bytes())?
+            }
+        };
+        let signature = base64::encode_config(&signature, base64::URL_SAFE_NO_PAD);
+        Ok(format!("{}.{}", signing_input, signature))
+    }
+
+    /// Public part of the key as a JWK (RFC 7517)
+    pub fn to_jwk(&self) -> FResult<serde_json::Value> {
+        match self.alg {
+            OidcAlg::RS256 => {
+                let rsa = self.key.rsa()?;
+                Ok(serde_json::json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": self.alg.as_str(),
+                    "kid": self.kid(),
+                    "n": base64::encode_config(rsa.n().to_vec(), base64::URL_SAFE_NO_PAD),
+                    "e": base64::encode_config(rsa.e().to_vec(), base64::URL_SAFE_NO_PAD),
+                }))
+            }
+            OidcAlg::EdDSA => Ok(serde_json::json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": self.alg.as_str(),
+                "kid": self.kid(),
+                "x": base64::encode_config(self.key.raw_public_key()?, base64::URL_SAFE_NO_PAD),
+            })),
+        }
+    }
+}
@@ -1,4 +1,8 @@
 use crate::model::prelude::*;
+use std::sync::{Arc, RwLock};
+
+pub const MAX_POLICY_DELEGATION_ACTION_LEN: usize = 190;
+pub const MAX_POLICY_DELEGATION_LIFE: i64 = 90 * 24 * 3600; // 90 days
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 // make everything private to help enforce permissions!
@@ -26,3 +30,341 @@ struct PolicyDelegationChange {
     pub granted_at: Option<DateTime<Utc>>,
     pub valid_until: Option<DateTime<Utc>>,
 }
+
+/// A row of `policy_delegation`, `to_type` is the `object_type` of `to_uuid`
+struct DelegationRow {
+    uuid: Vec<u8>,
+    _revision: i32,
+    from_uuid: Vec<u8>,
+    to_uuid: Vec<u8>,
+    to_type: Option<String>,
+    desc: String,
+    action: String,
+    resource_uuid: Option<Vec<u8>>,
+    resource_group: i8,
+    granted_at: chrono::NaiveDateTime,
+    valid_until: chrono::NaiveDateTime,
+}
+
+impl DelegationRow {
+    fn into_delegation(self) -> FResult<PolicyDelegation> {
+        let to = parse_uuid_vec(self.to_uuid)?;
+        Ok(PolicyDelegation {
+            uuid: parse_uuid_vec(self.uuid)?,
+            _revision: self._revision,
+            desc: self.desc,
+            from: parse_uuid_vec(self.from_uuid)?,
+            to: UuidObjectOption::new(Some(to), self.to_type.as_deref() == Some("GROUP")),
+            resource: UuidObjectOption::new(
+                match self.resource_uuid {
+                    Some(v) => Some(parse_uuid_vec(v)?),
+                    None => None,
+                },
+                self.resource_group != 0,
+            ),
+            action: self.action,
+            granted_at: Utc.from_utc_datetime(&self.granted_at),
+            valid_until: Utc.from_utc_datetime(&self.valid_until),
+        })
+    }
+}
+
+impl PolicyDelegation {
+    pub fn new(
+        from: Uuid,
+        to: UuidObjectOption,
+        action: &str,
+        resource: UuidObjectOption,
+        valid_until: DateTime<Utc>,
+        desc: &str,
+    ) -> Self {
+        PolicyDelegation {
+            uuid: Uuid::new_v4(),
+            _revision: 0,
+            desc: desc.to_string(),
+            from,
+            to,
+            resource,
+            action: action.to_string(),
+            granted_at: Utc::now(),
+            valid_until,
+        }
+    }
+
+    #[inline]
+    pub fn get_uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    #[inline]
+    pub fn get_from(&self) -> Uuid {
+        self.from
+    }
+
+    #[inline]
+    pub fn get_action(&self) -> &str {
+        &self.action
+    }
+
+    #[inline]
+    pub fn get_resource(&self) -> &UuidObjectOption {
+        &self.resource
+    }
+
+    pub fn is_active(&self) -> bool {
+        Utc::now() < self.valid_until
+    }
+
+    /// The delegation is given to `user`, directly or through one of their groups
+    pub fn is_granted_to(&self, user: &User) -> bool {
+        match self.to.to_uuid_option() {
+            Some(uuid) => uuid == user.get_uuid() || user.groups.has(uuid),
+            None => false,
+        }
+    }
+
+    /// The delegation applies to the object `uuid` which is in `groups`
+    pub fn covers(&self, uuid: Uuid, groups: &GroupMembership) -> bool {
+        match self.resource {
+            NoObject => true,
+            OneObject(v) => v == uuid,
+            ObjectsInGroup(v) => groups.has(v),
+        }
+    }
+
+    pub async fn load_by_uuid(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<Self> {
+        trace!("Loading PolicyDelegation {:?}", uuid);
+        let row = sqlx::query_as_unchecked!(
+            DelegationRow,
+            "SELECT D.`uuid`, D.`_revision`, D.`from_uuid`, D.`to_uuid`, OT.`type` AS `to_type`, D.`desc`, D.`action`, D.`resource_uuid`, D.`resource_group`, D.`granted_at`, D.`valid_until` FROM `policy_delegation` D LEFT JOIN `object_type` OT ON (OT.`uuid` = D.`to_uuid`) WHERE D.`uuid` = ?",
+            uuid
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        row.into_delegation()
+    }
+
+    /// Every delegation that did not expire yet
+    pub async fn load_active(tx: &mut Transaction<'_>) -> FResult<Vec<Self>> {
+        let rows = sqlx::query_as_unchecked!(
+            DelegationRow,
+            "SELECT D.`uuid`, D.`_revision`, D.`from_uuid`, D.`to_uuid`, OT.`type` AS `to_type`, D.`desc`, D.`action`, D.`resource_uuid`, D.`resource_group`, D.`granted_at`, D.`valid_until` FROM `policy_delegation` D LEFT JOIN `object_type` OT ON (OT.`uuid` = D.`to_uuid`) WHERE D.`valid_until` > ?",
+            Utc::now()
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        rows.into_iter().map(DelegationRow::into_delegation).collect()
+    }
+
+    /// Every delegation given by `from`, expired ones included
+    pub async fn load_given(from: Uuid, tx: &mut Transaction<'_>) -> FResult<Vec<Self>> {
+        let rows = sqlx::query_as_unchecked!(
+            DelegationRow,
+            "SELECT D.`uuid`, D.`_revision`, D.`from_uuid`, D.`to_uuid`, OT.`type` AS `to_type`, D.`desc`, D.`action`, D.`resource_uuid`, D.`resource_group`, D.`granted_at`, D.`valid_until` FROM `policy_delegation` D LEFT JOIN `object_type` OT ON (OT.`uuid` = D.`to_uuid`) WHERE D.`from_uuid` = ? ORDER BY D.`granted_at` DESC",
+            from
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        rows.into_iter().map(DelegationRow::into_delegation).collect()
+    }
+
+    /// Active delegations given to `user` or one of their groups
+    pub async fn load_received(user: &User, tx: &mut Transaction<'_>) -> FResult<Vec<Self>> {
+        Ok(PolicyDelegation::load_active(tx)
+            .await?
+            .into_iter()
+            .filter(|d| d.is_granted_to(user))
+            .collect())
+    }
+
+    pub async fn delete(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!("DELETE FROM `policy_delegation` WHERE `uuid` = ?", uuid)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Vec<InvalidValue> {
+        let mut ans = vec![];
+        let len = self.action.chars().count();
+        if !(MIN_NON_EMPTY_STR <= len && len <= MAX_POLICY_DELEGATION_ACTION_LEN) {
+            ans.push(InvalidValue::OutOfRange(
+                "policy_delegation.action",
+                MIN_NON_EMPTY_STR,
+                MAX_POLICY_DELEGATION_ACTION_LEN,
+            ));
+        }
+        if self.to == NoObject {
+            ans.push(InvalidValue::MustNotNull("policy_delegation.to"));
+        }
+        if self.valid_until <= self.granted_at || self.valid_until > self.granted_at + chrono::Duration::seconds(MAX_POLICY_DELEGATION_LIFE) {
+            ans.push(InvalidValue::BadFormat("policy_delegation.valid_until"));
+        }
+        ans
+    }
+
+    pub fn validate_as_err(&self) -> FResult<()> {
+        let errs = self.validate();
+        if errs.len() != 0 {
+            return Err(FError::new(ValidationError(errs)));
+        }
+        Ok(())
+    }
+
+    pub async fn save(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving PolicyDelegation {:?}", self.uuid);
+
+        self.validate_as_err()?;
+
+        match self._revision {
+            0 => self.db_insert(tx).await?,
+            _ => self.db_update(tx).await?,
+        };
+        Ok(())
+    }
+
+    async fn db_insert(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        self._revision = 1;
+        let (resource_uuid, resource_group) = self.resource.to_pair(None, false);
+        sqlx::query!(
+            "INSERT INTO `policy_delegation` (`uuid`, `_revision`, `from_uuid`, `to_uuid`, `desc`, `action`, `resource_uuid`, `resource_group`, `granted_at`, `valid_until`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            self.uuid,
+            self._revision,
+            self.from,
+            self.to.to_uuid_option(),
+            self.desc,
+            self.action,
+            resource_uuid,
+            resource_group,
+            self.granted_at,
+            self.valid_until
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    async fn db_update(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        self._revision += 1;
+        let (resource_uuid, resource_group) = self.resource.to_pair(None, false);
+        sqlx::query!(
+            "UPDATE `policy_delegation` SET `_revision` = ?, `to_uuid` = ?, `desc` = ?, `action` = ?, `resource_uuid` = ?, `resource_group` = ?, `valid_until` = ? WHERE `uuid` = ?",
+            self._revision,
+            self.to.to_uuid_option(),
+            self.desc,
+            self.action,
+            resource_uuid,
+            resource_group,
+            self.valid_until,
+            self.uuid
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+}
+
+/// A delegation as seen by Polar, with the user who gave it
+#[derive(Debug, Clone, PolarClass)]
+pub struct ActiveDelegation {
+    #[polar(attribute)]
+    pub from_user: User,
+    delegation: PolicyDelegation,
+}
+
+impl ActiveDelegation {
+    pub fn new(from_user: User, delegation: PolicyDelegation) -> Self {
+        ActiveDelegation { from_user, delegation }
+    }
+
+    pub fn polar_covers(&self, uuid: Uuid, groups: GroupMembership) -> bool {
+        self.delegation.covers(uuid, &groups)
+    }
+
+    pub fn polar_covers_any(&self) -> bool {
+        self.delegation.resource == NoObject
+    }
+
+    pub fn polar_covers_uuid(&self, uuid: Uuid) -> bool {
+        self.delegation.covers(uuid, &GroupMembership::new())
+    }
+}
+
+/// Delegations known to the policy, shared by every engine of a [`PolicyEnforcer`] and registered as
+/// `POLICY_DELEGATIONS`
+#[derive(Debug, Clone, PolarClass)]
+pub struct DelegationSet(Arc<RwLock<Vec<ActiveDelegation>>>);
+
+impl DelegationSet {
+    pub fn new() -> Self {
+        DelegationSet(Arc::new(RwLock::new(Vec::new())))
+    }
+
+    /// Loads the active delegations along with the users who gave them.
+    ///
+    /// Givers are kept as loaded here, changes to them or to their groups bump `policy_revision` (see V13.sql) so the
+    /// next poll reloads them.
+    pub async fn load(tx: &mut Transaction<'_>, enforcer: &PolicyEnforcer) -> FResult<Vec<ActiveDelegation>> {
+        let su = User::system_super_user();
+        let mut ans = Vec::new();
+        for delegation in PolicyDelegation::load_active(tx).await? {
+            let from_user = User::load_by_uuid(delegation.from, &su, enforcer, tx).await?;
+            ans.push(ActiveDelegation::new(from_user, delegation));
+        }
+        Ok(ans)
+    }
+
+    pub fn replace(&self, delegations: Vec<ActiveDelegation>) -> FResult<()> {
+        *self.0.write().map_err(|_| FError::new(LockError))? = delegations;
+        Ok(())
+    }
+
+    /// Delegations of `action` given to `actor` (or their groups) that did not expire, expiry is checked at each call
+    pub fn polar_granted_to(&self, actor: User, action: String) -> Vec<ActiveDelegation> {
+        let delegations = match self.0.read() {
+            Ok(v) => v,
+            Err(err) => {
+                error!("{:?}", err);
+                return Vec::new();
+            }
+        };
+        delegations
+            .iter()
+            .filter(|d| d.delegation.action == action && d.delegation.is_active() && d.delegation.is_granted_to(&actor))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_granted_to_and_covers() {
+        let group = Uuid::new_v4();
+        let mut member = User::new();
+        member.groups.add(group, "Group");
+        let other = User::new();
+        let resource = Uuid::new_v4();
+
+        let mut d = PolicyDelegation::new(
+            Uuid::new_v4(),
+            ObjectsInGroup(group),
+            "foo",
+            OneObject(resource),
+            Utc::now() + chrono::Duration::hours(1),
+            "",
+        );
+        assert!(d.is_granted_to(&member));
+        assert!(!d.is_granted_to(&other));
+        assert!(d.covers(resource, &GroupMembership::new()));
+        assert!(!d.covers(Uuid::new_v4(), &GroupMembership::new()));
+        assert!(d.is_active());
+
+        d.valid_until = Utc::now() - chrono::Duration::seconds(1);
+        assert!(!d.is_active());
+    }
+}
@@ -2,12 +2,92 @@ use std::sync::Arc;
 use crate::model::prelude::*;
 use oso::Oso;
 use oso::ToPolar;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::RwLock;
 use std::sync::{RwLockReadGuard, RwLockWriteGuard, TryLockError, TryLockResult};
 
+/// Rules every policy builds on, by name
+const BASIC_RULES: &[(&str, &str)] = &[
+    ("user.sav-new", r#"allow(actor: User, POLVERB_USER_SAV, user: User) if allow(actor, POLVERB_USER_ADD, user) and user.is_new();"#),
+    ("app.sav-new", r#"allow(actor: User, POLVERB_APP_SAV, app: App) if allow(actor, POLVERB_APP_ADD, app) and app.is_new();"#),
+    ("superuser", r#"allow(actor: User, _, _) if actor.superuser;"#),
+    ("user.get-self", r#"allow(actor: User, action, user: User) if action in [POLVERB_USER_GET, POLVERB_USER_SUPER_GET, POLVERB_USER_LOGIN_GET, POLVERB_USER_GROUP_GET, POLVERB_USER_DATES_GET] and actor.uuid = user.uuid;"#),
+    // Owners look after their service accounts, but creating one is left to the policy rules
+    ("service-account-owner", r#"allow(actor: User, action, user: User) if action in [POLVERB_USER_GET, POLVERB_USER_SAV, POLVERB_USER_NAME_SET] and not user.is_new() and user.is_owned_by(actor);"#),
+    ("user-allowed", r#"allow(actor: User, action, resource) if user_allowed(actor, action, resource);"#),
+    ("user-allowed-any", r#"user_allowed_any(actor: User, action) if user_allowed(actor, action, _);"#),
+    ("delegation", r#"allow(actor: User, action, resource) if delegation_exists(other_actor, actor, action, resource) and user_allowed(other_actor, action, resource);"#),
+    // Delegations are looked up in the DelegationSet of the enforcer, expired ones are skipped
+    ("delegation-exists", r#"delegation_exists(other_actor, actor: User, action, resource) if delegation in POLICY_DELEGATIONS.granted_to(actor, action) and delegation_covers(delegation, resource) and other_actor = delegation.from_user;"#),
+    ("delegation-covers", r#"delegation_covers(delegation, _) if delegation.covers_any();
+delegation_covers(delegation, resource: User) if delegation.covers(resource.uuid, resource.groups);
+delegation_covers(delegation, resource: Group) if delegation.covers(resource.uuid, resource.groups);
+delegation_covers(delegation, resource: PolicyRule) if delegation.covers(resource.uuid, resource.groups);
+delegation_covers(delegation, resource: App) if delegation.covers_uuid(resource.uuid);"#),
+];
+
+/// A DB rule as loaded in the enforcer
+struct LoadedRule {
+    uuid: Uuid,
+    title: String,
+    code: String,
+    /// The rule next to the basic rules only
+    alone: Oso,
+}
+
+/// Scratch engines of [`PolicyEnforcer::explain`], built on the first explanation of each policy revision
+struct Explainers {
+    revision: i64,
+    /// Each basic rule by itself
+    basic_alone: Vec<Oso>,
+    /// Every rule but one basic rule
+    without_basic: Vec<Oso>,
+    /// Every rule but one DB rule
+    without_rule: Vec<Oso>,
+}
+
 #[derive(Clone)]
 pub struct PolicyEnforcer {
     oso: Arc<RwLock<Oso>>,
+    /// Only the basic rules, tells apart the requests they allow by themselves
+    basic: Arc<Oso>,
+    rules: Arc<RwLock<Vec<LoadedRule>>>,
+    /// Shared by every engine above
+    delegations: DelegationSet,
+    /// Value of `policy_revision` when the DB rules were loaded, 0 before the first load
+    revision: Arc<AtomicI64>,
+    explainers: Arc<RwLock<Option<Explainers>>>,
+}
+
+/// Outcome of [`PolicyEnforcer::decide`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyDecision {
+    pub allowed: bool,
+    /// The basic rules allow the request without help from the DB rules (e.g. superusers)
+    pub builtin: bool,
+    /// DB rules that allow the request on their own
+    pub rules: Vec<Uuid>,
+}
+
+/// How one rule took part in a decision, see [`PolicyEnforcer::explain`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyRuleTrace {
+    /// `None` for basic rules
+    pub uuid: Option<Uuid>,
+    /// Title of DB rules or name of basic rules
+    pub title: String,
+    /// The rule allows the request by itself (DB rules are always next to the basic rules)
+    pub allows_alone: bool,
+    /// The request is allowed and would be denied without this rule
+    pub required: bool,
+}
+
+/// Outcome of [`PolicyEnforcer::explain`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyExplanation {
+    pub allowed: bool,
+    /// Every rule tried, basic rules first
+    pub rules: Vec<PolicyRuleTrace>,
 }
 
 impl std::fmt::Debug for PolicyEnforcer {
@@ -17,8 +97,13 @@ impl std::fmt::Debug for PolicyEnforcer {
 }
 
 impl PolicyEnforcer {
-    fn get_oso_r(&self) -> TryLockResult<RwLockReadGuard<'_, Oso>> {
-        self.oso.try_read()
+    fn get_oso_r(&self) -> FResult<RwLockReadGuard<'_, Oso>> {
+        // Reloads only hold the write lock to swap in a ready engine, so waiting is fine
+        self.oso.read().map_err(|_| FError::new(LockError))
+    }
+
+    fn get_rules_r(&self) -> FResult<RwLockReadGuard<'_, Vec<LoadedRule>>> {
+        self.rules.read().map_err(|_| FError::new(LockError))
     }
 
     fn get_oso_rw(&self) -> TryLockResult<RwLockWriteGuard<'_, Oso>> {
@@ -26,24 +111,56 @@ impl PolicyEnforcer {
     }
 
     fn add_basic_rules(oso: &Oso) -> FResult<()> {
-        oso.load_str(r#"allow(actor: User, POLVERB_USER_SAV, user: User) if allow(actor, POLVERB_USER_ADD, user) and user.is_new();"#)?;
-        oso.load_str(r#"allow(actor: User, _, _) if actor.superuser;"#)?;
-        oso.load_str(
-            r#"allow(actor: User, action, resource) if user_allowed(actor, action, resource);"#,
-        )?;
-        oso.load_str(r#"allow(actor: User, action, resource) if delegation_exists(other_actor, actor, action, resource) and user_allowed(other_actor, action, resource);"#)?;
+        for (_, code) in BASIC_RULES {
+            oso.load_str(code)?;
+        }
         Ok(())
     }
 
-    pub fn new() -> FResult<PolicyEnforcer> {
+    /// Makes an Oso instance with all classes and constants but no rules
+    fn new_bare_oso(delegations: &DelegationSet) -> FResult<Oso> {
         let mut oso = Oso::new();
-
         // Make classes
         oso.register_class(User::get_polar_class_builder()
             .add_method("is_new", User::is_new)
+            .add_method("is_service_account", User::is_service_account)
+            .add_method("is_owned_by", User::polar_is_owned_by)
+            .build()
+        )?;
+        oso.register_class(LoginHandle::get_polar_class_builder().build())?;
+        oso.register_class(Group::get_polar_class_builder()
+            .add_method("is_new", Group::is_new)
+            .build()
+        )?;
+        oso.register_class(MinObject::get_polar_class_builder().build())?;
+        oso.register_class(FullSession::get_polar_class_builder()
+            .add_method("is_valid", FullSession::is_valid)
+            .add_method("is_ephemeral", FullSession::is_ephemeral)
+            .add_method("is_impersonating", FullSession::is_impersonating)
+            .build()
+        )?;
+        oso.register_class(HttpResource::get_polar_class_builder()
+            .add_method("path_starts_with", HttpResource::path_starts_with)
+            .build()
+        )?;
+        oso.register_class(App::get_polar_class_builder()
+            .add_method("is_new", App::is_new)
+            .build()
+        )?;
+        oso.register_class(PolicyRule::get_polar_class_builder()
+            .add_method("is_new", PolicyRule::is_new)
+            .build()
+        )?;
+        oso.register_class(ActiveDelegation::get_polar_class_builder()
+            .add_method("covers", ActiveDelegation::polar_covers)
+            .add_method("covers_uuid", ActiveDelegation::polar_covers_uuid)
+            .add_method("covers_any", ActiveDelegation::polar_covers_any)
+            .build()
+        )?;
+        oso.register_class(DelegationSet::get_polar_class_builder()
+            .add_method("granted_to", DelegationSet::polar_granted_to)
             .build()
         )?;
-        oso.register_class(Group::get_polar_class_builder().build())?;
         oso.register_class(
             GroupMembership::get_polar_class_builder()
                 .add_method("has_uuid", GroupMembership::polar_has_uuid)
@@ -51,22 +168,75 @@ impl PolicyEnforcer {
                 .build(),
         )?;
 
-        oso.register_constant(POLVERB_USER_ADD, "POLVERB_USER_ADD")?;
-        oso.register_constant(POLVERB_USER_SAV, "POLVERB_USER_SAV")?;
+        for verb in POLICY_VERBS {
+            oso.register_constant(verb.verb, verb.name)?;
+        }
+        oso.register_constant(delegations.clone(), "POLICY_DELEGATIONS")?;
 
-        PolicyEnforcer::add_basic_rules(&oso);
+        Ok(oso)
+    }
+
+    /// Makes an Oso instance with all classes, constants and basic rules
+    fn new_oso(delegations: &DelegationSet) -> FResult<Oso> {
+        let oso = PolicyEnforcer::new_bare_oso(delegations)?;
+        PolicyEnforcer::add_basic_rules(&oso)?;
+        Ok(oso)
+    }
 
+    /// Makes an Oso instance with every rule but the basic rule `skip_basic` and the DB rule `skip_rule`
+    fn new_oso_without(&self, rules: &[LoadedRule], skip_basic: Option<usize>, skip_rule: Option<usize>) -> FResult<Oso> {
+        let oso = PolicyEnforcer::new_bare_oso(&self.delegations)?;
+        for (i, (_, code)) in BASIC_RULES.iter().enumerate() {
+            if Some(i) != skip_basic {
+                oso.load_str(code)?;
+            }
+        }
+        for (i, rule) in rules.iter().enumerate() {
+            if Some(i) != skip_rule {
+                oso.load_str(&rule.code)?;
+            }
+        }
+        Ok(oso)
+    }
+
+    /// Loads `codes` one after the other in a scratch engine next to the basic rules, returns the failing ones by index
+    pub fn try_compile(codes: &[&str]) -> FResult<Vec<(usize, OsoErrorReal)>> {
+        let oso = PolicyEnforcer::new_oso(&DelegationSet::new())?;
+        let mut ans = Vec::new();
+        for (i, code) in codes.iter().enumerate() {
+            if let Err(err) = oso.load_str(code) {
+                ans.push((i, err));
+            }
+        }
+        Ok(ans)
+    }
+
+    pub fn new() -> FResult<PolicyEnforcer> {
+        let delegations = DelegationSet::new();
         Ok(PolicyEnforcer {
-            oso: Arc::new(RwLock::new(oso)),
+            oso: Arc::new(RwLock::new(PolicyEnforcer::new_oso(&delegations)?)),
+            basic: Arc::new(PolicyEnforcer::new_oso(&delegations)?),
+            rules: Arc::new(RwLock::new(Vec::new())),
+            delegations,
+            revision: Arc::new(AtomicI64::new(0)),
+            explainers: Arc::new(RwLock::new(None)),
         })
     }
 
+    async fn load_revision(tx: &mut Transaction<'_>) -> FResult<i64> {
+        let row = sqlx::query!("SELECT `revision` FROM `policy_revision` WHERE `id` = 1")
+            .fetch_one(&mut *tx)
+            .await?;
+        Ok(row.revision)
+    }
+
+    /// Loads the DB rules in a new engine and swaps it in, every clone of this enforcer sees the new rules
     pub async fn reload(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        let revision = PolicyEnforcer::load_revision(tx).await?;
         // Load DB rules
         let rules = PolicyRule::load_all(tx).await?;
-        let oso = self.get_oso_rw()?;
-        oso.clear_rules();
-        PolicyEnforcer::add_basic_rules(&oso)?;
+        let mut loaded = Vec::new();
+        let oso = PolicyEnforcer::new_oso(&self.delegations)?;
         for rule in rules {
             if let Err(err) = oso.load_str(&rule.code) {
                 error!(
@@ -75,11 +245,131 @@ impl PolicyEnforcer {
                     rule.title,
                     err
                 );
+                continue;
             }
+            let alone = PolicyEnforcer::new_oso(&self.delegations)?;
+            alone.load_str(&rule.code)?;
+            loaded.push(LoadedRule {
+                uuid: rule.get_uuid(),
+                title: rule.title,
+                code: rule.code,
+                alone,
+            });
         }
+        *self.oso.write().map_err(|_| FError::new(LockError))? = oso;
+        *self.rules.write().map_err(|_| FError::new(LockError))? = loaded;
+        *self.explainers.write().map_err(|_| FError::new(LockError))? = None;
+        let delegations = DelegationSet::load(tx, self).await?;
+        self.delegations.replace(delegations)?;
+        self.revision.store(revision, Ordering::SeqCst);
+        debug!("Loaded policy revision {}", revision);
         Ok(())
     }
 
+    /// Reloads when `policy_revision` moved since the last load, whichever instance made the change
+    pub async fn reload_if_changed(&mut self, tx: &mut Transaction<'_>) -> FResult<bool> {
+        let revision = PolicyEnforcer::load_revision(tx).await?;
+        if revision == self.revision.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        self.reload(tx).await?;
+        Ok(true)
+    }
+
+    /// Like [`PolicyEnforcer::is_allowed`] but also tells which rules allow the request.
+    ///
+    /// A rule is reported when it allows the request by itself (with only the basic rules next to it), rules that only
+    /// work together are not.
+    pub fn decide<Resource>(&self, actor: &User, action: &str, resource: &Resource) -> FResult<PolicyDecision>
+    where
+        Resource: ToPolar + Clone,
+    {
+        let mut ans = PolicyDecision {
+            allowed: self.is_allowed(actor.clone(), action.to_string(), resource.clone())?,
+            builtin: false,
+            rules: Vec::new(),
+        };
+        if !ans.allowed {
+            return Ok(ans);
+        }
+        if self.basic.is_allowed(actor.clone(), action.to_string(), resource.clone())? {
+            ans.builtin = true;
+            return Ok(ans);
+        }
+        for rule in self.get_rules_r()?.iter() {
+            if rule.alone.is_allowed(actor.clone(), action.to_string(), resource.clone())? {
+                ans.rules.push(rule.uuid);
+            }
+        }
+        Ok(ans)
+    }
+
+    /// Builds the engines of [`PolicyEnforcer::explain`] for the rules loaded now
+    fn build_explainers(&self, revision: i64, rules: &[LoadedRule]) -> FResult<Explainers> {
+        let mut ans = Explainers {
+            revision,
+            basic_alone: Vec::with_capacity(BASIC_RULES.len()),
+            without_basic: Vec::with_capacity(BASIC_RULES.len()),
+            without_rule: Vec::with_capacity(rules.len()),
+        };
+        for (i, (_, code)) in BASIC_RULES.iter().enumerate() {
+            let alone = PolicyEnforcer::new_bare_oso(&self.delegations)?;
+            alone.load_str(code)?;
+            ans.basic_alone.push(alone);
+            ans.without_basic.push(self.new_oso_without(rules, Some(i), None)?);
+        }
+        for i in 0..rules.len() {
+            ans.without_rule.push(self.new_oso_without(rules, None, Some(i))?);
+        }
+        Ok(ans)
+    }
+
+    /// Tries every rule alone and the policy without each rule to tell which ones make the decision.
+    ///
+    /// The scratch engines (two per rule) are built on the first call after a reload and kept until the next one, so
+    /// the first explanation of each revision is slow. Meant for admins and debug logs, regular checks use
+    /// [`PolicyEnforcer::decide`].
+    pub fn explain<Resource>(&self, actor: &User, action: &str, resource: &Resource) -> FResult<PolicyExplanation>
+    where
+        Resource: ToPolar + Clone,
+    {
+        // Read before the rules: a reload in between leaves a stale revision behind, which is rebuilt next time
+        let revision = self.revision.load(Ordering::SeqCst);
+        let rules = self.get_rules_r()?;
+        let mut explainers = self.explainers.write().map_err(|_| FError::new(LockError))?;
+        if explainers.as_ref().map(|v| v.revision) != Some(revision) {
+            *explainers = Some(self.build_explainers(revision, &rules)?);
+        }
+        let explainers = explainers.as_ref().ok_or_else(|| FError::new(LockError))?;
+
+        let allowed = self.is_allowed(actor.clone(), action.to_string(), resource.clone())?;
+        let mut ans = PolicyExplanation {
+            allowed,
+            rules: Vec::new(),
+        };
+        for (i, (name, _)) in BASIC_RULES.iter().enumerate() {
+            let required = allowed
+                && !explainers.without_basic[i].is_allowed(actor.clone(), action.to_string(), resource.clone())?;
+            ans.rules.push(PolicyRuleTrace {
+                uuid: None,
+                title: name.to_string(),
+                allows_alone: explainers.basic_alone[i].is_allowed(actor.clone(), action.to_string(), resource.clone())?,
+                required,
+            });
+        }
+        for (i, rule) in rules.iter().enumerate() {
+            let required = allowed
+                && !explainers.without_rule[i].is_allowed(actor.clone(), action.to_string(), resource.clone())?;
+            ans.rules.push(PolicyRuleTrace {
+                uuid: Some(rule.uuid),
+                title: rule.title.clone(),
+                allows_alone: rule.alone.is_allowed(actor.clone(), action.to_string(), resource.clone())?,
+                required,
+            });
+        }
+        Ok(ans)
+    }
+
     #[track_caller]
     pub fn ensure_allowed<Resource>(
         &self,
@@ -92,11 +382,41 @@ impl PolicyEnforcer {
     {
         match self.is_allowed(actor.clone(), action.to_string(), resource.clone()) {
             Ok(true) => Ok(()),
-            Ok(false) => Err(FError::new_permission_error(actor, action, &resource)),
+            Ok(false) => {
+                // Explaining costs a query per rule, only pay for it when the explanation gets logged
+                if log::log_enabled!(log::Level::Debug) {
+                    match self.explain(actor, action, resource) {
+                        Ok(v) => debug!("Denied {} to {} on {:?}: {:?}", action, actor.get_uuid(), resource, v.rules),
+                        Err(err) => warn!("Failed to explain policy decision: {:?}", err),
+                    }
+                }
+                Err(FError::new_permission_error(actor, action, &resource))
+            }
             Err(err) => Err(err)
         }
     }
 
+    fn query_any<Args: oso::ToPolarList>(&self, rule: &str, args: Args) -> FResult<bool> {
+        match self.get_oso_r()?.query_rule(rule, args)?.next() {
+            Some(Ok(_)) => Ok(true),
+            Some(Err(err)) => Err(err.into()),
+            None => Ok(false),
+        }
+    }
+
+    /// The DB rules grant `action` on `resource` to `actor` by themselves (no superuser, ownership or delegation)
+    pub fn user_allowed<Resource>(&self, actor: &User, action: &str, resource: &Resource) -> FResult<bool>
+    where
+        Resource: ToPolar + Clone,
+    {
+        self.query_any("user_allowed", (actor.clone(), action.to_string(), resource.clone()))
+    }
+
+    /// The DB rules grant `action` on at least one resource to `actor`
+    pub fn user_allowed_any(&self, actor: &User, action: &str) -> FResult<bool> {
+        self.query_any("user_allowed_any", (actor.clone(), action.to_string()))
+    }
+
     #[track_caller]
     pub fn is_allowed<Actor, Action, Resource>(
         &self,
@@ -138,4 +458,50 @@ mod tests {
         assert_eq!(true, enforcer.is_allowed(user2, "foo", "bar").unwrap());
         assert_eq!(true, enforcer.is_allowed(superuser, "foo", "bar").unwrap());
     }
+
+    #[test]
+    fn test_explain() {
+        let enforcer = PolicyEnforcer::new().unwrap();
+        let user = User::new();
+        let mut superuser = User::new();
+        superuser.superuser = true;
+
+        let ans = enforcer.explain(&user, "foo", &"bar".to_string()).unwrap();
+        assert_eq!(false, ans.allowed);
+        assert!(ans.rules.iter().all(|r| !r.allows_alone && !r.required));
+
+        let ans = enforcer.explain(&superuser, "foo", &"bar".to_string()).unwrap();
+        assert_eq!(true, ans.allowed);
+        let rule = ans.rules.iter().find(|r| r.title == "superuser").unwrap();
+        assert!(rule.allows_alone && rule.required);
+        assert_eq!(1, ans.rules.iter().filter(|r| r.required).count());
+    }
+
+    #[test]
+    fn test_delegation() {
+        let enforcer = PolicyEnforcer::new().unwrap();
+        let oso = enforcer.get_oso_rw().unwrap();
+        oso.load_str(r#"user_allowed(actor: User, "foo", _) if actor.groups.has_uuid("e90b861a-5f59-4b2c-bd18-d18df76b27dd");"#)
+            .unwrap();
+        drop(oso);
+        let mut boss = User::new();
+        boss.groups.add(
+            Uuid::parse_str("e90b861a-5f59-4b2c-bd18-d18df76b27dd").unwrap(),
+            "Admins",
+        );
+        let helper = User::new();
+        let delegation = |valid_until| {
+            ActiveDelegation::new(
+                boss.clone(),
+                PolicyDelegation::new(boss.get_uuid(), OneObject(helper.get_uuid()), "foo", NoObject, valid_until, ""),
+            )
+        };
+
+        assert_eq!(false, enforcer.is_allowed(helper.clone(), "foo", "bar").unwrap());
+        enforcer.delegations.replace(vec![delegation(Utc::now() + chrono::Duration::hours(1))]).unwrap();
+        assert_eq!(true, enforcer.is_allowed(helper.clone(), "foo", "bar").unwrap());
+        assert_eq!(false, enforcer.is_allowed(helper.clone(), "baz", "bar").unwrap());
+        enforcer.delegations.replace(vec![delegation(Utc::now() - chrono::Duration::seconds(1))]).unwrap();
+        assert_eq!(false, enforcer.is_allowed(helper, "foo", "bar").unwrap());
+    }
 }
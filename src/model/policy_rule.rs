@@ -2,6 +2,25 @@ use crate::model::prelude::*;
 
 pub const MAX_POLICY_RULE_TITLE_LEN: usize = 190;
 
+/// Turns the `at line L, column C` of a Polar error message into a character position in `code`, 0 when missing
+fn polar_error_position(code: &str, msg: &str) -> usize {
+    let number_after = |marker: &str| -> Option<usize> {
+        let start = msg.rfind(marker)? + marker.len();
+        msg[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect::<String>()
+            .parse()
+            .ok()
+    };
+    let (line, column) = match (number_after("line "), number_after("column ")) {
+        (Some(l), Some(c)) => (l.max(1), c.max(1)),
+        _ => return 0,
+    };
+    let line_start: usize = code.split('\n').take(line - 1).map(|l| l.chars().count() + 1).sum();
+    (line_start + column - 1).min(code.chars().count())
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, PolarClass)]
 pub struct PolicyRule {
     #[polar(attribute)]
@@ -17,13 +36,64 @@ pub struct PolicyRule {
     pub groups: GroupMembership,
 }
 
+/// Changes to a [`PolicyRule`], `_revision` is the revision the change is based on
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PolicyRuleChange {
+    pub _revision: Option<i32>,
+    pub title: Option<String>,
+    pub desc: Option<String>,
+    pub code: Option<String>,
+}
+
 impl PolicyRule {
+    pub fn new(title: &str, desc: &str, code: &str) -> PolicyRule {
+        PolicyRule {
+            uuid: Uuid::new_v4(),
+            _revision: 0,
+            title: title.to_string(),
+            desc: desc.to_string(),
+            code: code.to_string(),
+            groups: GroupMembership::new(),
+        }
+    }
+
     #[inline]
     #[allow(unused)]
     pub fn get_uuid(&self) -> Uuid {
         self.uuid
     }
 
+    #[inline]
+    pub fn get_revision(&self) -> i32 {
+        self._revision
+    }
+
+    pub fn is_new(&self) -> bool {
+        self._revision == 0
+    }
+
+    /// Fails when the rule was saved since `revision`
+    pub fn ensure_revision(&self, revision: Option<i32>) -> FResult<()> {
+        match revision {
+            Some(v) if v != self._revision => Err(FError::new(StaleRevision(self.uuid, v))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply_changes(&mut self, changes: PolicyRuleChange) -> FResult<()> {
+        self.ensure_revision(changes._revision)?;
+        if let Some(v) = changes.title {
+            self.title = v;
+        }
+        if let Some(v) = changes.desc {
+            self.desc = v;
+        }
+        if let Some(v) = changes.code {
+            self.code = v;
+        }
+        Ok(())
+    }
+
     #[allow(unused)]
     pub async fn load_by_uuid(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<Self> {
         trace!("Loading PolicyRule {:?}", uuid);
@@ -41,7 +111,7 @@ impl PolicyRule {
             title: row.title,
             desc: row.desc,
             code: row.code,
-            groups: GroupMembership::new(),
+            groups: GroupMembership::load_for(uuid, tx).await?,
         })
     }
 
@@ -97,11 +167,53 @@ impl PolicyRule {
         Ok(())
     }
 
+    /// Compiles the rule together with all the other rules in a scratch engine.
+    ///
+    /// Fails when the rule does not compile or when it makes another rule fail, rules that were already broken are
+    /// ignored.
+    pub async fn validate_code(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        let others: Vec<PolicyRule> = PolicyRule::load_all(tx)
+            .await?
+            .into_iter()
+            .filter(|r| r.uuid != self.uuid)
+            .collect();
+        let mut codes: Vec<&str> = others.iter().map(|r| r.code.as_str()).collect();
+        let broken: Vec<usize> = PolicyEnforcer::try_compile(&codes)?
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+
+        codes.insert(0, &self.code);
+        let mut errs = vec![];
+        for (i, err) in PolicyEnforcer::try_compile(&codes)? {
+            let msg = err.to_string();
+            if i == 0 {
+                errs.push(InvalidValue::BadSyntax(
+                    "policy_rule.code",
+                    polar_error_position(&self.code, &msg),
+                    msg,
+                ));
+            } else if !broken.contains(&(i - 1)) {
+                let other = &others[i - 1];
+                errs.push(InvalidValue::BadSyntax(
+                    "policy_rule.code",
+                    0,
+                    format!("breaks rule {} - {}: {}", other.uuid, other.title, msg),
+                ));
+            }
+        }
+        if errs.len() != 0 {
+            return Err(FError::new(ValidationError(errs)));
+        }
+        Ok(())
+    }
+
     #[allow(unused)]
     pub async fn save(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
         trace!("Saving PolicyRule {:?}", self.uuid);
 
         self.validate_as_err()?;
+        self.validate_code(tx).await?;
 
         match self._revision {
             0 => self.db_insert(tx).await?,
@@ -127,17 +239,35 @@ impl PolicyRule {
     }
 
     async fn db_update(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        let previous = self._revision;
         self._revision += 1;
-        sqlx::query!(
-            "UPDATE `policy_rule` SET `_revision` = ?, `title` = ?, `desc` = ?, `code` = ? WHERE `uuid` = ?",
+        let ans = sqlx::query!(
+            "UPDATE `policy_rule` SET `_revision` = ?, `title` = ?, `desc` = ?, `code` = ? WHERE `uuid` = ? AND `_revision` = ?",
             self._revision,
             self.title,
             self.desc,
             self.code,
-            self.uuid
+            self.uuid,
+            previous
         )
         .execute(&mut *tx)
         .await?;
+        if ans.rows_affected() == 0 {
+            return Err(FError::new(StaleRevision(self.uuid, previous)));
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polar_error_position() {
+        let code = "allow(a, b, c);\nallow(a b);";
+        assert_eq!(24, polar_error_position(code, "did not expect to find the token 'b' at line 2, column 9"));
+        assert_eq!(0, polar_error_position(code, "something else"));
+        assert_eq!(code.chars().count(), polar_error_position(code, "at line 9, column 1"));
+    }
+}
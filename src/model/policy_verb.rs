@@ -0,0 +1,65 @@
+use crate::model::prelude::*;
+
+/// A verb of the policy, `name` is the Polar constant rules use to refer to `verb`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PolicyVerb {
+    pub name: &'static str,
+    pub verb: &'static str,
+    pub desc: &'static str,
+}
+
+const fn verb(name: &'static str, verb: &'static str, desc: &'static str) -> PolicyVerb {
+    PolicyVerb { name, verb, desc }
+}
+
+/// Every verb checked by feroauth, all are registered with Oso under their name
+pub const POLICY_VERBS: &[PolicyVerb] = &[
+    verb("POLVERB_USER_ADD", POLVERB_USER_ADD, "Create a user"),
+    verb("POLVERB_USER_GET", POLVERB_USER_GET, "See a user"),
+    verb("POLVERB_USER_SAV", POLVERB_USER_SAV, "Save changes to a user"),
+    verb("POLVERB_USER_DEL", POLVERB_USER_DEL, "Delete a user"),
+    verb("POLVERB_USER_SET_SUPER", POLVERB_USER_SET_SUPER, "Make a user superuser or revoke it"),
+    verb("POLVERB_USER_NAME_SET", POLVERB_USER_NAME_SET, "Change the display name of a user"),
+    verb("POLVERB_USER_PASSWORD_GET", POLVERB_USER_PASSWORD_GET, "See the passwords of a user (not their hashes)"),
+    verb("POLVERB_USER_PASSWORD_SET", POLVERB_USER_PASSWORD_SET, "Change the passwords of a user"),
+    verb("POLVERB_USER_2FA_SET", POLVERB_USER_2FA_SET, "Change the second factors of a user"),
+    verb("POLVERB_USER_2FA_GET", POLVERB_USER_2FA_GET, "See the second factors of a user"),
+    verb("POLVERB_USER_GROUP_ADD", POLVERB_USER_GROUP_ADD, "Add a user to a group, the resource is the group"),
+    verb("POLVERB_USER_GROUP_DEL", POLVERB_USER_GROUP_DEL, "Remove a user from a group, the resource is the group"),
+    verb("POLVERB_USER_LOGIN_ADD", POLVERB_USER_LOGIN_ADD, "Add a login handle to a user"),
+    verb("POLVERB_USER_LOGIN_DEL", POLVERB_USER_LOGIN_DEL, "Remove a login handle from a user"),
+    verb("POLVERB_USER_SUPER_GET", POLVERB_USER_SUPER_GET, "See whether a user is superuser"),
+    verb("POLVERB_USER_LOGIN_GET", POLVERB_USER_LOGIN_GET, "See the login handles of a user"),
+    verb("POLVERB_USER_GROUP_GET", POLVERB_USER_GROUP_GET, "See the groups of a user"),
+    verb("POLVERB_USER_DATES_GET", POLVERB_USER_DATES_GET, "See when a user was added and last logged in"),
+    verb("POLVERB_GROUP_ADD", POLVERB_GROUP_ADD, "Create a group"),
+    verb("POLVERB_GROUP_GET", POLVERB_GROUP_GET, "See a group"),
+    verb("POLVERB_GROUP_NAME_SET", POLVERB_GROUP_NAME_SET, "Rename a group"),
+    verb("POLVERB_GROUP_DESC_SET", POLVERB_GROUP_DESC_SET, "Change the description of a group"),
+    verb("POLVERB_GROUP_DEL", POLVERB_GROUP_DEL, "Delete a group"),
+    verb("POLVERB_GROUP_MEMBER_ADD_ANYKIND", POLVERB_GROUP_MEMBER_ADD_ANYKIND, "Add any kind of member to a group"),
+    verb("POLVERB_GROUP_MEMBER_DEL_ANYKIND", POLVERB_GROUP_MEMBER_DEL_ANYKIND, "Remove any kind of member from a group"),
+    verb("POLVERB_APP_ADD", POLVERB_APP_ADD, "Register an app"),
+    verb("POLVERB_APP_GET", POLVERB_APP_GET, "See an app"),
+    verb("POLVERB_APP_SAV", POLVERB_APP_SAV, "Save changes to an app"),
+    verb("POLVERB_APP_DEL", POLVERB_APP_DEL, "Delete an app"),
+    verb("POLVERB_APP_SECRET_SET", POLVERB_APP_SECRET_SET, "Rotate the client secret of an app"),
+    verb("POLVERB_HTTP_ACCESS", POLVERB_HTTP_ACCESS, "Reach an upstream behind forward-auth or ext_authz"),
+    verb("POLVERB_POLICY_RULE_GET", POLVERB_POLICY_RULE_GET, "See policy rules and explanations of decisions"),
+    verb("POLVERB_POLICY_RULE_SET", POLVERB_POLICY_RULE_SET, "Create, change and delete policy rules"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_unique() {
+        let names: HashSet<&str> = POLICY_VERBS.iter().map(|v| v.name).collect();
+        let verbs: HashSet<&str> = POLICY_VERBS.iter().map(|v| v.verb).collect();
+        assert_eq!(POLICY_VERBS.len(), names.len());
+        assert_eq!(POLICY_VERBS.len(), verbs.len());
+        assert!(POLICY_VERBS.iter().all(|v| v.verb.starts_with("feroauth/")));
+    }
+}
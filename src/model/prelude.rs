@@ -25,6 +25,7 @@ pub use oso::errors::OsoError as OsoErrorReal;
 pub use oso::PolarClass;
 
 pub use argonautica::Error as ArgoErrorReal;
+pub use openssl::error::ErrorStack as CryptoErrorReal;
 
 pub use crate::model::*;
 
@@ -58,7 +59,10 @@ pub fn unwrap_or_log<T, E: std::fmt::Debug>(input: Result<T, E>, msg: &str) -> T
 #[derive(Debug, Serialize)]
 pub enum InvalidValue {
     OutOfRange(&'static str, usize, usize), // field name, min, max
-    MustNotNull(&'static str)
+    MustNotNull(&'static str),
+    BadFormat(&'static str), // field name
+    BadSyntax(&'static str, usize, String), // field name, position, message
+    MembershipCycle(&'static str, Uuid, Uuid), // field name, group, member that would contain the group
 }
 
 #[derive(Debug)]
@@ -79,18 +83,26 @@ pub enum FErrorInner {
     SQLError(SQLErrorReal),
     IOError(IOErrorReal),
     StaleSession(Uuid),
+    /// Object and revision the change was based on, someone else saved it in the meantime
+    StaleRevision(Uuid, i32),
     UuidParseError(String),
     ArgoError(ArgoErrorReal),
     PermissionError(String, String, String),
+    /// Actor, denied fields with the verb each one needs, resource
+    FieldPermissionError(String, Vec<(&'static str, &'static str)>, String),
     LockError,
     #[allow(unused)]
     FauxPanic(&'static str, Option<String>),
     OsoError(OsoErrorReal),
+    CryptoError(CryptoErrorReal),
+    /// Error code and description as defined in RFC 6749 section 5.2
+    OAuthError(&'static str, String),
 }
 
 pub use FErrorInner::{
-    ArgoError, FauxPanic, IOError, LockError, NotImplemented, OsoError, SQLError,
-    SerializationError, StaleSession, UuidParseError, ValidationError, PermissionError
+    ArgoError, CryptoError, FauxPanic, IOError, LockError, NotImplemented, OAuthError, OsoError, SQLError,
+    SerializationError, StaleRevision, StaleSession, UuidParseError, ValidationError, PermissionError,
+    FieldPermissionError
 };
 
 pub type Transaction<'a> = sqlx::Transaction<'a, sqlx::mysql::MySql>;
@@ -114,6 +126,20 @@ impl FError {
         FError::new(FErrorInner::PermissionError(format!("{:?}", actor), verb.to_string(), format!("{:?}", resource)))
     }
 
+    #[track_caller]
+    pub fn new_field_permission_error<T, U>(actor: T, denied: Vec<(&'static str, &'static str)>, resource: U) -> Self
+    where
+        T: fmt::Debug,
+        U: fmt::Debug,
+    {
+        FError::new(FErrorInner::FieldPermissionError(format!("{:?}", actor), denied, format!("{:?}", resource)))
+    }
+
+    #[track_caller]
+    pub fn new_oauth_error(code: &'static str, desc: &str) -> Self {
+        FError::new(FErrorInner::OAuthError(code, desc.to_string()))
+    }
+
     #[track_caller]
     #[allow(unused)]
     pub fn new_faux_panic_1(a: &'static str) -> Self {
@@ -133,6 +159,19 @@ impl FError {
         FError::new_faux_panic_2(a, Some(msg))
     }
 
+    /// Turns "not found" into a permission error, so that callers who may not see an object cannot tell whether it exists
+    #[track_caller]
+    pub fn hide_not_found<T, U>(self, actor: T, verb: &str, resource: U) -> Self
+    where
+        T: fmt::Debug,
+        U: fmt::Debug,
+    {
+        if self.is_not_found() {
+            return FError::new_permission_error(actor, verb, resource);
+        }
+        self
+    }
+
     pub fn is_not_found(&self) -> bool {
         match &self.inner {
             SQLError(SQLErrorReal::RowNotFound) => true,
@@ -151,8 +190,34 @@ impl FError {
         }
     }
 
+    pub fn is_permission(&self) -> bool {
+        match &self.inner {
+            PermissionError(_, _, _) => true,
+            FieldPermissionError(_, _, _) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        match &self.inner {
+            StaleRevision(_, _) => true,
+            _ => false,
+        }
+    }
+
     pub fn is_unauthorized(&self) -> bool {
-        return false;
+        match &self.inner {
+            OAuthError("invalid_client", _) => true,
+            OAuthError("invalid_token", _) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_oauth(&self) -> bool {
+        match &self.inner {
+            OAuthError(_, _) => true,
+            _ => false,
+        }
     }
 }
 
@@ -166,11 +231,15 @@ impl std::fmt::Display for FErrorInner {
             SQLError(_) => "SQL error",
             IOError(_) => "IO error",
             StaleSession(_) => "stale session error",
+            StaleRevision(_, _) => "stale revision error",
             UuidParseError(_) => "uuid parse error",
             ArgoError(_) => "argonautica error",
             FauxPanic(_, _) => "faux panic error",
             PermissionError(_, _, _) => "permission error",
+            FieldPermissionError(_, _, _) => "permission error",
             OsoError(_) => "Oso error",
+            CryptoError(_) => "crypto error",
+            OAuthError(_, _) => "OAuth error",
         };
         fmt.write_str(kind)
     }
@@ -183,6 +252,10 @@ impl fmt::Display for FError {
         } else if let ValidationError(errs) = &self.inner {
             let json = serde_json::to_string(&errs).unwrap_or("validation error".to_string());
             fmt.write_str(&json)
+        } else if let FieldPermissionError(_, denied, _) = &self.inner {
+            // Only the fields and verbs, actor and resource are for the logs
+            let json = serde_json::to_string(&denied).unwrap_or("permission error".to_string());
+            fmt.write_str(&json)
         } else {
             fmt.write_fmt(format_args!(
                 "{} at {}:{}:{}",
@@ -198,12 +271,27 @@ impl actix_web::error::ResponseError for FError {
             actix_web::http::StatusCode::NOT_FOUND
         } else if self.is_unauthorized() {
             actix_web::http::StatusCode::UNAUTHORIZED
-        } else if self.is_validation() {
+        } else if self.is_permission() {
+            actix_web::http::StatusCode::FORBIDDEN
+        } else if self.is_conflict() {
+            actix_web::http::StatusCode::CONFLICT
+        } else if self.is_validation() || self.is_oauth() {
             actix_web::http::StatusCode::BAD_REQUEST
         } else {
             actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = HttpResponse::build(self.status_code());
+        if let OAuthError(code, desc) = &self.inner {
+            return resp
+                .header(httpHeader::CACHE_CONTROL, "no-store")
+                .json(serde_json::json!({"error": code, "error_description": desc}));
+        }
+        resp.content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
 }
 
 impl std::convert::From<IOErrorReal> for FError {
@@ -234,6 +322,13 @@ impl std::convert::From<OsoErrorReal> for FError {
     }
 }
 
+impl std::convert::From<CryptoErrorReal> for FError {
+    #[track_caller]
+    fn from(err: CryptoErrorReal) -> Self {
+        FError::new(CryptoError(err))
+    }
+}
+
 impl<Guard> std::convert::From<TryLockError<Guard>> for FError {
     #[track_caller]
     fn from(_: TryLockError<Guard>) -> Self {
@@ -258,16 +353,86 @@ pub fn parse_uuid_str(val: &str) -> FResult<Uuid> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Placeholders for an `IN (...)` list of `count` values, e.g. `?, ?, ?`
+pub fn sql_placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
+/// Selects the uuid, kind and display name (for users, groups and apps) of objects aliased as `O`
+const MIN_OBJECT_SELECT: &'static str = "SELECT O.`uuid`, O.`type`, COALESCE(U.`display_name`, G.`name`, A.`name`) \
+    FROM `object_type` O \
+    LEFT JOIN `user` U ON U.`uuid` = O.`uuid` \
+    LEFT JOIN `group` G ON G.`uuid` = O.`uuid` \
+    LEFT JOIN `app` A ON A.`uuid` = O.`uuid`";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, PolarClass)]
 pub struct MinObject {
+    #[polar(attribute)]
     uuid: Uuid,
+    #[polar(attribute)]
     kind: String,
+    /// Display name of users, groups and apps
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
 }
 
 impl MinObject {
+    pub fn new(uuid: Uuid, kind: &str, name: Option<String>) -> Self {
+        MinObject {
+            uuid,
+            kind: kind.to_string(),
+            name,
+        }
+    }
+
     pub fn get_uuid(&self) -> Uuid {
         return self.uuid;
     }
+
+    pub fn get_kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Drops the display name, for callers who may not see the object itself
+    pub fn hide_name(&mut self) {
+        self.name = None;
+    }
+
+    /// Finds the kind of any object from `object_type` (e.g. `USER`, `GROUP`, `APP`)
+    pub async fn load_by_uuid(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<MinObject> {
+        let sql_query = format!("{} WHERE O.`uuid` = ?", MIN_OBJECT_SELECT);
+        let row: (Uuid, String, Option<String>) = sqlx::query_as(&sql_query)
+            .bind(uuid)
+            .fetch_one(&mut *tx)
+            .await?;
+        Ok(MinObject::new(row.0, &row.1, row.2))
+    }
+
+    /// Loads the objects that exist among `uuids`, in the same order
+    pub async fn load_many(uuids: &[Uuid], tx: &mut Transaction<'_>) -> FResult<Vec<MinObject>> {
+        if uuids.is_empty() {
+            return Ok(vec![]);
+        }
+        let sql_query = format!(
+            "{} WHERE O.`uuid` IN ({})",
+            MIN_OBJECT_SELECT,
+            sql_placeholders(uuids.len())
+        );
+        let mut query = sqlx::query_as(&sql_query);
+        for uuid in uuids {
+            query = query.bind(uuid);
+        }
+        let rows: Vec<(Uuid, String, Option<String>)> = query.fetch_all(&mut *tx).await?;
+        let mut found: std::collections::HashMap<Uuid, MinObject> = rows
+            .into_iter()
+            .map(|row| (row.0, MinObject::new(row.0, &row.1, row.2)))
+            .collect();
+        Ok(uuids.iter().filter_map(|uuid| found.remove(uuid)).collect())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -0,0 +1,578 @@
+use crate::model::prelude::*;
+use chrono::Duration;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use openssl::x509::{X509Name, X509};
+
+pub const SAML_RSA_BITS: u32 = 2048;
+/// The IdP certificate is pinned by the SPs, so it is long lived and never rotated automatically
+pub const SAML_CERT_LIFE: u32 = 10 * 365; // 10 years, in days
+/// How long an assertion may be presented to the SP
+pub const SAML_ASSERTION_LIFE: i64 = 5 * 60; // 5 min
+
+pub const SAML_NS_ASSERTION: &'static str = "urn:oasis:names:tc:SAML:2.0:assertion";
+pub const SAML_NS_PROTOCOL: &'static str = "urn:oasis:names:tc:SAML:2.0:protocol";
+pub const SAML_NS_METADATA: &'static str = "urn:oasis:names:tc:SAML:2.0:metadata";
+pub const SAML_NS_DSIG: &'static str = "http://www.w3.org/2000/09/xmldsig#";
+pub const SAML_BINDING_REDIRECT: &'static str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect";
+pub const SAML_BINDING_POST: &'static str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+const SAML_STATUS_SUCCESS: &'static str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const SAML_ATTRNAME_BASIC: &'static str = "urn:oasis:names:tc:SAML:2.0:attrname-format:basic";
+const SAML_CM_BEARER: &'static str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const SAML_AC_PASSWORD: &'static str = "urn:oasis:names:tc:SAML:2.0:ac:classes:PasswordProtectedTransport";
+const DSIG_EXC_C14N: &'static str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const DSIG_ENVELOPED: &'static str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const DSIG_RSA_SHA256: &'static str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const DSIG_SHA256: &'static str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SamlNameIdFormat {
+    /// The user UUID
+    Persistent,
+    /// See [`User::primary_email`]
+    Email,
+}
+
+impl SamlNameIdFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SamlNameIdFormat::Persistent => "PERSISTENT",
+            SamlNameIdFormat::Email => "EMAIL",
+        }
+    }
+
+    pub fn as_urn(&self) -> &'static str {
+        match self {
+            SamlNameIdFormat::Persistent => "urn:oasis:names:tc:SAML:2.0:nameid-format:persistent",
+            SamlNameIdFormat::Email => "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress",
+        }
+    }
+
+    #[track_caller]
+    pub fn parse(val: &str) -> FResult<Self> {
+        match val {
+            "PERSISTENT" => Ok(SamlNameIdFormat::Persistent),
+            "EMAIL" => Ok(SamlNameIdFormat::Email),
+            _ => Err(FError::new_faux_panic_3("unknown SAML NameID format", val)),
+        }
+    }
+
+    /// Value of the NameID for the user, `None` if the user has no suitable handle
+    pub fn name_id_for(&self, user: &User) -> Option<String> {
+        match self {
+            SamlNameIdFormat::Persistent => Some(user.get_uuid().to_string()),
+            SamlNameIdFormat::Email => user.primary_email().map(|v| v.to_string()),
+        }
+    }
+}
+
+/// SAML service provider registered for an [`App`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamlSp {
+    pub app_uuid: Uuid,
+    pub entity_id: String,
+    /// Assertion consumer service (HTTP-POST binding), assertions are only ever sent here
+    pub acs_url: String,
+    pub name_id_format: SamlNameIdFormat,
+    /// The metadata as uploaded
+    #[serde(skip)]
+    pub metadata: String,
+}
+
+/// The parts of an `AuthnRequest` the IdP cares about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamlAuthnRequest {
+    pub id: String,
+    pub issuer: String,
+    pub acs_url: Option<String>,
+}
+
+#[track_caller]
+fn bad_xml(field: &'static str, err: roxmltree::Error) -> FError {
+    FError::new(ValidationError(vec![InvalidValue::BadSyntax(field, 0, err.to_string())]))
+}
+
+#[track_caller]
+fn bad_format(field: &'static str) -> FError {
+    FError::new(ValidationError(vec![InvalidValue::BadFormat(field)]))
+}
+
+impl SamlSp {
+    /// Reads the entity ID, the HTTP-POST assertion consumer service and the preferred NameID format from SP metadata
+    pub fn from_metadata(app_uuid: Uuid, metadata: &str) -> FResult<SamlSp> {
+        let doc = roxmltree::Document::parse(metadata).map_err(|err| bad_xml("saml.metadata", err))?;
+        let entity = doc
+            .descendants()
+            .find(|n| n.has_tag_name((SAML_NS_METADATA, "EntityDescriptor")))
+            .ok_or_else(|| bad_format("saml.metadata"))?;
+        let entity_id = entity.attribute("entityID").ok_or_else(|| bad_format("saml.entity_id"))?;
+        let sp = entity
+            .descendants()
+            .find(|n| n.has_tag_name((SAML_NS_METADATA, "SPSSODescriptor")))
+            .ok_or_else(|| bad_format("saml.metadata"))?;
+
+        let mut services: Vec<_> = sp
+            .children()
+            .filter(|n| n.has_tag_name((SAML_NS_METADATA, "AssertionConsumerService")))
+            .filter(|n| n.attribute("Binding") == Some(SAML_BINDING_POST))
+            .collect();
+        // The default service wins, then the one with the lowest index
+        services.sort_by_key(|n| {
+            (
+                n.attribute("isDefault") != Some("true"),
+                n.attribute("index").and_then(|v| v.parse::<u32>().ok()).unwrap_or(u32::MAX),
+            )
+        });
+        let acs_url = services
+            .first()
+            .and_then(|n| n.attribute("Location"))
+            .ok_or_else(|| bad_format("saml.acs_url"))?;
+
+        let wants_email = sp
+            .children()
+            .filter(|n| n.has_tag_name((SAML_NS_METADATA, "NameIDFormat")))
+            .filter_map(|n| n.text())
+            .any(|v| v.trim() == SamlNameIdFormat::Email.as_urn());
+
+        Ok(SamlSp {
+            app_uuid,
+            entity_id: entity_id.to_string(),
+            acs_url: acs_url.to_string(),
+            name_id_format: match wants_email {
+                true => SamlNameIdFormat::Email,
+                false => SamlNameIdFormat::Persistent,
+            },
+            metadata: metadata.to_string(),
+        })
+    }
+
+    pub fn validate(&self) -> Vec<InvalidValue> {
+        let mut ans = vec![];
+        if self.entity_id.len() == 0 {
+            ans.push(InvalidValue::MustNotNull("saml.entity_id"))
+        }
+        match url::Url::parse(&self.acs_url) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+            _ => ans.push(InvalidValue::BadFormat("saml.acs_url")),
+        }
+        ans
+    }
+
+    pub fn validate_as_err(&self) -> FResult<()> {
+        let errs = self.validate();
+        if errs.len() != 0 {
+            return Err(FError::new(ValidationError(errs)));
+        }
+        Ok(())
+    }
+
+    pub async fn load_by_app(app_uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<SamlSp> {
+        let row = sqlx::query!(
+            "SELECT `app_uuid`, `entity_id`, `acs_url`, `name_id_format`, `metadata` FROM `saml_sp` WHERE `app_uuid` = ?",
+            app_uuid
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(SamlSp {
+            app_uuid: parse_uuid_vec(row.app_uuid)?,
+            entity_id: row.entity_id,
+            acs_url: row.acs_url,
+            name_id_format: SamlNameIdFormat::parse(&row.name_id_format)?,
+            metadata: row.metadata,
+        })
+    }
+
+    pub async fn load_by_entity_id(entity_id: &str, tx: &mut Transaction<'_>) -> FResult<SamlSp> {
+        let row = sqlx::query!(
+            "SELECT `app_uuid`, `entity_id`, `acs_url`, `name_id_format`, `metadata` FROM `saml_sp` WHERE `entity_id` = ?",
+            entity_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(SamlSp {
+            app_uuid: parse_uuid_vec(row.app_uuid)?,
+            entity_id: row.entity_id,
+            acs_url: row.acs_url,
+            name_id_format: SamlNameIdFormat::parse(&row.name_id_format)?,
+            metadata: row.metadata,
+        })
+    }
+
+    /// Inserts or replaces the registration of the app
+    pub async fn save(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving SAML SP {:?} for app {:?}", self.entity_id, self.app_uuid);
+        self.validate_as_err()?;
+        SamlSp::delete(self.app_uuid, tx).await?;
+        sqlx::query!(
+            "INSERT INTO `saml_sp` (`app_uuid`, `entity_id`, `acs_url`, `name_id_format`, `metadata`) VALUES (?, ?, ?, ?, ?)",
+            self.app_uuid,
+            self.entity_id,
+            self.acs_url,
+            self.name_id_format.as_str(),
+            self.metadata
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete(app_uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!("DELETE FROM `saml_sp` WHERE `app_uuid` = ?", app_uuid)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+}
+
+impl SamlAuthnRequest {
+    /// Parses the XML of an `AuthnRequest` (already base64 decoded and inflated)
+    pub fn parse(xml: &str) -> FResult<SamlAuthnRequest> {
+        let doc = roxmltree::Document::parse(xml).map_err(|err| bad_xml("saml.request", err))?;
+        let root = doc.root_element();
+        if !root.has_tag_name((SAML_NS_PROTOCOL, "AuthnRequest")) {
+            return Err(bad_format("saml.request"));
+        }
+        let id = root.attribute("ID").ok_or_else(|| bad_format("saml.request.id"))?;
+        let issuer = root
+            .children()
+            .find(|n| n.has_tag_name((SAML_NS_ASSERTION, "Issuer")))
+            .and_then(|n| n.text())
+            .ok_or_else(|| bad_format("saml.request.issuer"))?;
+        Ok(SamlAuthnRequest {
+            id: id.to_string(),
+            issuer: issuer.trim().to_string(),
+            acs_url: root.attribute("AssertionConsumerServiceURL").map(|v| v.to_string()),
+        })
+    }
+
+    /// Decodes a `SAMLRequest` parameter, inflating it when it came through the Redirect binding
+    pub fn decode(saml_request: &str, deflated: bool) -> FResult<SamlAuthnRequest> {
+        use std::io::Read;
+
+        let raw = base64::decode(saml_request.trim()).map_err(|_| bad_format("saml.request"))?;
+        let xml = match deflated {
+            true => {
+                let mut xml = String::new();
+                flate2::read::DeflateDecoder::new(&raw[..])
+                    .take(1 << 20)
+                    .read_to_string(&mut xml)
+                    .map_err(|_| bad_format("saml.request"))?;
+                xml
+            }
+            false => String::from_utf8(raw).map_err(|_| bad_format("saml.request"))?,
+        };
+        SamlAuthnRequest::parse(&xml)
+    }
+}
+
+/// Escapes text the way exclusive XML canonicalization outputs it
+pub fn xml_escape_text(val: &str) -> String {
+    let mut ans = String::with_capacity(val.len());
+    for c in val.chars() {
+        match c {
+            '&' => ans.push_str("&amp;"),
+            '<' => ans.push_str("&lt;"),
+            '>' => ans.push_str("&gt;"),
+            '\r' => ans.push_str("&#xD;"),
+            _ => ans.push(c),
+        }
+    }
+    ans
+}
+
+/// Escapes attribute values the way exclusive XML canonicalization outputs them
+pub fn xml_escape_attr(val: &str) -> String {
+    let mut ans = String::with_capacity(val.len());
+    for c in val.chars() {
+        match c {
+            '&' => ans.push_str("&amp;"),
+            '<' => ans.push_str("&lt;"),
+            '"' => ans.push_str("&quot;"),
+            '\t' => ans.push_str("&#x9;"),
+            '\n' => ans.push_str("&#xA;"),
+            '\r' => ans.push_str("&#xD;"),
+            _ => ans.push(c),
+        }
+    }
+    ans
+}
+
+fn saml_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// SAML IDs must not start with a digit
+fn saml_id_for(uuid: Uuid) -> String {
+    format!("_{}", uuid.to_simple())
+}
+
+fn saml_id() -> String {
+    saml_id_for(Uuid::new_v4())
+}
+
+/// Key and self-signed certificate used to sign assertions
+pub struct SamlKey {
+    uuid: Uuid,
+    key: PKey<Private>,
+    cert: X509,
+    pub created_at: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for SamlKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SamlKey")
+            .field("uuid", &self.uuid)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+impl SamlKey {
+    pub fn generate(common_name: &str) -> FResult<SamlKey> {
+        let key = PKey::from_rsa(Rsa::generate(SAML_RSA_BITS)?)?;
+
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_text("CN", common_name)?;
+        let name = name.build();
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial.to_asn1_integer()?)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_not_before(&Asn1Time::days_from_now(0)?)?;
+        builder.set_not_after(&Asn1Time::days_from_now(SAML_CERT_LIFE)?)?;
+        builder.set_pubkey(&key)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+
+        Ok(SamlKey {
+            uuid: Uuid::new_v4(),
+            key,
+            cert: builder.build(),
+            created_at: Utc::now(),
+        })
+    }
+
+    pub async fn save(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving SAML key {:?}", self.uuid);
+        let key_pem = self.key.private_key_to_pem_pkcs8()?;
+        let cert_pem = self.cert.to_pem()?;
+        sqlx::query!(
+            "INSERT INTO `saml_key` (`uuid`, `private_key`, `certificate`, `created_at`) VALUES (?, ?, ?, ?)",
+            self.uuid,
+            key_pem,
+            cert_pem,
+            self.created_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the newest key, generating one (named after `common_name`) if there is none yet
+    pub async fn get_or_create(common_name: &str, tx: &mut Transaction<'_>) -> FResult<SamlKey> {
+        let row = sqlx::query!(
+            "SELECT `uuid`, `private_key`, `certificate`, `created_at` FROM `saml_key` ORDER BY `created_at` DESC LIMIT 1 FOR UPDATE"
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(row) = row {
+            return Ok(SamlKey {
+                uuid: parse_uuid_vec(row.uuid)?,
+                key: PKey::private_key_from_pem(&row.private_key)?,
+                cert: X509::from_pem(&row.certificate)?,
+                created_at: Utc.from_utc_datetime(&row.created_at),
+            });
+        }
+
+        info!("Generating SAML signing key");
+        let ans = SamlKey::generate(common_name)?;
+        ans.save(tx).await?;
+        Ok(ans)
+    }
+
+    /// Base64 DER of the certificate, as used in `X509Certificate` elements
+    pub fn cert_base64(&self) -> FResult<String> {
+        Ok(base64::encode(self.cert.to_der()?))
+    }
+
+    /// Builds the enveloped signature of the element with ID `id` whose canonical form is `canonical`
+    fn sign_element(&self, id: &str, canonical: &str) -> FResult<String> {
+        let digest = base64::encode(hash(MessageDigest::sha256(), canonical.as_bytes())?);
+        let signed_info = format!(
+            r##"<ds:CanonicalizationMethod Algorithm="{c14n}"></ds:CanonicalizationMethod><ds:SignatureMethod Algorithm="{rsa}"></ds:SignatureMethod><ds:Reference URI="#{id}"><ds:Transforms><ds:Transform Algorithm="{env}"></ds:Transform><ds:Transform Algorithm="{c14n}"></ds:Transform></ds:Transforms><ds:DigestMethod Algorithm="{sha}"></ds:DigestMethod><ds:DigestValue>{digest}</ds:DigestValue></ds:Reference>"##,
+            c14n = DSIG_EXC_C14N,
+            rsa = DSIG_RSA_SHA256,
+            env = DSIG_ENVELOPED,
+            sha = DSIG_SHA256,
+            id = id,
+            digest = digest,
+        );
+        // When canonicalized on its own SignedInfo carries the namespace declared by Signature
+        let canonical_signed_info = format!(r#"<ds:SignedInfo xmlns:ds="{}">{}</ds:SignedInfo>"#, SAML_NS_DSIG, signed_info);
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(canonical_signed_info.as_bytes())?;
+        let signature = base64::encode(signer.sign_to_vec()?);
+
+        Ok(format!(
+            r#"<ds:Signature xmlns:ds="{ns}"><ds:SignedInfo>{signed_info}</ds:SignedInfo><ds:SignatureValue>{signature}</ds:SignatureValue><ds:KeyInfo><ds:X509Data><ds:X509Certificate>{cert}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></ds:Signature>"#,
+            ns = SAML_NS_DSIG,
+            signed_info = signed_info,
+            signature = signature,
+            cert = self.cert_base64()?,
+        ))
+    }
+}
+
+/// Everything needed to build a `Response` for an SP
+#[derive(Debug)]
+pub struct SamlAssertionParams<'a> {
+    pub idp_entity_id: &'a str,
+    pub sp: &'a SamlSp,
+    pub in_response_to: &'a str,
+    pub name_id: &'a str,
+    pub session_index: Uuid,
+    pub authn_instant: DateTime<Utc>,
+    pub attributes: Vec<(&'static str, Vec<String>)>,
+}
+
+/// Builds a `Response` with a signed assertion.
+///
+/// The assertion is written directly in exclusive canonical form (sorted attributes, no self-closing tags and no whitespace between elements) so it can be digested as is.
+pub fn build_saml_response(key: &SamlKey, params: &SamlAssertionParams<'_>) -> FResult<String> {
+    let now = Utc::now();
+    let not_after = saml_time(now + Duration::seconds(SAML_ASSERTION_LIFE));
+    let assertion_id = saml_id();
+
+    let mut attributes = String::new();
+    for (name, values) in &params.attributes {
+        attributes.push_str(&format!(r#"<saml:Attribute Name="{}" NameFormat="{}">"#, name, SAML_ATTRNAME_BASIC));
+        for value in values {
+            attributes.push_str(&format!("<saml:AttributeValue>{}</saml:AttributeValue>", xml_escape_text(value)));
+        }
+        attributes.push_str("</saml:Attribute>");
+    }
+
+    let issuer = format!("<saml:Issuer>{}</saml:Issuer>", xml_escape_text(params.idp_entity_id));
+    let body = format!(
+        concat!(
+            r#"<saml:Subject><saml:NameID Format="{format}">{name_id}</saml:NameID><saml:SubjectConfirmation Method="{bearer}"><saml:SubjectConfirmationData InResponseTo="{in_response_to}" NotOnOrAfter="{not_after}" Recipient="{acs}"></saml:SubjectConfirmationData></saml:SubjectConfirmation></saml:Subject>"#,
+            r#"<saml:Conditions NotBefore="{now}" NotOnOrAfter="{not_after}"><saml:AudienceRestriction><saml:Audience>{audience}</saml:Audience></saml:AudienceRestriction></saml:Conditions>"#,
+            r#"<saml:AuthnStatement AuthnInstant="{authn_instant}" SessionIndex="{session_index}"><saml:AuthnContext><saml:AuthnContextClassRef>{ac}</saml:AuthnContextClassRef></saml:AuthnContext></saml:AuthnStatement>"#,
+            r#"<saml:AttributeStatement>{attributes}</saml:AttributeStatement>"#,
+        ),
+        format = params.sp.name_id_format.as_urn(),
+        name_id = xml_escape_text(params.name_id),
+        bearer = SAML_CM_BEARER,
+        in_response_to = xml_escape_attr(params.in_response_to),
+        not_after = not_after,
+        acs = xml_escape_attr(&params.sp.acs_url),
+        now = saml_time(now),
+        audience = xml_escape_text(&params.sp.entity_id),
+        authn_instant = saml_time(params.authn_instant),
+        session_index = saml_id_for(params.session_index),
+        ac = SAML_AC_PASSWORD,
+        attributes = attributes,
+    );
+    let assertion_open = format!(
+        r#"<saml:Assertion xmlns:saml="{ns}" ID="{id}" IssueInstant="{now}" Version="2.0">"#,
+        ns = SAML_NS_ASSERTION,
+        id = assertion_id,
+        now = saml_time(now),
+    );
+
+    // The enveloped-signature transform removes the signature before digesting
+    let canonical = format!("{}{}{}</saml:Assertion>", assertion_open, issuer, body);
+    let signature = key.sign_element(&assertion_id, &canonical)?;
+    let assertion = format!("{}{}{}{}</saml:Assertion>", assertion_open, issuer, signature, body);
+
+    Ok(format!(
+        r#"<samlp:Response xmlns:saml="{ns}" xmlns:samlp="{nsp}" Destination="{acs}" ID="{id}" InResponseTo="{in_response_to}" IssueInstant="{now}" Version="2.0">{issuer}<samlp:Status><samlp:StatusCode Value="{success}"></samlp:StatusCode></samlp:Status>{assertion}</samlp:Response>"#,
+        ns = SAML_NS_ASSERTION,
+        nsp = SAML_NS_PROTOCOL,
+        acs = xml_escape_attr(&params.sp.acs_url),
+        id = saml_id(),
+        in_response_to = xml_escape_attr(params.in_response_to),
+        now = saml_time(now),
+        issuer = issuer,
+        success = SAML_STATUS_SUCCESS,
+        assertion = assertion,
+    ))
+}
+
+/// IdP metadata advertising the Redirect and POST bindings of `sso_url`
+pub fn build_idp_metadata(key: &SamlKey, entity_id: &str, sso_url: &str) -> FResult<String> {
+    Ok(format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<md:EntityDescriptor xmlns:md="{md}" entityID="{entity_id}">"#,
+            r#"<md:IDPSSODescriptor WantAuthnRequestsSigned="false" protocolSupportEnumeration="{nsp}">"#,
+            r#"<md:KeyDescriptor use="signing"><ds:KeyInfo xmlns:ds="{ds}"><ds:X509Data><ds:X509Certificate>{cert}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></md:KeyDescriptor>"#,
+            r#"<md:NameIDFormat>{persistent}</md:NameIDFormat>"#,
+            r#"<md:NameIDFormat>{email}</md:NameIDFormat>"#,
+            r#"<md:SingleSignOnService Binding="{redirect}" Location="{sso}"/>"#,
+            r#"<md:SingleSignOnService Binding="{post}" Location="{sso}"/>"#,
+            r#"</md:IDPSSODescriptor>"#,
+            r#"</md:EntityDescriptor>"#,
+        ),
+        md = SAML_NS_METADATA,
+        entity_id = xml_escape_attr(entity_id),
+        nsp = SAML_NS_PROTOCOL,
+        ds = SAML_NS_DSIG,
+        cert = key.cert_base64()?,
+        persistent = SamlNameIdFormat::Persistent.as_urn(),
+        email = SamlNameIdFormat::Email.as_urn(),
+        redirect = SAML_BINDING_REDIRECT,
+        post = SAML_BINDING_POST,
+        sso = xml_escape_attr(sso_url),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SP_METADATA: &'static str = r#"<?xml version="1.0"?>
+<md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata" entityID="https://sp.example.com/saml">
+  <md:SPSSODescriptor protocolSupportEnumeration="urn:oasis:names:tc:SAML:2.0:protocol">
+    <md:NameIDFormat>urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress</md:NameIDFormat>
+    <md:AssertionConsumerService Binding="urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Artifact" Location="https://sp.example.com/artifact" index="0"/>
+    <md:AssertionConsumerService Binding="urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST" Location="https://sp.example.com/acs2" index="2"/>
+    <md:AssertionConsumerService Binding="urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST" Location="https://sp.example.com/acs1" index="1"/>
+  </md:SPSSODescriptor>
+</md:EntityDescriptor>"#;
+
+    #[test]
+    fn test_sp_metadata() {
+        let sp = SamlSp::from_metadata(Uuid::nil(), SP_METADATA).unwrap();
+        assert_eq!("https://sp.example.com/saml", sp.entity_id);
+        assert_eq!("https://sp.example.com/acs1", sp.acs_url);
+        assert_eq!(SamlNameIdFormat::Email, sp.name_id_format);
+
+        assert!(SamlSp::from_metadata(Uuid::nil(), "<foo>").is_err());
+    }
+
+    #[test]
+    fn test_authn_request() {
+        let xml = r#"<samlp:AuthnRequest xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_abc" Version="2.0" AssertionConsumerServiceURL="https://sp.example.com/acs1"><saml:Issuer> https://sp.example.com/saml </saml:Issuer></samlp:AuthnRequest>"#;
+        let req = SamlAuthnRequest::decode(&base64::encode(xml), false).unwrap();
+        assert_eq!("_abc", req.id);
+        assert_eq!("https://sp.example.com/saml", req.issuer);
+        assert_eq!(Some("https://sp.example.com/acs1".to_string()), req.acs_url);
+    }
+
+    #[test]
+    fn test_xml_escape() {
+        assert_eq!("a&amp;b&lt;c&gt;\"\n", xml_escape_text("a&b<c>\"\n"));
+        assert_eq!("a&amp;b&lt;c>&quot;&#xA;", xml_escape_attr("a&b<c>\"\n"));
+    }
+}
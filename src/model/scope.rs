@@ -0,0 +1,307 @@
+use crate::model::prelude::*;
+
+pub const MAX_SCOPE_NAME_LEN: usize = 190;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    uuid: Uuid,
+    _revision: i32,
+    /// Human readable name, see [`Scope::oauth_name`] for the name used in OAuth requests
+    pub name: String,
+    pub desc: String,
+    /// List of claim selectors (e.g. `user.login_handle[kind='EMAIL']`)
+    pub keys: String,
+}
+
+impl Scope {
+    pub fn new(uuid: Uuid, name: &str, desc: &str, keys: &str) -> Self {
+        Scope {
+            uuid,
+            _revision: 0,
+            name: name.to_string(),
+            desc: desc.to_string(),
+            keys: keys.to_string(),
+        }
+    }
+
+    #[inline]
+    pub fn get_uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Name used in the space separated `scope` parameter of OAuth requests.
+    ///
+    /// It is the lowercase [`Scope::name`] with whitespace replaced by underscores (e.g. `Email Address` becomes `email_address`).
+    pub fn oauth_name(&self) -> String {
+        self.name
+            .trim()
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+
+    pub async fn load_all(tx: &mut Transaction<'_>) -> FResult<Vec<Scope>> {
+        let rows = sqlx::query!(
+            "SELECT `uuid`, `_revision`, `name`, `desc`, `keys` FROM `scope` ORDER BY `name` ASC"
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut ans = Vec::new();
+        for row in rows {
+            ans.push(Scope {
+                uuid: parse_uuid_vec(row.uuid)?,
+                _revision: row._revision,
+                name: row.name,
+                desc: row.desc,
+                keys: row.keys,
+            })
+        }
+        Ok(ans)
+    }
+
+    /// Resolves a space separated list of [`Scope::oauth_name`]. Unknown names are returned in the second element.
+    pub async fn load_by_oauth_names(
+        names: &str,
+        tx: &mut Transaction<'_>,
+    ) -> FResult<(Vec<Scope>, Vec<String>)> {
+        let all = Scope::load_all(tx).await?;
+        let mut found = Vec::new();
+        let mut unknown = Vec::new();
+        for name in names.split_whitespace() {
+            match all.iter().find(|s| s.oauth_name() == name) {
+                Some(scope) => {
+                    if !found.contains(scope) {
+                        found.push(scope.clone())
+                    }
+                }
+                None => unknown.push(name.to_string()),
+            }
+        }
+        Ok((found, unknown))
+    }
+
+    /// Loads the scopes the user has already consented to share with the app
+    pub async fn load_granted(
+        app_uuid: Uuid,
+        user_uuid: Uuid,
+        tx: &mut Transaction<'_>,
+    ) -> FResult<Vec<Scope>> {
+        let rows = sqlx::query!(
+            "SELECT `scope`.`uuid`, `scope`.`_revision`, `scope`.`name`, `scope`.`desc`, `scope`.`keys` FROM `scope` JOIN `app_user_scope` ON (`app_user_scope`.`scope_uuid` = `scope`.`uuid`) WHERE `app_user_scope`.`app_uuid` = ? AND `app_user_scope`.`user_uuid` = ? ORDER BY `scope`.`name` ASC",
+            app_uuid,
+            user_uuid
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut ans = Vec::new();
+        for row in rows {
+            ans.push(Scope {
+                uuid: parse_uuid_vec(row.uuid)?,
+                _revision: row._revision,
+                name: row.name,
+                desc: row.desc,
+                keys: row.keys,
+            })
+        }
+        Ok(ans)
+    }
+
+    /// Records the user's consent to share the scopes with the app
+    pub async fn grant(
+        app_uuid: Uuid,
+        user_uuid: Uuid,
+        scopes: &[Scope],
+        tx: &mut Transaction<'_>,
+    ) -> FResult<()> {
+        for scope in scopes {
+            sqlx::query!(
+                "INSERT IGNORE INTO `app_user_scope` (`app_uuid`, `user_uuid`, `scope_uuid`) VALUES (?, ?, ?)",
+                app_uuid,
+                user_uuid,
+                scope.uuid
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Parses [`Scope::keys`], a list of quoted strings such as `['user.uuid', 'user.display_name']`
+    pub fn key_list(&self) -> FResult<Vec<String>> {
+        match parse_key_list(&self.keys) {
+            Some(v) => Ok(v),
+            None => Err(FError::new(ValidationError(vec![InvalidValue::BadFormat(
+                "scope.keys",
+            )]))),
+        }
+    }
+
+    /// Parses every key in [`Scope::keys`] as a [`Selector`]
+    pub fn selectors(&self) -> FResult<Vec<Selector>> {
+        let mut ans = Vec::new();
+        for key in self.key_list()? {
+            match Selector::parse(&key) {
+                Ok(v) => ans.push(v),
+                Err(err) => {
+                    return Err(FError::new(ValidationError(vec![InvalidValue::BadSyntax(
+                        "scope.keys",
+                        err.pos,
+                        format!("{}: {}", key, err.msg),
+                    )])))
+                }
+            }
+        }
+        Ok(ans)
+    }
+
+    pub fn validate(&self) -> Vec<InvalidValue> {
+        let len = self.name.chars().count();
+        let mut ans = vec![];
+        if !(MIN_NON_EMPTY_STR < len && len <= MAX_SCOPE_NAME_LEN) {
+            ans.push(InvalidValue::OutOfRange(
+                "scope.name",
+                MIN_NON_EMPTY_STR,
+                MAX_SCOPE_NAME_LEN,
+            ))
+        }
+        match self.key_list() {
+            Ok(keys) => {
+                for key in keys {
+                    if let Err(err) = Selector::parse(&key) {
+                        ans.push(InvalidValue::BadSyntax(
+                            "scope.keys",
+                            err.pos,
+                            format!("{}: {}", key, err.msg),
+                        ))
+                    }
+                }
+            }
+            Err(_) => ans.push(InvalidValue::BadFormat("scope.keys")),
+        }
+        ans
+    }
+
+    pub fn validate_as_err(&self) -> FResult<()> {
+        let errs = self.validate();
+        if errs.len() != 0 {
+            return Err(FError::new(ValidationError(errs)));
+        }
+        Ok(())
+    }
+
+    pub async fn save(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        trace!("Saving scope {:?}", self.uuid);
+
+        self.validate_as_err()?;
+
+        match self._revision {
+            0 => self.db_insert(tx).await?,
+            _ => self.db_update(tx).await?,
+        };
+        Ok(())
+    }
+
+    pub async fn delete(uuid: Uuid, tx: &mut Transaction<'_>) -> FResult<()> {
+        sqlx::query!("DELETE FROM `scope` WHERE `uuid` = ?", uuid)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn db_insert(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        self._revision = 1;
+        sqlx::query!(
+            "INSERT INTO `scope` (`uuid`, `_revision`, `name`, `desc`, `keys`) VALUES (?, ?, ?, ?, ?)",
+            self.uuid,
+            self._revision,
+            self.name,
+            self.desc,
+            self.keys
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    async fn db_update(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
+        self._revision += 1;
+        sqlx::query!(
+            "UPDATE `scope` SET `_revision` = ?, `name` = ?, `desc` = ?, `keys` = ? WHERE `uuid` = ?",
+            self._revision,
+            self.name,
+            self.desc,
+            self.keys,
+            self.uuid
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Joins the [`Scope::oauth_name`] of each scope into a string suitable for the OAuth `scope` parameter
+    pub fn to_oauth_str(scopes: &[Scope]) -> String {
+        scopes
+            .iter()
+            .map(|s| s.oauth_name())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Parses a list of single or double quoted strings (with backslash escapes) surrounded by square brackets
+fn parse_key_list(val: &str) -> Option<Vec<String>> {
+    let val = val.trim();
+    if !val.starts_with('[') || !val.ends_with(']') {
+        return None;
+    }
+    let mut chars = val[1..val.len() - 1].chars().peekable();
+    let mut ans = Vec::new();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let quote = match chars.next() {
+            Some(c) if c == '\'' || c == '"' => c,
+            None if ans.len() == 0 => return Some(ans),
+            _ => return None,
+        };
+        let mut item = String::new();
+        loop {
+            match chars.next()? {
+                '\\' => item.push(chars.next()?),
+                c if c == quote => break,
+                c => item.push(c),
+            }
+        }
+        ans.push(item);
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.next() {
+            Some(',') => continue,
+            None => return Some(ans),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_keys() {
+        let scope = Scope::new(Uuid::new_v4(), "Phone Number", "", r#"['user.login_handle[kind=\'PHONE\'|kind=\'MOBILE\']']"#);
+        assert_eq!(0, scope.validate().len());
+        assert_eq!("phone_number", scope.oauth_name());
+
+        let scope = Scope::new(Uuid::new_v4(), "Bad", "", "['user.login_handle[kind=']");
+        assert_eq!(1, scope.validate().len());
+
+        let scope = Scope::new(Uuid::new_v4(), "Bad", "", "user.uuid");
+        assert_eq!(1, scope.validate().len());
+    }
+}
@@ -0,0 +1,62 @@
+use crate::model::prelude::*;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Number of random bytes in every generated secret (tokens, codes, client secrets)
+pub const SECRET_LEN: usize = 32;
+
+/// Generates a random URL safe secret
+pub fn new_secret() -> String {
+    let mut buf = [0u8; SECRET_LEN];
+    OsRng.fill_bytes(&mut buf);
+    base64::encode_config(&buf, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hashes a high entropy secret so it can be stored and looked up in the database.
+///
+/// Do NOT use this for user chosen passwords, see [`Password`] for that.
+pub fn hash_secret(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+/// Compares two byte strings in a time that only depends on their lengths
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
+/// Computes the PKCE `S256` code challenge for a code verifier (RFC 7636 section 4.2)
+pub fn pkce_s256(verifier: &str) -> String {
+    let digest = Sha256::digest(verifier.as_bytes());
+    base64::encode_config(&digest, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce_s256() {
+        // Example from RFC 7636 appendix B
+        assert_eq!(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGWSstw-cM",
+            pkce_s256("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk")
+        );
+    }
+
+    #[test]
+    fn test_new_secret() {
+        let a = new_secret();
+        let b = new_secret();
+        assert_ne!(a, b);
+        assert!(constant_time_eq(&hash_secret(&a), &hash_secret(&a)));
+        assert!(!constant_time_eq(&hash_secret(&a), &hash_secret(&b)));
+    }
+}
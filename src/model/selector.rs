@@ -0,0 +1,366 @@
+//! Claim selectors used in [`Scope::keys`].
+//!
+//! Grammar:
+//!
+//! ```text
+//! selector := "user" "." field filter?
+//! field    := "uuid" | "display_name" | "login_handle" | "groups"
+//! filter   := "[" or "]"
+//! or       := and ("|" and)*
+//! and      := cmp ("&" cmp)*
+//! cmp      := ident ("=" | "!=") string
+//! string   := "'" chars "'" | '"' chars '"'
+//! ```
+//!
+//! Examples: `user.uuid`, `user.login_handle[kind='EMAIL']`, `user.login_handle[kind!='EMAIL'&kind!='PHONE']`.
+use crate::model::prelude::*;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError {
+    /// Position (in characters) where the problem was found
+    pub pos: usize,
+    pub msg: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cmp {
+    Eq(String, String),
+    NotEq(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selector {
+    /// The selected field, e.g. `login_handle` for `user.login_handle`
+    field: UserField,
+    /// Disjunction of conjunctions, empty means no filter
+    filter: Vec<Vec<Cmp>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UserField {
+    Uuid,
+    DisplayName,
+    LoginHandle,
+    Groups,
+}
+
+impl UserField {
+    fn parse(name: &str) -> Option<UserField> {
+        match name {
+            "uuid" => Some(UserField::Uuid),
+            "display_name" => Some(UserField::DisplayName),
+            "login_handle" => Some(UserField::LoginHandle),
+            "groups" => Some(UserField::Groups),
+            _ => None,
+        }
+    }
+
+    fn claim_name(&self) -> &'static str {
+        match self {
+            UserField::Uuid => "uuid",
+            UserField::DisplayName => "display_name",
+            UserField::LoginHandle => "login_handle",
+            UserField::Groups => "groups",
+        }
+    }
+
+    /// Attributes that may be used in filters
+    fn attributes(&self) -> &'static [&'static str] {
+        match self {
+            UserField::Uuid | UserField::DisplayName => &[],
+            UserField::LoginHandle => &["handle", "kind"],
+            UserField::Groups => &["uuid", "name"],
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn err<T>(&self, msg: &str) -> Result<T, SelectorError> {
+        Err(SelectorError {
+            pos: self.pos,
+            msg: msg.to_string(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().map_or(false, |c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, SelectorError> {
+        self.skip_ws();
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return self.err("expected identifier");
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn string(&mut self) -> Result<String, SelectorError> {
+        self.skip_ws();
+        let quote = match self.peek() {
+            Some(c) if c == '\'' || c == '"' => c,
+            _ => return self.err("expected quoted string"),
+        };
+        self.pos += 1;
+        let mut ans = String::new();
+        loop {
+            match self.peek() {
+                None => return self.err("unterminated string"),
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c) => ans.push(c),
+                        None => return self.err("unterminated string"),
+                    }
+                }
+                Some(c) if c == quote => {
+                    self.pos += 1;
+                    return Ok(ans);
+                }
+                Some(c) => ans.push(c),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn cmp(&mut self, field: UserField) -> Result<Cmp, SelectorError> {
+        let attr_pos = self.pos;
+        let attr = self.ident()?;
+        if !field.attributes().contains(&attr.as_str()) {
+            return Err(SelectorError {
+                pos: attr_pos,
+                msg: format!("{} has no attribute {:?}", field.claim_name(), attr),
+            });
+        }
+        if self.eat('!') {
+            if !self.eat('=') {
+                return self.err("expected '='");
+            }
+            Ok(Cmp::NotEq(attr, self.string()?))
+        } else if self.eat('=') {
+            Ok(Cmp::Eq(attr, self.string()?))
+        } else {
+            self.err("expected '=' or '!='")
+        }
+    }
+
+    fn filter(&mut self, field: UserField) -> Result<Vec<Vec<Cmp>>, SelectorError> {
+        let mut ors = Vec::new();
+        loop {
+            let mut ands = vec![self.cmp(field)?];
+            while self.eat('&') {
+                ands.push(self.cmp(field)?);
+            }
+            ors.push(ands);
+            if !self.eat('|') {
+                return Ok(ors);
+            }
+        }
+    }
+
+    fn selector(&mut self) -> Result<Selector, SelectorError> {
+        let root = self.ident()?;
+        if root != "user" {
+            self.pos = 0;
+            return self.err("selectors must start with 'user'");
+        }
+        if !self.eat('.') {
+            return self.err("expected '.'");
+        }
+        let field_pos = self.pos;
+        let name = self.ident()?;
+        let field = match UserField::parse(&name) {
+            Some(v) => v,
+            None => {
+                return Err(SelectorError {
+                    pos: field_pos,
+                    msg: format!("unknown field {:?}", name),
+                })
+            }
+        };
+
+        let mut filter = Vec::new();
+        if self.eat('[') {
+            if field.attributes().len() == 0 {
+                return self.err("this field can not be filtered");
+            }
+            filter = self.filter(field)?;
+            if !self.eat(']') {
+                return self.err("expected ']'");
+            }
+        }
+        self.skip_ws();
+        if self.pos != self.chars.len() {
+            return self.err("unexpected trailing characters");
+        }
+        Ok(Selector { field, filter })
+    }
+}
+
+impl Cmp {
+    fn matches(&self, attrs: &dyn Fn(&str) -> String) -> bool {
+        match self {
+            Cmp::Eq(attr, val) => &attrs(attr) == val,
+            Cmp::NotEq(attr, val) => &attrs(attr) != val,
+        }
+    }
+}
+
+impl Selector {
+    pub fn parse(src: &str) -> Result<Selector, SelectorError> {
+        let mut parser = Parser {
+            chars: src.chars().collect(),
+            pos: 0,
+        };
+        parser.selector()
+    }
+
+    pub fn claim_name(&self) -> &'static str {
+        self.field.claim_name()
+    }
+
+    fn filter_matches(&self, attrs: &dyn Fn(&str) -> String) -> bool {
+        self.filter.len() == 0
+            || self
+                .filter
+                .iter()
+                .any(|ands| ands.iter().all(|cmp| cmp.matches(attrs)))
+    }
+
+    /// Returns the claim name and the selected value
+    pub fn eval(&self, user: &User) -> (&'static str, Value) {
+        let val = match self.field {
+            UserField::Uuid => Value::String(user.get_uuid().to_string()),
+            UserField::DisplayName => Value::String(user.display_name.clone()),
+            UserField::LoginHandle => {
+                let mut handles: Vec<&LoginHandle> = user
+                    .login_handles
+                    .iter()
+                    .filter(|h| {
+                        self.filter_matches(&|attr| match attr {
+                            "handle" => h.get_handle().to_string(),
+                            "kind" => h.get_kind().to_string(),
+                            _ => String::new(),
+                        })
+                    })
+                    .collect();
+                handles.sort_by(|a, b| a.get_handle().cmp(b.get_handle()));
+                handles
+                    .into_iter()
+                    .map(|h| serde_json::json!({"handle": h.get_handle(), "kind": h.get_kind()}))
+                    .collect()
+            }
+            UserField::Groups => {
+                let mut groups: Vec<(Uuid, String)> = user
+                    .groups
+                    .iter()
+                    .filter(|(uuid, name)| {
+                        self.filter_matches(&|attr| match attr {
+                            "uuid" => uuid.to_string(),
+                            "name" => name.to_string(),
+                            _ => String::new(),
+                        })
+                    })
+                    .map(|(uuid, name)| (uuid, name.to_string()))
+                    .collect();
+                groups.sort();
+                groups
+                    .into_iter()
+                    .map(|(uuid, name)| serde_json::json!({"uuid": uuid, "name": name}))
+                    .collect()
+            }
+        };
+        (self.field.claim_name(), val)
+    }
+}
+
+/// Adds the claim to the object, merging lists selected by different selectors
+pub fn merge_claim(claims: &mut Map<String, Value>, name: &str, val: Value) {
+    match (claims.get_mut(name), val) {
+        (Some(Value::Array(old)), Value::Array(new)) => {
+            for item in new {
+                if !old.contains(&item) {
+                    old.push(item);
+                }
+            }
+        }
+        (_, val) => {
+            claims.insert(name.to_string(), val);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_user() -> User {
+        let mut user = User::new();
+        user.display_name = "Alice".to_string();
+        user.login_handles.insert(LoginHandle::new("alice", "OTHER"));
+        user.login_handles.insert(LoginHandle::new("alice@example.com", "EMAIL"));
+        user.login_handles.insert(LoginHandle::new("+5511999999999", "MOBILE"));
+        user
+    }
+
+    fn handles(val: &Value) -> Vec<&str> {
+        val.as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["handle"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(0, Selector::parse("usr.uuid").unwrap_err().pos);
+        assert_eq!(5, Selector::parse("user.foo").unwrap_err().pos);
+        assert_eq!(18, Selector::parse("user.login_handle[foo='x']").unwrap_err().pos);
+        assert!(Selector::parse("user.uuid[kind='x']").is_err());
+        assert!(Selector::parse("user.login_handle[kind='x'").is_err());
+        assert!(Selector::parse("user.login_handle[kind=x]").is_err());
+    }
+
+    #[test]
+    fn test_eval() {
+        let user = test_user();
+
+        let (name, val) = Selector::parse("user.display_name").unwrap().eval(&user);
+        assert_eq!("display_name", name);
+        assert_eq!(Value::String("Alice".to_string()), val);
+
+        let (_, val) = Selector::parse("user.login_handle[kind='EMAIL']").unwrap().eval(&user);
+        assert_eq!(vec!["alice@example.com"], handles(&val));
+
+        let (_, val) = Selector::parse("user.login_handle[kind='PHONE'|kind='MOBILE']").unwrap().eval(&user);
+        assert_eq!(vec!["+5511999999999"], handles(&val));
+
+        let (_, val) = Selector::parse("user.login_handle[kind!='EMAIL'&kind!='PHONE'&kind!='MOBILE']").unwrap().eval(&user);
+        assert_eq!(vec!["alice"], handles(&val));
+    }
+}
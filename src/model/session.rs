@@ -23,13 +23,38 @@ struct FullSessionRaw {
     data: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PolarClass)]
 pub struct FullSession {
+    #[polar(attribute)]
     uuid: Uuid,
+    #[polar(attribute)]
     user: User,
+    /// Differs from `user` while impersonating
+    #[polar(attribute)]
     real_user: User,
     login_time: DateTime<Utc>,
     last_used: DateTime<Utc>,
+    #[polar(attribute)]
+    remember_me: bool,
+    ip_addr_real: String,
+    ip_addr_peer: String,
+    user_agent: String,
+    /// Sessions built from an OAuth access token live only for the request, they are never saved nor sent as cookies
+    #[serde(skip)]
+    ephemeral: bool,
+    /// False for access tokens that may only reach upstreams (forward-auth, ext_authz), see [`API_SCOPE`]
+    #[serde(skip)]
+    api_access: bool,
+}
+
+/// What the user of a session may see of it, see [`UserView`]
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionView {
+    uuid: Uuid,
+    user: UserView,
+    real_user: UserView,
+    login_time: DateTime<Utc>,
+    last_used: DateTime<Utc>,
     remember_me: bool,
     ip_addr_real: String,
     ip_addr_peer: String,
@@ -52,6 +77,10 @@ impl FullSession {
         return &self.real_user;
     }
 
+    pub fn get_login_time(&self) -> DateTime<Utc> {
+        return self.login_time;
+    }
+
     #[allow(unused)]
     pub fn valid_until(&self) -> DateTime<Utc> {
         let duration = match self.remember_me {
@@ -66,6 +95,25 @@ impl FullSession {
         now <= self.valid_until()
     }
 
+    pub fn is_impersonating(&self) -> bool {
+        self.user.get_uuid() != self.real_user.get_uuid()
+    }
+
+    /// Each user is seen through their own permissions, so an impersonator keeps seeing themselves in full
+    pub fn to_view(&self, enforcer: &PolicyEnforcer) -> FResult<SessionView> {
+        Ok(SessionView {
+            uuid: self.uuid,
+            user: self.user.to_view(&self.user, enforcer)?,
+            real_user: self.real_user.to_view(&self.real_user, enforcer)?,
+            login_time: self.login_time,
+            last_used: self.last_used,
+            remember_me: self.remember_me,
+            ip_addr_real: self.ip_addr_real.clone(),
+            ip_addr_peer: self.ip_addr_peer.clone(),
+            user_agent: self.user_agent.clone(),
+        })
+    }
+
     #[allow(unused)]
     pub fn new(
         user: &User,
@@ -86,9 +134,42 @@ impl FullSession {
             login_time: now,
             last_used: now,
             remember_me: remember_me,
+            ephemeral: false,
+            api_access: true,
         }
     }
 
+    /// Builds a request-scoped session for the user of an active OAuth access token (e.g. a service account).
+    ///
+    /// Only service accounts and tokens granted [`API_SCOPE`] may use the feroauth API, any other token was given to a third party app.
+    pub fn for_access_token(
+        token: &OAuthToken,
+        user: &User,
+        ip_addr_real: &str,
+        ip_addr_peer: &str,
+        user_agent: &str,
+    ) -> FullSession {
+        let mut ans = FullSession::new(user, user, false, ip_addr_real, ip_addr_peer, user_agent);
+        ans.uuid = token.get_uuid();
+        ans.login_time = token.issued_at;
+        ans.ephemeral = true;
+        ans.api_access = user.is_service_account() || token.scopes().contains(&API_SCOPE);
+        ans
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    pub fn has_api_access(&self) -> bool {
+        self.api_access
+    }
+
+    /// The session of the request whether or not it may use the feroauth API, for endpoints that only describe it to upstreams
+    pub fn from_request_any(req: &HttpRequest) -> Option<FullSession> {
+        req.head().extensions().get::<FullSession>().cloned()
+    }
+
     async fn refresh_internal(
         uuid: Uuid,
         time: DateTime<Utc>,
@@ -150,6 +231,8 @@ impl FullSession {
             ip_addr_real: row.ip_addr_real,
             ip_addr_peer: row.ip_addr_peer,
             user_agent: row.user_agent,
+            ephemeral: false,
+            api_access: true,
         })
     }
 
@@ -187,8 +270,36 @@ impl FromRequest for FullSession {
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         use futures_util::future::{err, ok};
         if let Some(session) = req.head().extensions().get::<FullSession>() {
+            if !session.api_access {
+                return err(actix_web::error::ErrorForbidden("insufficient_scope"));
+            }
             return ok(session.clone());
         }
         err(actix_web::error::ErrorUnauthorized(""))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn put_user_with(user: &User, scope: &str) -> Result<FullSession, AWError> {
+        let (token, _) = OAuthToken::new(OAuthTokenKind::Access, Uuid::new_v4(), user.get_uuid(), None, scope);
+        let session = FullSession::for_access_token(&token, user, "", "", "");
+        let mut req = TestRequest::put().uri(&format!("/users/{}", user.get_uuid())).to_http_request();
+        session.to_request(&mut req);
+        FullSession::from_request(&req, &mut Payload::None).into_inner()
+    }
+
+    #[test]
+    fn test_bearer_api_access() {
+        // A third party app holding a token with the basic scope cannot PUT /users as the user
+        let user = User::new();
+        let err = put_user_with(&user, "basic").unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), actix_web::http::StatusCode::FORBIDDEN);
+
+        assert!(put_user_with(&user, "basic feroauth").is_ok());
+        assert!(put_user_with(&User::new_service_account(Uuid::new_v4()), "").is_ok());
+    }
+}
@@ -18,6 +18,8 @@ pub struct User {
     pub display_name: String,
     added: Option<DateTime<Utc>>,
     last_login: Option<DateTime<Utc>>,
+    /// Set only for service accounts: the user or group that owns (and is responsible for) the account
+    pub service_owner: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PolarClass, Serialize, Deserialize)]
@@ -36,6 +38,30 @@ pub struct UserChange {
     pub login_handles: Option<FSet<LoginHandle>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub groups: Option<GroupMembership>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub superuser: Option<bool>,
+    /// Only honoured when creating the user, a human user can not become a service account (or vice versa)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_owner: Option<Uuid>,
+}
+
+/// What a given user may see of a [`User`], fields they may not read are left out
+#[derive(Debug, Clone, Serialize)]
+pub struct UserView {
+    uuid: Uuid,
+    display_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_owner: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    superuser: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    login_handles: Option<FSet<LoginHandle>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<GroupMembership>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    added: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_login: Option<DateTime<Utc>>,
 }
 
 // This is used when we need just a vague idea of the user (e.g. when storing sessions via [`FullSession`])
@@ -47,6 +73,23 @@ pub struct MinUser {
     // TODO: add groups?
 }
 
+impl LoginHandle {
+    pub fn new(handle: &str, kind: &str) -> LoginHandle {
+        LoginHandle {
+            handle: handle.trim().to_string(),
+            kind: kind.to_string(),
+        }
+    }
+
+    pub fn get_handle(&self) -> &str {
+        &self.handle
+    }
+
+    pub fn get_kind(&self) -> &str {
+        &self.kind
+    }
+}
+
 impl MinUser {
     pub fn get_uuid(&self) -> Uuid {
         self.uuid
@@ -68,8 +111,9 @@ impl MinUser {
         let login_handle = login_handle.trim();
 
         trace!("Loading user {:?}", login_handle);
+        // Service accounts are non-interactive, for them this behaves as if the user does not exist
         let base_row = sqlx::query!(
-            "SELECT `uuid`, `display_name` FROM `user` INNER JOIN `login_handle` ON (`user_uuid` = `uuid`) WHERE `login_handle` = ?",
+            "SELECT `uuid`, `display_name` FROM `user` INNER JOIN `login_handle` ON (`user_uuid` = `uuid`) WHERE `login_handle` = ? AND `service_owner` IS NULL",
             login_handle
         )
         .fetch_one(&mut *tx)
@@ -105,6 +149,33 @@ impl User {
         Ok(())
     }
 
+    /// Asks the enforcer field by field what `as_user` may read, the user itself must already be readable
+    pub fn to_view(&self, as_user: &User, enforcer: &PolicyEnforcer) -> FResult<UserView> {
+        let may = |verb: &str| -> FResult<bool> {
+            Ok(as_user.is_system_super_user() || enforcer.is_allowed(as_user.clone(), verb.to_string(), self.clone())?)
+        };
+        let dates = may(POLVERB_USER_DATES_GET)?;
+        Ok(UserView {
+            uuid: self.uuid,
+            display_name: self.display_name.clone(),
+            service_owner: self.service_owner,
+            superuser: match may(POLVERB_USER_SUPER_GET)? {
+                true => Some(self.superuser),
+                false => None,
+            },
+            login_handles: match may(POLVERB_USER_LOGIN_GET)? {
+                true => Some(self.login_handles.clone()),
+                false => None,
+            },
+            groups: match may(POLVERB_USER_GROUP_GET)? {
+                true => Some(self.groups.clone()),
+                false => None,
+            },
+            added: self.added.filter(|_| dates),
+            last_login: self.last_login.filter(|_| dates),
+        })
+    }
+
     pub fn to_min_user(&self) -> MinUser {
         MinUser {
             uuid: self.uuid,
@@ -132,9 +203,55 @@ impl User {
             last_login: None,
             login_handles: FSet::new(),
             groups: GroupMembership::new(),
+            service_owner: None,
+        }
+    }
+
+    /// Creates a non-interactive user owned by `owner` (a user or a group)
+    pub fn new_service_account(owner: Uuid) -> User {
+        let mut ans = User::new();
+        ans.service_owner = Some(owner);
+        ans
+    }
+
+    pub fn is_service_account(&self) -> bool {
+        self.service_owner.is_some()
+    }
+
+    /// True if `other` is the owner of this service account or a member of the group that owns it
+    pub fn is_owned_by(&self, other: &User) -> bool {
+        match self.service_owner {
+            Some(owner) => other.uuid == owner || other.groups.has(owner),
+            None => false,
         }
     }
 
+    pub fn polar_is_owned_by(&self, other: User) -> bool {
+        self.is_owned_by(&other)
+    }
+
+    /// The alphabetically first login handle of kind `EMAIL`
+    pub fn primary_email(&self) -> Option<&str> {
+        self.login_handles
+            .iter()
+            .filter(|h| h.kind == "EMAIL")
+            .map(|h| h.handle.as_str())
+            .min()
+    }
+
+    pub fn is_system_super_user(&self) -> bool {
+        self.uuid.is_nil() && self.superuser
+    }
+
+    /// Loaded users are only handed out with `POLVERB_USER_GET`, the system super user skips the policy
+    #[track_caller]
+    fn ensure_readable(self, as_user: &User, enforcer: &PolicyEnforcer) -> FResult<User> {
+        if !as_user.is_system_super_user() {
+            enforcer.ensure_allowed(as_user, POLVERB_USER_GET, &self)?;
+        }
+        Ok(self)
+    }
+
     pub fn system_super_user() -> User {
         User {
             uuid: Uuid::nil(),
@@ -145,6 +262,7 @@ impl User {
             last_login: None,
             login_handles: FSet::new(),
             groups: GroupMembership::new(),
+            service_owner: None,
         }
     }
 
@@ -163,6 +281,12 @@ impl User {
                 "user.uuid"
             ))
         }
+        if self.is_service_account() && self.superuser {
+            ans.push(InvalidValue::BadFormat("user.superuser"))
+        }
+        if self.service_owner == Some(self.uuid) {
+            ans.push(InvalidValue::BadFormat("user.service_owner"))
+        }
         ans
     }
 
@@ -200,7 +324,7 @@ impl User {
     pub async fn load_by_uuid(uuid: Uuid, as_user: &User, enforcer: &PolicyEnforcer, tx: &mut Transaction<'_>) -> FResult<User> {
         trace!("Loading user {:?}", uuid);
         let base_row = sqlx::query!(
-            "SELECT `uuid`, `_revision`, `superuser`, `display_name`, `added`, `last_login` FROM `user` WHERE `uuid` = ?",
+            "SELECT `uuid`, `_revision`, `superuser`, `display_name`, `added`, `last_login`, `service_owner` FROM `user` WHERE `uuid` = ?",
             uuid
         )
         .fetch_one(&mut *tx)
@@ -217,7 +341,7 @@ impl User {
 
         let login_handles = User::load_login_handles(uuid, tx).await?;
 
-        Ok(User {
+        User {
             uuid: uuid,
             _revision: base_row._revision,
             superuser: base_row.superuser != 0,
@@ -229,7 +353,12 @@ impl User {
                 .map(|dt| Utc.from_utc_datetime(dt)),
             login_handles: login_handles,
             groups: GroupMembership::load_for(uuid, tx).await?,
-        })
+            service_owner: match base_row.service_owner {
+                Some(v) => Some(parse_uuid_vec(v)?),
+                None => None,
+            },
+        }
+        .ensure_readable(as_user, enforcer)
     }
 
     pub async fn load_by_login_handle(
@@ -246,7 +375,7 @@ impl User {
         }
 
         let base_row = sqlx::query!(
-            "SELECT `user`.`uuid`, `user`.`_revision`, `user`.`superuser`, `user`.`display_name`, `user`.`added`, `user`.`last_login` FROM `user` JOIN `login_handle` ON (`user`.`uuid` = `login_handle`.`user_uuid`) WHERE `login_handle` =  ?",
+            "SELECT `user`.`uuid`, `user`.`_revision`, `user`.`superuser`, `user`.`display_name`, `user`.`added`, `user`.`last_login`, `user`.`service_owner` FROM `user` JOIN `login_handle` ON (`user`.`uuid` = `login_handle`.`user_uuid`) WHERE `login_handle` =  ?",
             login_handle
         )
         .fetch_one(&mut *tx)
@@ -255,7 +384,7 @@ impl User {
 
         let login_handles = User::load_login_handles(uuid, tx).await?;
 
-        Ok(User {
+        User {
             uuid: uuid,
             _revision: base_row._revision,
             superuser: base_row.superuser != 0,
@@ -267,7 +396,12 @@ impl User {
                 .map(|dt| Utc.from_utc_datetime(dt)),
             login_handles: login_handles,
             groups: GroupMembership::load_for(uuid, tx).await?,
-        })
+            service_owner: match base_row.service_owner {
+                Some(v) => Some(parse_uuid_vec(v)?),
+                None => None,
+            },
+        }
+        .ensure_readable(as_user, enforcer)
     }
 
     pub async fn save(&mut self, as_user: &User, enforcer: &PolicyEnforcer, tx: &mut Transaction<'_>) -> FResult<()> {
@@ -276,6 +410,9 @@ impl User {
         enforcer.ensure_allowed(as_user, POLVERB_USER_SAV, &self.clone())?;
 
         self.validate_as_err()?;
+        if self.is_new() {
+            self.ensure_service_owner_exists(tx).await?;
+        }
 
         match self._revision {
             0 => self.db_insert(tx).await?,
@@ -286,17 +423,36 @@ impl User {
         Ok(())
     }
 
+    /// Service accounts must be owned by an existing user or group
+    async fn ensure_service_owner_exists(&self, tx: &mut Transaction<'_>) -> FResult<()> {
+        let owner = match self.service_owner {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let kind = match MinObject::load_by_uuid(owner, tx).await {
+            Ok(v) => v.get_kind().to_string(),
+            Err(err) if err.is_not_found() => String::new(),
+            Err(err) => return Err(err),
+        };
+        if kind != "USER" && kind != "GROUP" {
+            return Err(FError::new(ValidationError(vec![InvalidValue::BadFormat("user.service_owner")])));
+        }
+        Ok(())
+    }
+
     async fn db_insert(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
         self.last_login = None;
         self.added = Some(Utc::now());
         self._revision = 1;
         sqlx::query!(
-            "INSERT INTO `user` (`uuid`, `_revision`, `display_name`, `added`, `last_login`) VALUES (?, ?, ?, ?, ?)",
+            "INSERT INTO `user` (`uuid`, `_revision`, `superuser`, `display_name`, `added`, `last_login`, `service_owner`) VALUES (?, ?, ?, ?, ?, ?, ?)",
             self.uuid,
             self._revision,
+            self.superuser,
             self.display_name,
             self.added,
-            self.last_login
+            self.last_login,
+            self.service_owner
         )
         .execute(&mut *tx)
         .await?;
@@ -306,8 +462,9 @@ impl User {
     async fn db_update(&mut self, tx: &mut Transaction<'_>) -> FResult<()> {
         self._revision += 1;
         sqlx::query!(
-            "UPDATE `user` SET `_revision` = ?, `display_name` = ? WHERE `uuid` = ?",
+            "UPDATE `user` SET `_revision` = ?, `superuser` = ?, `display_name` = ? WHERE `uuid` = ?",
             self._revision,
+            self.superuser,
             self.display_name,
             self.uuid
         )
@@ -345,6 +502,63 @@ impl User {
         Ok(())
     }
 
+    /// Checks the verb of every element `changes` would modify, before [`User::apply_changes`].
+    ///
+    /// Display name and login handles of new users are covered by `POLVERB_USER_ADD`, groups are checked with the group
+    /// as resource. Every denied field is reported in a single error.
+    pub async fn ensure_changes_allowed(
+        &self,
+        changes: &UserChange,
+        as_user: &User,
+        enforcer: &PolicyEnforcer,
+        tx: &mut Transaction<'_>,
+    ) -> FResult<()> {
+        let mut denied = vec![];
+        let mut check = |field: &'static str, verb: &'static str, allowed: bool| {
+            if !allowed {
+                denied.push((field, verb));
+            }
+        };
+
+        if let Some(display_name) = &changes.display_name {
+            if !self.is_new() && *display_name != self.display_name {
+                check("user.display_name", POLVERB_USER_NAME_SET, enforcer.is_allowed(as_user.clone(), POLVERB_USER_NAME_SET, self.clone())?);
+            }
+        }
+        if let Some(login_handles) = &changes.login_handles {
+            if !self.is_new() && login_handles.iter().any(|h| !self.login_handles.contains(h)) {
+                check("user.login_handles", POLVERB_USER_LOGIN_ADD, enforcer.is_allowed(as_user.clone(), POLVERB_USER_LOGIN_ADD, self.clone())?);
+            }
+            if self.login_handles.iter().any(|h| !login_handles.contains(h)) {
+                check("user.login_handles", POLVERB_USER_LOGIN_DEL, enforcer.is_allowed(as_user.clone(), POLVERB_USER_LOGIN_DEL, self.clone())?);
+            }
+        }
+        if let Some(superuser) = changes.superuser {
+            if superuser != self.superuser {
+                check("user.superuser", POLVERB_USER_SET_SUPER, enforcer.is_allowed(as_user.clone(), POLVERB_USER_SET_SUPER, self.clone())?);
+            }
+        }
+        if let Some(groups) = &changes.groups {
+            let before = self.groups.direct_keys_set();
+            let after = groups.direct_keys_set();
+            let added = groups.added_or_rebounded(&self.groups);
+            let su = User::system_super_user();
+            for (uuid, verb) in added
+                .iter()
+                .map(|v| (v, POLVERB_USER_GROUP_ADD))
+                .chain(before.difference(&after).map(|v| (v, POLVERB_USER_GROUP_DEL)))
+            {
+                let group = Group::load_by_uuid(*uuid, &su, enforcer, tx).await?;
+                check("user.groups", verb, enforcer.is_allowed(as_user.clone(), verb, group)?);
+            }
+        }
+
+        if denied.len() != 0 {
+            return Err(FError::new_field_permission_error(as_user, denied, self));
+        }
+        Ok(())
+    }
+
     pub fn apply_changes(&mut self, changes: UserChange) {
         if let Some(display_name) = changes.display_name {
             self.display_name = display_name
@@ -355,5 +569,11 @@ impl User {
         if let Some(groups) = changes.groups {
             self.groups = groups
         }
+        if let Some(superuser) = changes.superuser {
+            self.superuser = superuser
+        }
+        if self.is_new() {
+            self.service_owner = changes.service_owner
+        }
     }
 }
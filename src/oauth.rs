@@ -0,0 +1,576 @@
+use crate::device::{token_from_device_code, DEVICE_CODE_GRANT};
+use crate::oidc::{make_id_token, OIDC_SCOPE};
+use crate::prelude::*;
+
+pub const CLIENT_CREDENTIALS_GRANT: &'static str = "client_credentials";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthorizeRequest {
+    response_type: String,
+    client_id: Uuid,
+    redirect_uri: String,
+    #[serde(default)]
+    scope: String,
+    state: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    nonce: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthorizeConsent {
+    #[serde(flatten)]
+    request: AuthorizeRequest,
+    approve: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum AuthorizeResponseStatus {
+    LoginRequired,
+    ConsentRequired,
+    Redirect,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthorizeResponse {
+    status: AuthorizeResponseStatus,
+    app: Option<MinApp>,
+    scopes: Vec<Scope>,
+    redirect_to: Option<String>,
+}
+
+impl AuthorizeResponse {
+    fn new(status: AuthorizeResponseStatus) -> AuthorizeResponse {
+        AuthorizeResponse {
+            status: status,
+            app: None,
+            scopes: vec![],
+            redirect_to: None,
+        }
+    }
+
+    fn redirect(redirect_to: String) -> AuthorizeResponse {
+        AuthorizeResponse {
+            status: AuthorizeResponseStatus::Redirect,
+            app: None,
+            scopes: vec![],
+            redirect_to: Some(redirect_to),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    device_code: Option<String>,
+    scope: Option<String>,
+    client_id: Option<Uuid>,
+    client_secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RevokeRequest {
+    token: String,
+    token_type_hint: Option<String>,
+    client_id: Option<Uuid>,
+    client_secret: Option<String>,
+}
+
+/// Appends the query parameters to the redirect URI
+#[track_caller]
+fn build_redirect(redirect_uri: &str, params: &[(&str, &str)]) -> FResult<String> {
+    let mut url = match url::Url::parse(redirect_uri) {
+        Ok(v) => v,
+        Err(_) => return Err(FError::new_oauth_error("invalid_request", "invalid redirect_uri")),
+    };
+    url.query_pairs_mut().extend_pairs(params);
+    Ok(url.to_string())
+}
+
+/// Builds the redirect that reports an error back to the app (RFC 6749 section 4.1.2.1)
+fn redirect_error(info: &AuthorizeRequest, code: &str, desc: &str) -> FResult<HttpResponse> {
+    let mut params = vec![("error", code), ("error_description", desc)];
+    if let Some(state) = &info.state {
+        params.push(("state", state));
+    }
+    let url = build_redirect(&info.redirect_uri, &params)?;
+    Ok(HttpResponse::Ok().json(AuthorizeResponse::redirect(url)))
+}
+
+/// Issues an authorization code and builds the redirect that sends it back to the app
+async fn redirect_with_code(
+    info: &AuthorizeRequest,
+    user: &User,
+    scopes: &[Scope],
+    tx: &mut Transaction<'_>,
+) -> FResult<HttpResponse> {
+    // Safety: callers only get here after validate_authorize_request
+    let code_challenge = info.code_challenge.as_deref().unwrap_or_default();
+    let (code, code_clear) = OAuthCode::new(
+        info.client_id,
+        user.get_uuid(),
+        &info.redirect_uri,
+        &Scope::to_oauth_str(scopes),
+        code_challenge,
+        info.nonce.as_deref(),
+    );
+    code.save(tx).await?;
+
+    let mut params = vec![("code", code_clear.as_str())];
+    if let Some(state) = &info.state {
+        params.push(("state", state));
+    }
+    let url = build_redirect(&info.redirect_uri, &params)?;
+    Ok(HttpResponse::Ok().json(AuthorizeResponse::redirect(url)))
+}
+
+/// Checks the parts of the request that must be valid before we can redirect anything back to the app
+async fn load_app_for_authorize(info: &AuthorizeRequest, tx: &mut Transaction<'_>) -> FResult<App> {
+    let app = match App::load_by_uuid(info.client_id, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new_oauth_error("invalid_request", "unknown client_id"));
+            }
+            return Err(err);
+        }
+    };
+    if !app.is_redirect_allowed(&info.redirect_uri) {
+        return Err(FError::new_oauth_error("invalid_request", "redirect_uri does not match the app's URL"));
+    }
+    Ok(app)
+}
+
+/// Returns the requested scopes or the error (code, description) to be redirected back to the app
+async fn validate_authorize_request(
+    info: &AuthorizeRequest,
+    app: &App,
+    user: &User,
+    tx: &mut Transaction<'_>,
+) -> FResult<Result<Vec<Scope>, (&'static str, String)>> {
+    if info.response_type != "code" {
+        return Ok(Err(("unsupported_response_type", "only the code response type is supported".to_string())));
+    }
+    if info.code_challenge.as_deref().unwrap_or_default().is_empty() {
+        return Ok(Err(("invalid_request", "code_challenge is required".to_string())));
+    }
+    if info.code_challenge_method.as_deref() != Some("S256") {
+        return Ok(Err(("invalid_request", "code_challenge_method must be S256".to_string())));
+    }
+    if !app.is_user_allowed(user) {
+        return Ok(Err(("access_denied", "user is not allowed to use this app".to_string())));
+    }
+    let (scopes, unknown) = Scope::load_by_oauth_names(&info.scope, tx).await?;
+    if unknown.len() != 0 {
+        return Ok(Err(("invalid_scope", format!("unknown scopes: {}", unknown.join(" ")))));
+    }
+    Ok(Ok(scopes))
+}
+
+/// Consent can only be given from a real login, never with an access token (which may belong to a service account)
+pub(crate) fn ensure_interactive(auth: &FullSession) -> FResult<()> {
+    if auth.is_ephemeral() {
+        return Err(FError::new_oauth_error("access_denied", "consent requires an interactive login"));
+    }
+    Ok(())
+}
+
+#[get("/oauth/authorize")]
+async fn get_authorize_endpoint(
+    data: web::Data<AppState>,
+    auth: Option<FullSession>,
+    info: web::Query<AuthorizeRequest>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let app = load_app_for_authorize(&info, &mut tx).await?;
+
+    let auth = match auth {
+        Some(v) if !v.is_ephemeral() => v,
+        _ => {
+            return Ok(HttpResponse::Ok().json(AuthorizeResponse::new(AuthorizeResponseStatus::LoginRequired)))
+        }
+    };
+    let user = auth.get_user();
+
+    let scopes = match validate_authorize_request(&info, &app, user, &mut tx).await? {
+        Ok(v) => v,
+        Err((code, desc)) => return redirect_error(&info, code, &desc),
+    };
+
+    // Skip the consent screen if the user already agreed to share everything
+    let granted = Scope::load_granted(app.get_uuid(), user.get_uuid(), &mut tx).await?;
+    if scopes.iter().all(|s| granted.contains(s)) {
+        let ans = redirect_with_code(&info, user, &scopes, &mut tx).await?;
+        tx.commit().await?;
+        return Ok(ans);
+    }
+
+    let mut ans = AuthorizeResponse::new(AuthorizeResponseStatus::ConsentRequired);
+    ans.app = Some(app.to_min_app());
+    ans.scopes = scopes;
+    Ok(HttpResponse::Ok().json(ans))
+}
+
+#[post("/oauth/authorize")]
+async fn post_authorize_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Json<AuthorizeConsent>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let info = info.into_inner();
+    let app = load_app_for_authorize(&info.request, &mut tx).await?;
+    ensure_interactive(&auth)?;
+    let user = auth.get_user();
+
+    let scopes = match validate_authorize_request(&info.request, &app, user, &mut tx).await? {
+        Ok(v) => v,
+        Err((code, desc)) => return redirect_error(&info.request, code, &desc),
+    };
+    if !info.approve {
+        return redirect_error(&info.request, "access_denied", "user denied the request");
+    }
+
+    Scope::grant(app.get_uuid(), user.get_uuid(), &scopes, &mut tx).await?;
+    let ans = redirect_with_code(&info.request, user, &scopes, &mut tx).await?;
+    tx.commit().await?;
+    Ok(ans)
+}
+
+/// Reads the token from the `Authorization: Bearer` header (RFC 6750 section 2.1)
+pub(crate) fn get_bearer_token(headers: &httpHeader::HeaderMap) -> Option<String> {
+    let header = headers.get(httpHeader::AUTHORIZATION)?.to_str().ok()?;
+    if header.starts_with("Bearer ") {
+        Some(header[7..].trim().to_string())
+    } else {
+        None
+    }
+}
+
+/// Reads the client credentials from the `Authorization: Basic` header or from the form (RFC 6749 section 2.3.1)
+fn get_client_credentials(
+    req: &HttpRequest,
+    form_id: Option<Uuid>,
+    form_secret: Option<String>,
+) -> FResult<(Uuid, Option<String>)> {
+    if let Some(header) = req.headers().get(httpHeader::AUTHORIZATION) {
+        let header = header.to_str().unwrap_or_default();
+        if header.starts_with("Basic ") {
+            let decoded = base64::decode(header[6..].trim())
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok());
+            let decoded = match decoded {
+                Some(v) => v,
+                None => return Err(FError::new_oauth_error("invalid_client", "malformed Authorization header")),
+            };
+            let mut parts = decoded.splitn(2, ':');
+            let client_id = parts.next().unwrap_or_default();
+            let client_secret = parts.next().unwrap_or_default();
+            return match parse_uuid_str(client_id) {
+                Ok(uuid) => Ok((uuid, Some(client_secret.to_string()))),
+                Err(_) => Err(FError::new_oauth_error("invalid_client", "malformed client_id")),
+            };
+        }
+    }
+    match form_id {
+        Some(uuid) => Ok((uuid, form_secret)),
+        None => Err(FError::new_oauth_error("invalid_client", "missing client credentials")),
+    }
+}
+
+/// Loads the app that is making the request. Only public apps may omit their secret, and only if `require_secret` is false.
+pub(crate) async fn authenticate_client(
+    req: &HttpRequest,
+    form_id: Option<Uuid>,
+    form_secret: Option<String>,
+    require_secret: bool,
+    tx: &mut Transaction<'_>,
+) -> FResult<App> {
+    let (client_id, client_secret) = get_client_credentials(req, form_id, form_secret)?;
+    let app = match App::load_by_uuid(client_id, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new_oauth_error("invalid_client", "unknown client"));
+            }
+            return Err(err);
+        }
+    };
+    match client_secret {
+        Some(secret) => {
+            if !app.verify_secret(&secret) {
+                return Err(FError::new_oauth_error("invalid_client", "wrong client secret"));
+            }
+        }
+        None => {
+            if require_secret || !app.public {
+                return Err(FError::new_oauth_error("invalid_client", "missing client secret"));
+            }
+        }
+    }
+    Ok(app)
+}
+
+/// Issues a new access and refresh token pair (and an ID token if the `openid` scope was granted)
+pub(crate) async fn issue_tokens(
+    data: &AppState,
+    app: &App,
+    user_uuid: Uuid,
+    scope: &str,
+    nonce: Option<&str>,
+    tx: &mut Transaction<'_>,
+) -> FResult<TokenResponse> {
+    let (refresh, refresh_clear) = OAuthToken::new(OAuthTokenKind::Refresh, app.get_uuid(), user_uuid, None, scope);
+    refresh.save(tx).await?;
+    let (access, access_clear) = OAuthToken::new(
+        OAuthTokenKind::Access,
+        app.get_uuid(),
+        user_uuid,
+        Some(refresh.get_uuid()),
+        scope,
+    );
+    access.save(tx).await?;
+
+    let id_token = match access.scopes().contains(&OIDC_SCOPE) {
+        true => Some(make_id_token(data, app, user_uuid, nonce, tx).await?),
+        false => None,
+    };
+
+    Ok(TokenResponse {
+        access_token: access_clear,
+        token_type: "Bearer",
+        expires_in: access.expires_in(),
+        refresh_token: Some(refresh_clear),
+        scope: scope.to_string(),
+        id_token,
+    })
+}
+
+/// Ensures the user still exists and is still allowed to use the app
+pub(crate) async fn ensure_user_still_allowed(
+    app: &App,
+    user_uuid: Uuid,
+    enforcer: &PolicyEnforcer,
+    tx: &mut Transaction<'_>,
+) -> FResult<()> {
+    let user = match User::load_by_uuid(user_uuid, &User::system_super_user(), enforcer, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new_oauth_error("invalid_grant", "user no longer exists"));
+            }
+            return Err(err);
+        }
+    };
+    if !app.is_user_allowed(&user) {
+        return Err(FError::new_oauth_error("invalid_grant", "user is not allowed to use this app"));
+    }
+    Ok(())
+}
+
+async fn token_from_code(
+    data: &AppState,
+    app: &App,
+    info: &TokenRequest,
+    tx: &mut Transaction<'_>,
+) -> FResult<TokenResponse> {
+    let code = match &info.code {
+        Some(v) => v,
+        None => return Err(FError::new_oauth_error("invalid_request", "missing code")),
+    };
+    let code = match OAuthCode::consume(code, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new_oauth_error("invalid_grant", "invalid or expired code"));
+            }
+            return Err(err);
+        }
+    };
+    if code.app_uuid != app.get_uuid() {
+        return Err(FError::new_oauth_error("invalid_grant", "code was issued to another client"));
+    }
+    if info.redirect_uri.as_deref() != Some(code.redirect_uri.as_str()) {
+        return Err(FError::new_oauth_error("invalid_grant", "redirect_uri does not match"));
+    }
+    let verifier = info.code_verifier.as_deref().unwrap_or_default();
+    if !code.verify_pkce(verifier) {
+        return Err(FError::new_oauth_error("invalid_grant", "wrong code_verifier"));
+    }
+    ensure_user_still_allowed(app, code.user_uuid, &data.enforcer, tx).await?;
+
+    issue_tokens(data, app, code.user_uuid, &code.scope, code.nonce.as_deref(), tx).await
+}
+
+/// Also returns the hashes of the revoked tokens, which the caller evicts from the introspection cache after the commit
+async fn token_from_refresh(
+    data: &AppState,
+    app: &App,
+    info: &TokenRequest,
+    tx: &mut Transaction<'_>,
+) -> FResult<(TokenResponse, Vec<Vec<u8>>)> {
+    let token = match &info.refresh_token {
+        Some(v) => v,
+        None => return Err(FError::new_oauth_error("invalid_request", "missing refresh_token")),
+    };
+    let mut token = match OAuthToken::load_by_token(token, tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new_oauth_error("invalid_grant", "invalid refresh_token"));
+            }
+            return Err(err);
+        }
+    };
+    if token.kind != OAuthTokenKind::Refresh || !token.is_active() || token.app_uuid != app.get_uuid() {
+        return Err(FError::new_oauth_error("invalid_grant", "invalid refresh_token"));
+    }
+
+    // The new tokens may only carry a subset of the original scopes
+    let scope = match &info.scope {
+        Some(requested) => {
+            let granted = token.scopes();
+            if !requested.split_whitespace().all(|s| granted.contains(&s)) {
+                return Err(FError::new_oauth_error("invalid_scope", "requested scope exceeds the original grant"));
+            }
+            requested.split_whitespace().collect::<Vec<_>>().join(" ")
+        }
+        None => token.scope.clone(),
+    };
+    ensure_user_still_allowed(app, token.user_uuid, &data.enforcer, tx).await?;
+
+    // Refresh tokens are rotated on every use, which also revokes the access tokens issued with them
+    let revoked = token.revoke(tx).await?;
+    let ans = issue_tokens(data, app, token.user_uuid, &scope, None, tx).await?;
+    Ok((ans, revoked))
+}
+
+/// The app obtains a token for its own service account (RFC 6749 section 4.4)
+async fn token_from_client_credentials(
+    data: &AppState,
+    app: &App,
+    info: &TokenRequest,
+    tx: &mut Transaction<'_>,
+) -> FResult<TokenResponse> {
+    let service_account = match app.service_account {
+        Some(v) => v,
+        None => return Err(FError::new_oauth_error("unauthorized_client", "the client has no service account")),
+    };
+    let user = User::load_by_uuid(service_account, &User::system_super_user(), &data.enforcer, tx).await?;
+    if !user.is_service_account() {
+        return Err(FError::new_oauth_error("unauthorized_client", "the client has no service account"));
+    }
+
+    let (scopes, unknown) = Scope::load_by_oauth_names(info.scope.as_deref().unwrap_or_default(), tx).await?;
+    if unknown.len() != 0 {
+        return Err(FError::new_oauth_error("invalid_scope", &format!("unknown scopes: {}", unknown.join(" "))));
+    }
+    // Nobody logs in here, so neither an ID token nor the openid scope make sense
+    let scopes: Vec<Scope> = scopes.into_iter().filter(|s| s.oauth_name() != OIDC_SCOPE).collect();
+    ensure_user_still_allowed(app, user.get_uuid(), &data.enforcer, tx).await?;
+
+    // No refresh token is issued as the client can always ask again (RFC 6749 section 4.4.3)
+    let scope = Scope::to_oauth_str(&scopes);
+    let (access, access_clear) = OAuthToken::new(OAuthTokenKind::Access, app.get_uuid(), user.get_uuid(), None, &scope);
+    access.save(tx).await?;
+
+    Ok(TokenResponse {
+        access_token: access_clear,
+        token_type: "Bearer",
+        expires_in: access.expires_in(),
+        refresh_token: None,
+        scope,
+        id_token: None,
+    })
+}
+
+#[post("/oauth/token")]
+async fn token_endpoint(
+    data: web::Data<AppState>,
+    info: web::Form<TokenRequest>,
+    req: HttpRequest,
+) -> FResult<HttpResponse> {
+    let info = info.into_inner();
+    let mut tx = data.db.begin().await?;
+    // Confidential clients always authenticate, public ones may not act on their own behalf
+    let require_secret = info.grant_type == CLIENT_CREDENTIALS_GRANT;
+    let app = authenticate_client(&req, info.client_id, info.client_secret.clone(), require_secret, &mut tx).await?;
+
+    let no_revoked = |ans: TokenResponse| (ans, Vec::new());
+    let ans = match info.grant_type.as_str() {
+        "authorization_code" => token_from_code(&data, &app, &info, &mut tx).await.map(no_revoked),
+        "refresh_token" => token_from_refresh(&data, &app, &info, &mut tx).await,
+        CLIENT_CREDENTIALS_GRANT => token_from_client_credentials(&data, &app, &info, &mut tx).await.map(no_revoked),
+        DEVICE_CODE_GRANT => {
+            token_from_device_code(&data, &app, info.device_code.as_deref(), &mut tx).await.map(no_revoked)
+        }
+        _ => return Err(FError::new_oauth_error("unsupported_grant_type", "unsupported grant_type")),
+    };
+    let (ans, revoked) = match ans {
+        Ok(v) => v,
+        Err(err) => {
+            // Consumed codes and device polls must be recorded even when the answer is an error
+            if err.is_oauth() {
+                tx.commit().await?;
+            }
+            return Err(err);
+        }
+    };
+    tx.commit().await?;
+    // Evict only after the commit, or a concurrent introspection could cache the tokens as active again
+    data.introspection_cache.invalidate(&revoked);
+
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .header(httpHeader::PRAGMA, "no-cache")
+        .json(ans))
+}
+
+/// Token revocation as defined in RFC 7009
+#[post("/oauth/revoke")]
+async fn revoke_endpoint(
+    data: web::Data<AppState>,
+    info: web::Form<RevokeRequest>,
+    req: HttpRequest,
+) -> FResult<HttpResponse> {
+    let info = info.into_inner();
+    let mut tx = data.db.begin().await?;
+    let app = authenticate_client(&req, info.client_id, info.client_secret.clone(), false, &mut tx).await?;
+    debug!("Revoking token for app {} (hint: {:?})", app.get_uuid(), info.token_type_hint);
+
+    // Invalid tokens and tokens of other clients are silently ignored (RFC 7009 section 2.2)
+    let mut revoked = vec![];
+    match OAuthToken::load_by_token(&info.token, &mut tx).await {
+        Ok(mut token) => {
+            if token.app_uuid == app.get_uuid() {
+                revoked = token.revoke(&mut tx).await?;
+            }
+        }
+        Err(err) => {
+            if !err.is_not_found() {
+                return Err(err);
+            }
+        }
+    };
+    tx.commit().await?;
+    // Evict only after the commit so no answer from before it gets cached again
+    data.introspection_cache.invalidate(&revoked);
+
+    Ok(HttpResponse::Ok().finish())
+}
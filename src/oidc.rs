@@ -0,0 +1,120 @@
+use crate::device::DEVICE_CODE_GRANT;
+use crate::model::claims::user_claims;
+use crate::model::oidc_key::OIDC_KEY_GRACE;
+use crate::oauth::{get_bearer_token, CLIENT_CREDENTIALS_GRANT};
+use crate::prelude::*;
+use chrono::Duration;
+
+pub const OIDC_SCOPE: &'static str = "openid";
+pub const ID_TOKEN_LIFE: i64 = 60 * 60; // 1 hour
+
+/// Signs an ID token for the user with the current signing key
+pub(crate) async fn make_id_token(
+    data: &AppState,
+    app: &App,
+    user_uuid: Uuid,
+    nonce: Option<&str>,
+    tx: &mut Transaction<'_>,
+) -> FResult<String> {
+    let key = OidcKey::get_signing_key(data.oidc_alg, tx).await?;
+    let now = Utc::now();
+    let mut claims = serde_json::json!({
+        "iss": data.issuer,
+        "sub": user_uuid.to_string(),
+        "aud": app.get_uuid().to_string(),
+        "azp": app.get_uuid().to_string(),
+        "iat": now.timestamp(),
+        "exp": (now + Duration::seconds(ID_TOKEN_LIFE)).timestamp(),
+    });
+    if let Some(nonce) = nonce {
+        claims["nonce"] = serde_json::Value::String(nonce.to_string());
+    }
+    key.sign_jwt(&claims)
+}
+
+#[get("/.well-known/openid-configuration")]
+async fn discovery_endpoint(data: web::Data<AppState>) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let scopes = Scope::load_all(&mut tx).await?;
+    let issuer = &data.issuer;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+        "jwks_uri": format!("{}/oauth/jwks", issuer),
+        "revocation_endpoint": format!("{}/oauth/revoke", issuer),
+        "introspection_endpoint": format!("{}/oauth/introspect", issuer),
+        "device_authorization_endpoint": format!("{}/oauth/device_authorization", issuer),
+        "scopes_supported": scopes.iter().map(|s| s.oauth_name()).collect::<Vec<_>>(),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token", CLIENT_CREDENTIALS_GRANT, DEVICE_CODE_GRANT],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": [data.oidc_alg.as_str()],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported": ["sub", "iss", "aud", "exp", "iat", "nonce", "uuid", "display_name", "login_handle", "groups"],
+    })))
+}
+
+#[get("/oauth/jwks")]
+async fn jwks_endpoint(data: web::Data<AppState>) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    // Ensures there is always a key to publish (and rotates it if needed)
+    OidcKey::get_signing_key(data.oidc_alg, &mut tx).await?;
+    let keys = OidcKey::load_published(&mut tx).await?;
+    tx.commit().await?;
+
+    let mut jwks = Vec::new();
+    for key in keys {
+        jwks.push(key.to_jwk()?);
+    }
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, format!("max-age={}", OIDC_KEY_GRACE / 4))
+        .json(serde_json::json!({ "keys": jwks })))
+}
+
+async fn userinfo(data: &AppState, req: &HttpRequest) -> FResult<HttpResponse> {
+    let token = match get_bearer_token(req.headers()) {
+        Some(v) => v,
+        None => return Err(FError::new_oauth_error("invalid_token", "missing bearer token")),
+    };
+    let mut tx = data.db.begin().await?;
+    let token = match OAuthToken::load_by_token(&token, &mut tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new_oauth_error("invalid_token", "invalid access token"));
+            }
+            return Err(err);
+        }
+    };
+    if token.kind != OAuthTokenKind::Access || !token.is_active() {
+        return Err(FError::new_oauth_error("invalid_token", "invalid access token"));
+    }
+    if !token.scopes().contains(&OIDC_SCOPE) {
+        return Err(FError::new_oauth_error("insufficient_scope", "the openid scope is required"));
+    }
+
+    let user = User::load_by_uuid(token.user_uuid, &User::system_super_user(), &data.enforcer, &mut tx).await?;
+    // Only scopes that are both in the token and still granted by the user are shared
+    let (requested, _) = Scope::load_by_oauth_names(&token.scope, &mut tx).await?;
+    let granted = Scope::load_granted(token.app_uuid, token.user_uuid, &mut tx).await?;
+    let scopes: Vec<Scope> = requested.into_iter().filter(|s| granted.contains(s)).collect();
+
+    let claims = user_claims(&user, &scopes)?;
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .json(claims))
+}
+
+#[get("/oauth/userinfo")]
+async fn get_userinfo_endpoint(data: web::Data<AppState>, req: HttpRequest) -> FResult<HttpResponse> {
+    userinfo(&data, &req).await
+}
+
+#[post("/oauth/userinfo")]
+async fn post_userinfo_endpoint(data: web::Data<AppState>, req: HttpRequest) -> FResult<HttpResponse> {
+    userinfo(&data, &req).await
+}
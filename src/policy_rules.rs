@@ -0,0 +1,95 @@
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize)]
+struct RevisionQuery {
+    /// Revision the client last saw, the request fails if the rule changed since
+    _revision: Option<i32>,
+}
+
+/// Picks up the committed rules, the change is already saved so failing here only gets logged
+pub(crate) async fn reload_enforcer(data: &AppState) {
+    let mut enforcer = data.enforcer.clone();
+    let ans: FResult<()> = async {
+        let mut tx = data.db.begin().await?;
+        enforcer.reload(&mut tx).await
+    }
+    .await;
+    if let Err(err) = ans {
+        error!("Failed to reload the policy: {:?}", err);
+    }
+}
+
+#[get("/policy/rules")]
+async fn list_rules_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let mut ans = vec![];
+    for rule in PolicyRule::load_all(&mut tx).await? {
+        if data.enforcer.is_allowed(auth.get_user().clone(), POLVERB_POLICY_RULE_GET, rule.clone())? {
+            ans.push(rule);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ans))
+}
+
+#[get("/policy/rules/{uuid}")]
+async fn get_rule_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let rule = PolicyRule::load_by_uuid(*path, &mut tx).await?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_POLICY_RULE_GET, &rule)?;
+
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+/// Adds a rule (when `uuid` is `new`) or updates it, `_revision` must match the stored rule when given
+#[put("/policy/rules/{uuid}")]
+async fn put_rule_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    info: web::Json<PolicyRuleChange>,
+    path: web::Path<String>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let mut rule = match path.as_str() {
+        "new" => PolicyRule::new("", "", ""),
+        _ => PolicyRule::load_by_uuid(parse_uuid_str(&path)?, &mut tx).await?,
+    };
+    rule.apply_changes(info.into_inner())?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_POLICY_RULE_SET, &rule)?;
+    rule.save(&mut tx).await?;
+    tx.commit().await?;
+    reload_enforcer(&data).await;
+
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+#[delete("/policy/rules/{uuid}")]
+async fn delete_rule_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+    info: web::Query<RevisionQuery>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let rule = PolicyRule::load_by_uuid(*path, &mut tx).await?;
+    rule.ensure_revision(info._revision)?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_POLICY_RULE_SET, &rule)?;
+    PolicyRule::delete(rule.get_uuid(), &mut tx).await?;
+    tx.commit().await?;
+    reload_enforcer(&data).await;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Lists every verb rules can refer to, for the rule editor
+#[get("/policy/verbs")]
+async fn list_verbs_endpoint(_auth: FullSession) -> FResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(POLICY_VERBS))
+}
@@ -9,7 +9,13 @@ pub use crate::model::{Password, User};
 
 pub struct AppState {
     pub db: Arc<sqlx::Pool<sqlx::MySql>>,
-    pub enforcer: PolicyEnforcer
+    pub enforcer: PolicyEnforcer,
+    /// OpenID Connect issuer identifier (i.e. the public base URL of this server)
+    pub issuer: String,
+    pub oidc_alg: OidcAlg,
+    pub introspection_cache: crate::introspect::IntrospectionCache,
+    pub user_code_limiter: crate::device::UserCodeLimiter,
+    pub forward_auth_headers: crate::auth::ForwardAuthHeaders,
 }
 
 pub fn get_ip(req: &HttpRequest) -> (String, String) {
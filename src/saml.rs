@@ -0,0 +1,201 @@
+use crate::model::saml::{build_idp_metadata, build_saml_response, SamlAssertionParams, SamlAuthnRequest, SamlKey, SamlNameIdFormat, SamlSp};
+use crate::prelude::*;
+
+#[derive(Debug, Deserialize)]
+struct SsoRequest {
+    #[serde(rename = "SAMLRequest")]
+    saml_request: String,
+    #[serde(rename = "RelayState")]
+    relay_state: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum SsoResponseStatus {
+    LoginRequired,
+    /// The WUI must POST `saml_response` (and `relay_state`) to `acs_url`
+    Post,
+}
+
+#[derive(Debug, Serialize)]
+struct SsoResponse {
+    status: SsoResponseStatus,
+    app: Option<MinApp>,
+    acs_url: Option<String>,
+    saml_response: Option<String>,
+    relay_state: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpRegistration {
+    /// SP metadata XML
+    metadata: String,
+    /// Overrides the NameID format guessed from the metadata
+    name_id_format: Option<SamlNameIdFormat>,
+}
+
+fn idp_entity_id(data: &AppState) -> String {
+    format!("{}/saml/metadata", data.issuer)
+}
+
+#[get("/saml/metadata")]
+async fn idp_metadata_endpoint(data: web::Data<AppState>) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let key = SamlKey::get_or_create(&data.issuer, &mut tx).await?;
+    tx.commit().await?;
+
+    let metadata = build_idp_metadata(&key, &idp_entity_id(&data), &format!("{}/saml/sso", data.issuer))?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/samlmetadata+xml")
+        .body(metadata))
+}
+
+#[get("/apps/{uuid}/saml")]
+async fn get_sp_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let app = App::load_by_uuid(*path, &mut tx).await?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_APP_GET, &app)?;
+    let sp = SamlSp::load_by_app(app.get_uuid(), &mut tx).await?;
+
+    Ok(HttpResponse::Ok().json(sp))
+}
+
+/// Registers (or replaces) the SAML service provider of the app from its metadata
+#[put("/apps/{uuid}/saml")]
+async fn put_sp_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+    info: web::Json<SpRegistration>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let app = App::load_by_uuid(*path, &mut tx).await?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_APP_SAV, &app)?;
+
+    let mut sp = SamlSp::from_metadata(app.get_uuid(), &info.metadata)?;
+    if let Some(name_id_format) = info.name_id_format {
+        sp.name_id_format = name_id_format;
+    }
+    sp.save(&mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(sp))
+}
+
+#[delete("/apps/{uuid}/saml")]
+async fn delete_sp_endpoint(
+    data: web::Data<AppState>,
+    auth: FullSession,
+    path: web::Path<Uuid>,
+) -> FResult<HttpResponse> {
+    let mut tx = data.db.begin().await?;
+    let app = App::load_by_uuid(*path, &mut tx).await?;
+    data.enforcer.ensure_allowed(auth.get_user(), POLVERB_APP_SAV, &app)?;
+    SamlSp::delete(app.get_uuid(), &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Answers an SP-initiated `AuthnRequest` with a signed assertion for the logged in user
+async fn sso(
+    data: &AppState,
+    auth: Option<FullSession>,
+    info: SsoRequest,
+    deflated: bool,
+) -> FResult<HttpResponse> {
+    let request = SamlAuthnRequest::decode(&info.saml_request, deflated)?;
+    let mut tx = data.db.begin().await?;
+    let sp = match SamlSp::load_by_entity_id(&request.issuer, &mut tx).await {
+        Ok(v) => v,
+        Err(err) => {
+            if err.is_not_found() {
+                return Err(FError::new(ValidationError(vec![InvalidValue::BadFormat("saml.request.issuer")])));
+            }
+            return Err(err);
+        }
+    };
+    // Assertions only ever go to the registered endpoint, this is what makes unsigned requests safe
+    if let Some(acs_url) = &request.acs_url {
+        if *acs_url != sp.acs_url {
+            return Err(FError::new(ValidationError(vec![InvalidValue::BadFormat("saml.request.acs_url")])));
+        }
+    }
+    let app = App::load_by_uuid(sp.app_uuid, &mut tx).await?;
+
+    let auth = match auth {
+        Some(v) if !v.is_ephemeral() => v,
+        _ => {
+            return Ok(HttpResponse::Ok().json(SsoResponse {
+                status: SsoResponseStatus::LoginRequired,
+                app: Some(app.to_min_app()),
+                acs_url: None,
+                saml_response: None,
+                relay_state: None,
+            }))
+        }
+    };
+    let user = auth.get_user();
+    if !app.is_user_allowed(user) {
+        return Err(FError::new_permission_error(user.get_uuid(), "use", app.get_uuid()));
+    }
+    let name_id = match sp.name_id_format.name_id_for(user) {
+        Some(v) => v,
+        None => return Err(FError::new(ValidationError(vec![InvalidValue::MustNotNull("user.login_handle")]))),
+    };
+
+    let mut groups: Vec<String> = user.groups.iter().map(|(_, name)| name.to_string()).collect();
+    groups.sort();
+    let key = SamlKey::get_or_create(&data.issuer, &mut tx).await?;
+    let idp_entity_id = idp_entity_id(data);
+    let response = build_saml_response(
+        &key,
+        &SamlAssertionParams {
+            idp_entity_id: &idp_entity_id,
+            sp: &sp,
+            in_response_to: &request.id,
+            name_id: &name_id,
+            session_index: auth.get_uuid(),
+            authn_instant: auth.get_login_time(),
+            attributes: vec![
+                ("uuid", vec![user.get_uuid().to_string()]),
+                ("display_name", vec![user.display_name.clone()]),
+                ("groups", groups),
+            ],
+        },
+    )?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok()
+        .header(httpHeader::CACHE_CONTROL, "no-store")
+        .json(SsoResponse {
+            status: SsoResponseStatus::Post,
+            app: Some(app.to_min_app()),
+            acs_url: Some(sp.acs_url),
+            saml_response: Some(base64::encode(response)),
+            relay_state: info.relay_state,
+        }))
+}
+
+/// HTTP-Redirect binding
+#[get("/saml/sso")]
+async fn get_sso_endpoint(
+    data: web::Data<AppState>,
+    auth: Option<FullSession>,
+    info: web::Query<SsoRequest>,
+) -> FResult<HttpResponse> {
+    sso(&data, auth, info.into_inner(), true).await
+}
+
+/// HTTP-POST binding
+#[post("/saml/sso")]
+async fn post_sso_endpoint(
+    data: web::Data<AppState>,
+    auth: Option<FullSession>,
+    info: web::Form<SsoRequest>,
+) -> FResult<HttpResponse> {
+    sso(&data, auth, info.into_inner(), false).await
+}
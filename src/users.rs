@@ -140,6 +140,11 @@ async fn login_endpoint(
     }
 }
 
+/// Answers 403 for unknown users too, so that existence does not leak to those who may not see them
+fn hide_not_found(err: FError, as_user: &User, handle: &str) -> FError {
+    err.hide_not_found(as_user, POLVERB_USER_GET, handle)
+}
+
 #[get("/users/{handle}")]
 async fn get_user_endpoint(
     data: web::Data<AppState>,
@@ -147,9 +152,11 @@ async fn get_user_endpoint(
     path: web::Path<String>,
 ) -> FResult<HttpResponse> {
     let mut tx = data.db.begin().await.unwrap();
-    let user = User::load_by_login_handle(&path, auth.get_user(), &data.enforcer, &mut tx).await?;
+    let user = User::load_by_login_handle(&path, auth.get_user(), &data.enforcer, &mut tx)
+        .await
+        .map_err(|err| hide_not_found(err, auth.get_user(), &path))?;
 
-    return Ok(HttpResponse::Ok().json(user));
+    return Ok(HttpResponse::Ok().json(user.to_view(auth.get_user(), &data.enforcer)?));
 }
 
 #[put("/users/{handle}")]
@@ -163,12 +170,16 @@ async fn put_user_endpoint(
     let handle = path.as_str();
     let mut user = match handle {
         "new" => User::new(),
-        _ => User::load_by_login_handle(handle, auth.get_user(), &data.enforcer, &mut tx).await?,
+        _ => User::load_by_login_handle(handle, auth.get_user(), &data.enforcer, &mut tx)
+            .await
+            .map_err(|err| hide_not_found(err, auth.get_user(), handle))?,
     };
-    user.apply_changes(info.into_inner());
+    let info = info.into_inner();
+    user.ensure_changes_allowed(&info, auth.get_user(), &data.enforcer, &mut tx).await?;
+    user.apply_changes(info);
     debug!("{:?}", user);
     user.save(auth.get_user(), &data.enforcer, &mut tx).await?;
     tx.commit().await?;
 
-    return Ok(HttpResponse::Ok().json(user));
+    return Ok(HttpResponse::Ok().json(user.to_view(auth.get_user(), &data.enforcer)?));
 }